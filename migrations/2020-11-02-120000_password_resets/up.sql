@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE password_resets (
+  id UUID NOT NULL UNIQUE PRIMARY KEY,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  expires_at TIMESTAMP NOT NULL,
+  used_at TIMESTAMP
+);
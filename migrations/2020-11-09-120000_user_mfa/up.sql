@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE user_mfa (
+  email VARCHAR(100) NOT NULL UNIQUE PRIMARY KEY REFERENCES users (email) ON DELETE CASCADE,
+  totp_secret VARCHAR(64) NOT NULL, --base32 encoded
+  enabled BOOLEAN NOT NULL DEFAULT false,
+  last_used_step BIGINT,
+  recovery_codes TEXT[] NOT NULL DEFAULT '{}', --sha256 hashes
+  created_at TIMESTAMP NOT NULL
+);
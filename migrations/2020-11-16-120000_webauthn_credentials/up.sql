@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE webauthn_credentials (
+  id VARCHAR(1024) NOT NULL UNIQUE PRIMARY KEY, --base64url credential id
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  public_key BYTEA NOT NULL, --COSE_Key
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL,
+  last_used_at TIMESTAMP
+);
+
+CREATE INDEX webauthn_credentials_email_idx ON webauthn_credentials (email);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE refresh_tokens (
+  id UUID NOT NULL UNIQUE PRIMARY KEY,
+  family_id UUID NOT NULL,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  token_hash VARCHAR(64) NOT NULL UNIQUE, --sha256 hash
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  used_at TIMESTAMP,
+  revoked_at TIMESTAMP
+);
+
+CREATE INDEX refresh_tokens_family_id_idx ON refresh_tokens (family_id);
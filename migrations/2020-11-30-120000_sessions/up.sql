@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE sessions (
+  id UUID NOT NULL UNIQUE PRIMARY KEY,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  user_agent TEXT,
+  ip_address VARCHAR(64),
+  created_at TIMESTAMP NOT NULL,
+  last_seen_at TIMESTAMP NOT NULL,
+  revoked_at TIMESTAMP
+);
+
+CREATE INDEX sessions_email_idx ON sessions (email);
+
+-- every refresh token family now belongs to a session, older families have none
+DELETE FROM refresh_tokens;
+ALTER TABLE refresh_tokens
+  ADD CONSTRAINT refresh_tokens_family_id_fkey
+  FOREIGN KEY (family_id) REFERENCES sessions (id) ON DELETE CASCADE;
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE roles (
+  name VARCHAR(64) NOT NULL UNIQUE PRIMARY KEY,
+  description TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE user_roles (
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  role VARCHAR(64) NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  PRIMARY KEY (email, role)
+);
+
+INSERT INTO roles (name, description) VALUES ('admin', 'Manage users, roles and invitations');
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE invitations DROP COLUMN revoked_at;
+ALTER TABLE invitations DROP COLUMN accepted_at;
+ALTER TABLE invitations DROP COLUMN created_at;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE invitations ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now();
+ALTER TABLE invitations ADD COLUMN accepted_at TIMESTAMP;
+ALTER TABLE invitations ADD COLUMN revoked_at TIMESTAMP;
+-- invitations that were already used to register
+UPDATE invitations SET accepted_at = now() WHERE email IN (SELECT email FROM users);
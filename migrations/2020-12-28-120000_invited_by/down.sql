@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM roles WHERE name = 'inviter';
+DROP INDEX invitations_invited_by_idx;
+ALTER TABLE invitations DROP COLUMN invited_by;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE invitations ADD COLUMN invited_by VARCHAR(100) REFERENCES users (email) ON DELETE SET NULL;
+CREATE INDEX invitations_invited_by_idx ON invitations (invited_by, created_at);
+INSERT INTO roles (name, description) VALUES ('inviter', 'Send invitations to new users');
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE user_identities (
+  provider VARCHAR(64) NOT NULL,
+  subject VARCHAR(255) NOT NULL,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  provider_email VARCHAR(255),
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  last_login_at TIMESTAMP,
+  PRIMARY KEY (provider, subject),
+  UNIQUE (email, provider)
+);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE pending_auths (
+  state_hash VARCHAR(64) NOT NULL PRIMARY KEY, --sha256 hash of the csrf state
+  provider VARCHAR(64) NOT NULL,
+  link_to VARCHAR(100) REFERENCES users (email) ON DELETE CASCADE,
+  nonce VARCHAR(64) NOT NULL,
+  code_verifier VARCHAR(64) NOT NULL,
+  final_url TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX pending_auths_expires_at_idx ON pending_auths (expires_at);
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE oauth_clients (
+  client_id VARCHAR(64) NOT NULL PRIMARY KEY,
+  name VARCHAR(100) NOT NULL,
+  secret_hash VARCHAR(64) NOT NULL, --sha256 hash
+  created_at TIMESTAMP NOT NULL,
+  disabled_at TIMESTAMP
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE oauth_consents;
+DROP TABLE oauth_codes;
+ALTER TABLE sessions DROP COLUMN client_id;
+ALTER TABLE oauth_clients DROP COLUMN public_client;
+ALTER TABLE oauth_clients DROP COLUMN redirect_uris;
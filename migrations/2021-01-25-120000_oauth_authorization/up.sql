@@ -0,0 +1,25 @@
+-- Your SQL goes here
+ALTER TABLE oauth_clients ADD COLUMN redirect_uris TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE oauth_clients ADD COLUMN public_client BOOLEAN NOT NULL DEFAULT false;
+
+-- sessions started by an authorization of a client, refresh tokens stay bound to it
+ALTER TABLE sessions ADD COLUMN client_id VARCHAR(64) REFERENCES oauth_clients (client_id) ON DELETE CASCADE;
+
+CREATE TABLE oauth_codes (
+  code_hash VARCHAR(64) NOT NULL PRIMARY KEY, --sha256 hash
+  client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  code_challenge VARCHAR(64) NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE oauth_consents (
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+  scope TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (email, client_id)
+);
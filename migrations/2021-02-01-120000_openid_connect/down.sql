@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE oauth_codes DROP COLUMN auth_methods;
+ALTER TABLE oauth_codes DROP COLUMN auth_time;
+ALTER TABLE oauth_codes DROP COLUMN nonce;
+
+ALTER TABLE sessions DROP COLUMN scope;
+ALTER TABLE sessions DROP COLUMN auth_methods;
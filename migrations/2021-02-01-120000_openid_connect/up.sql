@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- how the user authenticated (RFC 8176 amr values) and the scope granted to an oauth client
+ALTER TABLE sessions ADD COLUMN auth_methods TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE sessions ADD COLUMN scope TEXT;
+
+-- copied from the browser session when the code is issued, for the id token
+ALTER TABLE oauth_codes ADD COLUMN nonce TEXT;
+ALTER TABLE oauth_codes ADD COLUMN auth_time TIMESTAMP NOT NULL DEFAULT now();
+ALTER TABLE oauth_codes ADD COLUMN auth_methods TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE service_account_secrets;
+DROP TABLE service_accounts;
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE service_accounts (
+  account_id VARCHAR(64) NOT NULL PRIMARY KEY,
+  description TEXT NOT NULL,
+  scopes TEXT[] NOT NULL DEFAULT '{}',
+  created_at TIMESTAMP NOT NULL,
+  disabled_at TIMESTAMP
+);
+
+-- an account has more than one secret while a rotation is in progress, the replaced ones
+-- expire once the overlap is over
+CREATE TABLE service_account_secrets (
+  secret_hash VARCHAR(64) NOT NULL PRIMARY KEY, --sha256 hash
+  account_id VARCHAR(64) NOT NULL REFERENCES service_accounts (account_id) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP
+);
+
+CREATE INDEX service_account_secrets_account_id_idx ON service_account_secrets (account_id);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE personal_access_tokens (
+  id UUID NOT NULL PRIMARY KEY,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  token_hash VARCHAR(64) NOT NULL UNIQUE, --sha256 hash
+  scopes TEXT[] NOT NULL DEFAULT '{}',
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  last_used_at TIMESTAMP,
+  revoked_at TIMESTAMP
+);
+
+CREATE INDEX personal_access_tokens_email_idx ON personal_access_tokens (email);
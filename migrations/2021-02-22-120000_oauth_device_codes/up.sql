@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE oauth_device_codes (
+  device_code_hash VARCHAR(64) NOT NULL PRIMARY KEY, --sha256 hash
+  user_code VARCHAR(8) NOT NULL UNIQUE,
+  client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+  scope TEXT NOT NULL,
+  -- seconds the client has to wait between polls, raised on slow_down
+  poll_interval INTEGER NOT NULL,
+  last_polled_at TIMESTAMP,
+  -- set when the user approves, together with how the user logged in
+  email VARCHAR(100) REFERENCES users (email) ON DELETE CASCADE,
+  auth_time TIMESTAMP,
+  auth_methods TEXT[] NOT NULL DEFAULT '{}',
+  denied BOOLEAN NOT NULL DEFAULT false,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM oauth_codes WHERE redirect_uri IS NULL;
+ALTER TABLE oauth_codes ALTER COLUMN redirect_uri SET NOT NULL;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- the redirect_uri parameter exactly as sent to /oauth/authorize, NULL if it was left out
+ALTER TABLE oauth_codes ALTER COLUMN redirect_uri DROP NOT NULL;
@@ -0,0 +1,373 @@
+use async_trait::async_trait;
+use chrono::{Local, NaiveDateTime};
+use diesel::{
+    dsl::count_star, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    PgTextExpressionMethods, QueryDsl, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::{
+    errors::ServiceError,
+    logged_user::{LoggedUser, AUTHORIZED_USERS, SESSIONS, TRIGGER_DB_UPDATE},
+    models::{DbExecutor, HandleRequest, User},
+    role_handler::{get_user_roles, grant_role, revoke_role},
+    session_handler::revoke_user_sessions,
+    utils::{get_random_token, hash_password},
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Offset and page size of a listing, the size is capped so one request cannot load a whole
+/// table
+pub fn page_bounds(offset: Option<i64>, limit: Option<i64>) -> (i64, i64) {
+    (
+        offset.unwrap_or(0).max(0),
+        limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserList {
+    pub total: i64,
+    pub offset: i64,
+    pub limit: i64,
+    pub users: Vec<UserSummary>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListUsers {
+    // case insensitive substring of the email
+    pub search: Option<String>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[async_trait]
+impl HandleRequest<ListUsers> for DbExecutor {
+    type Result = Result<UserList, ServiceError>;
+
+    async fn handle(&self, msg: ListUsers) -> Self::Result {
+        use crate::schema::{user_roles, users};
+
+        let (offset, limit) = page_bounds(msg.offset, msg.limit);
+        let pattern = msg.search.map(|search| {
+            let escaped = search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let mut count_query = users::table.select(count_star()).into_boxed();
+            let mut page_query = users::table.into_boxed();
+            if let Some(pattern) = &pattern {
+                count_query = count_query.filter(users::email.ilike(pattern.clone()));
+                page_query = page_query.filter(users::email.ilike(pattern.clone()));
+            }
+            let total = count_query.first::<i64>(&conn)?;
+            let page: Vec<User> = page_query
+                .order(users::email.asc())
+                .offset(offset)
+                .limit(limit)
+                .load(&conn)?;
+
+            let emails: Vec<&String> = page.iter().map(|user| &user.email).collect();
+            let mut roles: HashMap<String, Vec<String>> = HashMap::new();
+            for (email, role) in user_roles::table
+                .filter(user_roles::email.eq_any(emails))
+                .select((user_roles::email, user_roles::role))
+                .order(user_roles::role.asc())
+                .load::<(String, String)>(&conn)?
+            {
+                roles.entry(email).or_default().push(role);
+            }
+
+            let users = page
+                .into_iter()
+                .map(|user| UserSummary {
+                    roles: roles.remove(&user.email).unwrap_or_default(),
+                    email: user.email,
+                    created_at: user.created_at,
+                    disabled_at: user.disabled_at,
+                })
+                .collect();
+            Ok(UserList {
+                total,
+                offset,
+                limit,
+                users,
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDetails {
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub roles: Vec<String>,
+    pub mfa_enabled: bool,
+    pub passkeys: i64,
+    pub active_sessions: i64,
+}
+
+#[derive(Debug)]
+pub struct GetUser {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<GetUser> for DbExecutor {
+    type Result = Result<Option<UserDetails>, ServiceError>;
+
+    async fn handle(&self, msg: GetUser) -> Self::Result {
+        use crate::schema::{sessions, user_mfa, users, webauthn_credentials};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let user = match users::table
+                .find(&msg.email)
+                .first::<User>(&conn)
+                .optional()?
+            {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+            let mfa_enabled = user_mfa::table
+                .find(&user.email)
+                .select(user_mfa::enabled)
+                .first::<bool>(&conn)
+                .optional()?
+                .unwrap_or(false);
+            let passkeys = webauthn_credentials::table
+                .filter(webauthn_credentials::email.eq(&user.email))
+                .select(count_star())
+                .first(&conn)?;
+            let active_sessions = sessions::table
+                .filter(sessions::email.eq(&user.email))
+                .filter(sessions::revoked_at.is_null())
+                .select(count_star())
+                .first(&conn)?;
+            Ok(Some(UserDetails {
+                roles: get_user_roles(&conn, &user.email)?,
+                email: user.email,
+                created_at: user.created_at,
+                disabled_at: user.disabled_at,
+                mfa_enabled,
+                passkeys,
+                active_sessions,
+            }))
+        })
+        .await?
+    }
+}
+
+// admins must not lock themselves out
+fn check_not_self(admin: &LoggedUser, email: &str) -> Result<(), ServiceError> {
+    if admin.email == email {
+        Err(ServiceError::BadRequest(
+            "Admins cannot do this to their own account".into(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Apply an admin change to a user in one transaction, optionally logging out all of their
+/// sessions, then update the authorization caches right away rather than waiting for the
+/// next db poll
+async fn update_user<F>(
+    dbex: &DbExecutor,
+    email: String,
+    revoke_sessions: bool,
+    authorized: Option<bool>,
+    f: F,
+) -> Result<bool, ServiceError>
+where
+    F: FnOnce(&PgConnection, &str) -> Result<bool, ServiceError> + Send + 'static,
+{
+    let dbex = dbex.clone();
+    let user_email = email.clone();
+    let (changed, revoked) = spawn_blocking(move || {
+        let conn = dbex.0.get()?;
+        conn.transaction(|| -> Result<(bool, Vec<Uuid>), ServiceError> {
+            let revoked = if revoke_sessions {
+                revoke_user_sessions(&conn, &user_email, None)?
+            } else {
+                Vec::new()
+            };
+            Ok((f(&conn, &user_email)?, revoked))
+        })
+    })
+    .await??;
+    SESSIONS.revoke(&revoked);
+    if changed {
+        if let Some(authorized) = authorized {
+            AUTHORIZED_USERS
+                .store_auth(LoggedUser::from_email(email), authorized)
+                .map_err(|_| ServiceError::InternalServerError)?;
+        }
+        TRIGGER_DB_UPDATE.set();
+    }
+    Ok(changed)
+}
+
+#[derive(Debug)]
+pub struct SetUserDisabled {
+    pub admin: LoggedUser,
+    pub email: String,
+    pub disabled: bool,
+}
+
+#[async_trait]
+impl HandleRequest<SetUserDisabled> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: SetUserDisabled) -> Self::Result {
+        use crate::schema::users::dsl::{disabled_at, email, users};
+
+        check_not_self(&msg.admin, &msg.email)?;
+        let disabled = msg.disabled;
+        update_user(
+            self,
+            msg.email,
+            disabled,
+            Some(!disabled),
+            move |conn, email_| {
+                let new_value = if disabled {
+                    Some(Local::now().naive_local())
+                } else {
+                    None
+                };
+                diesel::update(users.filter(email.eq(email_)))
+                    .set(disabled_at.eq(new_value))
+                    .execute(conn)
+                    .map(|changed| changed > 0)
+                    .map_err(Into::into)
+            },
+        )
+        .await
+    }
+}
+
+// the current password stops working and every session is logged out, the user then gets
+// a password reset email
+#[derive(Debug)]
+pub struct ForcePasswordReset {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<ForcePasswordReset> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: ForcePasswordReset) -> Self::Result {
+        use crate::schema::users::dsl::{email, password, users};
+
+        update_user(self, msg.email, true, None, |conn, email_| {
+            // bcrypt is slow, it runs on the blocking thread with the update
+            let password_ = hash_password(&get_random_token())?;
+            diesel::update(users.filter(email.eq(email_)))
+                .set(password.eq(password_))
+                .execute(conn)
+                .map(|changed| changed > 0)
+                .map_err(Into::into)
+        })
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteUser {
+    pub admin: LoggedUser,
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<DeleteUser> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: DeleteUser) -> Self::Result {
+        use crate::schema::users::dsl::{email, users};
+
+        check_not_self(&msg.admin, &msg.email)?;
+        update_user(self, msg.email, true, Some(false), |conn, email_| {
+            diesel::delete(users.filter(email.eq(email_)))
+                .execute(conn)
+                .map(|deleted| deleted > 0)
+                .map_err(Into::into)
+        })
+        .await
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RolesData {
+    pub roles: Vec<String>,
+}
+
+// replace the roles of a user, they apply to new tokens, i.e. at the latest after the next
+// refresh
+#[derive(Debug)]
+pub struct SetUserRoles {
+    pub admin: LoggedUser,
+    pub email: String,
+    pub roles: Vec<String>,
+}
+
+#[async_trait]
+impl HandleRequest<SetUserRoles> for DbExecutor {
+    type Result = Result<Vec<String>, ServiceError>;
+
+    async fn handle(&self, msg: SetUserRoles) -> Self::Result {
+        use crate::schema::users::dsl::users;
+
+        if msg.admin.email == msg.email && !msg.roles.iter().any(|role| role == "admin") {
+            return Err(ServiceError::BadRequest(
+                "Admins cannot remove their own admin role".into(),
+            ));
+        }
+        let dbex = self.clone();
+        let roles = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| -> Result<_, ServiceError> {
+                users
+                    .find(&msg.email)
+                    .first::<User>(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest("Unknown user".into()))?;
+                for role in get_user_roles(&conn, &msg.email)? {
+                    if !msg.roles.contains(&role) {
+                        revoke_role(&conn, &msg.email, &role)?;
+                    }
+                }
+                for role in &msg.roles {
+                    grant_role(&conn, &msg.email, role)?;
+                }
+                get_user_roles(&conn, &msg.email).map_err(Into::into)
+            })
+        })
+        .await??;
+        // the cached users carry their roles
+        TRIGGER_DB_UPDATE.set();
+        Ok(roles)
+    }
+}
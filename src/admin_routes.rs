@@ -0,0 +1,300 @@
+use actix_web::{
+    web::{Data, Json, Path, Query},
+    Error, HttpResponse, ResponseError,
+};
+use chrono::Duration;
+use maplit::hashmap;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    admin_handler::{
+        DeleteUser, ForcePasswordReset, GetUser, ListUsers, RolesData, SetUserDisabled,
+        SetUserRoles,
+    },
+    errors::ServiceError,
+    invitation_handler::{ListInvitations, ResendInvitation, RevokeInvitation},
+    logged_user::{Admin, RequireRole},
+    models::{DbExecutor, HandleRequest},
+    oauth_client_handler::{CreateOAuthClient, DeleteOAuthClient, ListOAuthClients},
+    password_reset_handler::RequestPasswordReset,
+    service_account_handler::{
+        CreateServiceAccount, ListServiceAccounts, RotateServiceAccountSecret,
+        SetServiceAccountDisabled,
+    },
+};
+
+fn status_response(success: bool) -> HttpResponse {
+    let status = if success { "success" } else { "failure" };
+    HttpResponse::Ok().json(hashmap! { "status" => status })
+}
+
+pub async fn list_users(
+    _admin: RequireRole<Admin>,
+    query: Query<ListUsers>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(query.into_inner()).await {
+        Ok(users) => Ok(HttpResponse::Ok().json(users)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn get_user(
+    _admin: RequireRole<Admin>,
+    email: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = GetUser {
+        email: email.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(Some(user)) => Ok(HttpResponse::Ok().json(user)),
+        Ok(None) => Ok(HttpResponse::NotFound().json("Unknown user")),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+async fn set_disabled(
+    admin: RequireRole<Admin>,
+    email: Path<String>,
+    db: Data<DbExecutor>,
+    disabled: bool,
+) -> Result<HttpResponse, Error> {
+    let msg = SetUserDisabled {
+        admin: admin.user,
+        email: email.into_inner(),
+        disabled,
+    };
+    match db.handle(msg).await {
+        Ok(success) => Ok(status_response(success)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn disable_user(
+    admin: RequireRole<Admin>,
+    email: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    set_disabled(admin, email, db, true).await
+}
+
+pub async fn enable_user(
+    admin: RequireRole<Admin>,
+    email: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    set_disabled(admin, email, db, false).await
+}
+
+pub async fn force_password_reset(
+    _admin: RequireRole<Admin>,
+    email: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let email = email.into_inner();
+    let result: Result<bool, ServiceError> = async {
+        let changed = db
+            .handle(ForcePasswordReset {
+                email: email.clone(),
+            })
+            .await?;
+        if changed {
+            db.handle(RequestPasswordReset { email }).await?;
+        }
+        Ok(changed)
+    }
+    .await;
+    match result {
+        Ok(success) => Ok(status_response(success)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn delete_user(
+    admin: RequireRole<Admin>,
+    email: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = DeleteUser {
+        admin: admin.user,
+        email: email.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => Ok(status_response(success)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn set_roles(
+    admin: RequireRole<Admin>,
+    email: Path<String>,
+    roles_data: Json<RolesData>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = SetUserRoles {
+        admin: admin.user,
+        email: email.into_inner(),
+        roles: roles_data.into_inner().roles,
+    };
+    match db.handle(msg).await {
+        Ok(roles) => Ok(HttpResponse::Ok().json(roles)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn list_invitations(
+    _admin: RequireRole<Admin>,
+    query: Query<ListInvitations>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(query.into_inner()).await {
+        Ok(invitations) => Ok(HttpResponse::Ok().json(invitations)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn resend_invitation(
+    _admin: RequireRole<Admin>,
+    id: Path<Uuid>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ResendInvitation {
+        id: id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(invitation) => Ok(HttpResponse::Ok().json(invitation)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn revoke_invitation(
+    _admin: RequireRole<Admin>,
+    id: Path<Uuid>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = RevokeInvitation {
+        id: id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => Ok(status_response(success)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn list_oauth_clients(
+    _admin: RequireRole<Admin>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(ListOAuthClients).await {
+        Ok(clients) => Ok(HttpResponse::Ok().json(clients)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn create_oauth_client(
+    _admin: RequireRole<Admin>,
+    client_data: Json<CreateOAuthClient>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(client_data.into_inner()).await {
+        Ok(client) => Ok(HttpResponse::Ok().json(client)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn delete_oauth_client(
+    _admin: RequireRole<Admin>,
+    client_id: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = DeleteOAuthClient {
+        client_id: client_id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => Ok(status_response(success)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn list_service_accounts(
+    _admin: RequireRole<Admin>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(ListServiceAccounts).await {
+        Ok(accounts) => Ok(HttpResponse::Ok().json(accounts)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn create_service_account(
+    _admin: RequireRole<Admin>,
+    account_data: Json<CreateServiceAccount>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(account_data.into_inner()).await {
+        Ok(account) => Ok(HttpResponse::Ok().json(account)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+async fn set_service_account_disabled(
+    account_id: Path<String>,
+    db: Data<DbExecutor>,
+    disabled: bool,
+) -> Result<HttpResponse, Error> {
+    let msg = SetServiceAccountDisabled {
+        account_id: account_id.into_inner(),
+        disabled,
+    };
+    match db.handle(msg).await {
+        Ok(success) => Ok(status_response(success)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn disable_service_account(
+    _admin: RequireRole<Admin>,
+    account_id: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    set_service_account_disabled(account_id, db, true).await
+}
+
+pub async fn enable_service_account(
+    _admin: RequireRole<Admin>,
+    account_id: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    set_service_account_disabled(account_id, db, false).await
+}
+
+fn default_overlap_hours() -> i64 {
+    24
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotateSecretData {
+    // how long the replaced secrets keep working
+    #[serde(default = "default_overlap_hours")]
+    pub overlap_hours: i64,
+}
+
+pub async fn rotate_service_account_secret(
+    _admin: RequireRole<Admin>,
+    account_id: Path<String>,
+    rotate_data: Option<Json<RotateSecretData>>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let overlap_hours = rotate_data.map_or_else(default_overlap_hours, |data| data.overlap_hours);
+    let msg = RotateServiceAccountSecret {
+        account_id: account_id.into_inner(),
+        overlap: Duration::hours(overlap_hours.max(0)),
+    };
+    match db.handle(msg).await {
+        Ok(Some(secret)) => Ok(HttpResponse::Ok().json(secret)),
+        Ok(None) => Ok(HttpResponse::NotFound().json("Unknown service account")),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
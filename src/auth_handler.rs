@@ -2,7 +2,7 @@ use actix_identity::Identity;
 use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
 use async_trait::async_trait;
 use bcrypt::verify;
-use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use diesel::{ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
 use futures::{
     executor::block_on,
     future::{ready, Ready},
@@ -13,22 +13,34 @@ use tokio::task::spawn_blocking;
 
 use crate::{
     errors::ServiceError,
+    mfa_handler::start_mfa_login,
     models::{DbExecutor, HandleRequest, SlimUser, User},
-    utils::Token,
 };
 
 #[derive(Debug, Deserialize)]
 pub struct AuthData {
     pub email: String,
     pub password: String,
+    // respond with the access and refresh token, for clients that do not keep cookies
+    #[serde(default)]
+    pub return_token: bool,
+}
+
+pub enum LoginResult {
+    LoggedIn(SlimUser),
+    // password was correct, but a second factor is required before issuing a token
+    MfaRequired(String),
 }
 
 #[async_trait]
 impl HandleRequest<AuthData> for DbExecutor {
-    type Result = Result<(SlimUser, Token), ServiceError>;
+    type Result = Result<LoginResult, ServiceError>;
 
     async fn handle(&self, msg: AuthData) -> Self::Result {
-        use crate::schema::users::dsl::{email, users};
+        use crate::schema::{
+            user_mfa::dsl::{enabled, user_mfa},
+            users::dsl::{email, users},
+        };
 
         let dbex = self.clone();
         spawn_blocking(move || {
@@ -37,9 +49,16 @@ impl HandleRequest<AuthData> for DbExecutor {
             let user = users.filter(email.eq(&email_)).first::<User>(&conn)?;
             match verify(&msg.password, &user.password) {
                 Ok(true) => {
-                    let user: SlimUser = user.into();
-                    let token = Token::create_token(&user)?;
-                    Ok((user, token))
+                    let mfa_enabled = user_mfa
+                        .find(&user.email)
+                        .select(enabled)
+                        .first::<bool>(&conn)
+                        .optional()?
+                        .unwrap_or(false);
+                    if mfa_enabled {
+                        return Ok(LoginResult::MfaRequired(start_mfa_login(user.email)));
+                    }
+                    Ok(LoginResult::LoggedIn(user.into()))
                 }
                 _ => Err(ServiceError::BadRequest(
                     "Username and Password don't match".into(),
@@ -1,39 +1,182 @@
 use actix::Addr;
 use actix_identity::Identity;
 use actix_web::{
+    cookie::Cookie,
+    http::header::USER_AGENT,
     web,
     web::{Data, Json},
-    Error, HttpRequest, HttpResponse, Responder, ResponseError,
+    Error, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError,
 };
 use futures::Future;
+use serde::Serialize;
 
 use crate::{
-    auth_handler::AuthData,
-    logged_user::LoggedUser,
-    models::{DbExecutor, HandleRequest},
-    utils::Token,
+    auth_handler::{AuthData, LoginResult},
+    errors::ServiceError,
+    jwt_keys::get_jwks,
+    logged_user::{bearer_token, LoggedUser, PERSONAL_TOKEN_PREFIX},
+    models::{DbExecutor, HandleRequest, SlimUser},
+    refresh_token_handler::{
+        refresh_cookie, IssueRefreshToken, RevokeRefreshToken, REFRESH_COOKIE,
+    },
+    role_handler::GetUserRoles,
+    session_handler::{CreateSession, RevokeSession},
+    utils::{access_token_lifetime, Claim, Token},
 };
 
+#[derive(Serialize)]
+struct MfaRequiredResponse {
+    mfa_required: bool,
+    mfa_token: String,
+}
+
+#[derive(Serialize)]
+struct TokenLoginResponse {
+    email: String,
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    refresh_token: String,
+}
+
+// record a new session for the user, authenticated by the RFC 8176 `auth_methods`, returning
+// its access token and the first refresh token of its family
+async fn create_session(
+    user: &SlimUser,
+    auth_methods: &[&str],
+    request: &HttpRequest,
+    db: &DbExecutor,
+) -> Result<(String, String), ServiceError> {
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(ToString::to_string);
+    let ip_address = request
+        .connection_info()
+        .realip_remote_addr()
+        .map(ToString::to_string);
+    let session = db
+        .handle(CreateSession {
+            email: user.email.clone(),
+            user_agent,
+            ip_address,
+            client_id: None,
+            auth_methods: auth_methods.iter().map(ToString::to_string).collect(),
+            scope: None,
+        })
+        .await?;
+    let roles = db
+        .handle(GetUserRoles {
+            email: user.email.clone(),
+        })
+        .await?;
+    let token = Token::create_token(user, session.id, roles)?;
+    let refresh_token = db
+        .handle(IssueRefreshToken {
+            email: user.email.clone(),
+            session_id: session.id,
+        })
+        .await?;
+    Ok((token.into(), refresh_token))
+}
+
+/// Start a session, store its access token in the auth cookie and return the refresh cookie
+/// to set
+pub async fn start_session(
+    user: &SlimUser,
+    auth_methods: &[&str],
+    request: &HttpRequest,
+    id: &Identity,
+    db: &DbExecutor,
+) -> Result<Cookie<'static>, ServiceError> {
+    let (token, refresh_token) = create_session(user, auth_methods, request, db).await?;
+    id.remember(token);
+    Ok(refresh_cookie(refresh_token))
+}
+
+/// Start a session and respond with the user, shared by every way of logging in. API clients
+/// ask for `return_token` to get the tokens in the body, the cookies are set either way.
+pub async fn complete_login(
+    user: &SlimUser,
+    auth_methods: &[&str],
+    return_token: bool,
+    request: &HttpRequest,
+    id: &Identity,
+    db: &DbExecutor,
+) -> Result<HttpResponse, ServiceError> {
+    let (token, refresh_token) = create_session(user, auth_methods, request, db).await?;
+    id.remember(token.clone());
+    let mut response = HttpResponse::Ok();
+    response.cookie(refresh_cookie(refresh_token.clone()));
+    if return_token {
+        Ok(response.json(TokenLoginResponse {
+            email: user.email.clone(),
+            access_token: token,
+            token_type: "Bearer",
+            expires_in: access_token_lifetime().num_seconds(),
+            refresh_token,
+        }))
+    } else {
+        Ok(response.json(user))
+    }
+}
+
 pub async fn login(
     auth_data: Json<AuthData>,
+    request: HttpRequest,
     id: Identity,
     db: Data<DbExecutor>,
 ) -> Result<HttpResponse, Error> {
-    db.handle(auth_data.into_inner())
-        .await
-        .map(|(user, token)| {
-            id.remember(token.into());
-            HttpResponse::Ok().json(user)
-        })
-        .map_err(Into::into)
+    let return_token = auth_data.return_token;
+    match db.handle(auth_data.into_inner()).await? {
+        LoginResult::LoggedIn(user) => {
+            complete_login(&user, &["pwd"], return_token, &request, &id, &db)
+                .await
+                .map_err(Into::into)
+        }
+        LoginResult::MfaRequired(mfa_token) => Ok(HttpResponse::Ok().json(MfaRequiredResponse {
+            mfa_required: true,
+            mfa_token,
+        })),
+    }
 }
 
-#[allow(clippy::needless_pass_by_value)]
-pub fn logout(id: Identity) -> HttpResponse {
+pub async fn logout(
+    request: HttpRequest,
+    id: Identity,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    // the access token may already be expired, the refresh cookie covers that case
+    let token = match bearer_token(&request) {
+        Some(token) if !token.starts_with(PERSONAL_TOKEN_PREFIX) => Some(token.to_string()),
+        _ => id.identity(),
+    };
+    let claim = token
+        .and_then(|token| Token::decode_token(&token.into()).ok())
+        .filter(Claim::is_first_party);
+    if let Some(claim) = claim {
+        let session_id = claim.get_session_id();
+        if let Some(email) = claim.get_email() {
+            db.handle(RevokeSession { session_id, email }).await?;
+        }
+    }
+    if let Some(cookie) = request.cookie(REFRESH_COOKIE) {
+        db.handle(RevokeRefreshToken {
+            refresh_token: cookie.value().to_string(),
+        })
+        .await?;
+    }
     id.forget();
-    HttpResponse::Ok().finish()
+    Ok(HttpResponse::Ok()
+        .del_cookie(&refresh_cookie(String::new()))
+        .finish())
 }
 
 pub fn get_me(logged_user: LoggedUser) -> HttpResponse {
     HttpResponse::Ok().json(logged_user)
 }
+
+pub async fn jwks() -> HttpResponse {
+    HttpResponse::Ok().json(get_jwks())
+}
@@ -1,13 +1,16 @@
 use async_trait::async_trait;
 use chrono::Local;
-use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use serde::{Deserialize, Serialize};
 use tokio::task::spawn_blocking;
 use uuid::Uuid;
 
 use crate::{
     errors::ServiceError,
+    logged_user::{PERSONAL_TOKENS, SESSIONS},
     models::{DbExecutor, HandleRequest, Invitation, SlimUser, User},
+    personal_token_handler::revoke_user_personal_tokens,
+    session_handler::revoke_user_sessions,
     utils::hash_password,
 };
 
@@ -22,6 +25,9 @@ pub struct UserData {
 pub struct ChangePassword {
     pub email: String,
     pub password: String,
+    // the session changing the password stays logged in, all others and every personal access
+    // token are revoked
+    pub session: Option<Uuid>,
 }
 
 #[async_trait]
@@ -35,16 +41,78 @@ impl HandleRequest<ChangePassword> for DbExecutor {
         };
 
         let dbex = self.clone();
-        spawn_blocking(move || {
+        let (changed, revoked, revoked_tokens) = spawn_blocking(move || {
             let conn = dbex.0.get()?;
             let password_: String = hash_password(&msg.password)?;
 
-            diesel::update(users.filter(email.eq(msg.email)))
-                .set(password.eq(password_))
-                .execute(&conn)
-                .map_err(|_db_error| ServiceError::BadRequest("Update failed".into()))
-                .map(|changed| changed > 0)
+            conn.transaction(|| -> Result<(bool, Vec<Uuid>, Vec<Uuid>), ServiceError> {
+                let changed = diesel::update(users.filter(email.eq(&msg.email)))
+                    .set(password.eq(password_))
+                    .execute(&conn)
+                    .map_err(|_db_error| ServiceError::BadRequest("Update failed".into()))?;
+                let revoked = revoke_user_sessions(&conn, &msg.email, msg.session)?;
+                let revoked_tokens = revoke_user_personal_tokens(&conn, &msg.email)?;
+                Ok((changed > 0, revoked, revoked_tokens))
+            })
         })
-        .await?
+        .await??;
+        SESSIONS.revoke(&revoked);
+        for token_id in revoked_tokens {
+            PERSONAL_TOKENS.remove(token_id);
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    use crate::{
+        change_password_handler::ChangePassword,
+        logged_user::PERSONAL_TOKENS,
+        models::{HandleRequest, User},
+        personal_token_handler::{CreatePersonalToken, PersonalTokenData},
+        rust_auth_server::{get_pool, load_config},
+        utils::get_random_string,
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_change_password_revokes_personal_tokens() -> Result<(), Error> {
+        use crate::schema::users;
+
+        load_config();
+        let pool = get_pool();
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        diesel::insert_into(users::table)
+            .values(&User::from_details(email.clone(), String::new()))
+            .execute(&pool.0.get()?)?;
+        let msg = CreatePersonalToken {
+            email: email.clone(),
+            roles: Vec::new(),
+            data: PersonalTokenData {
+                name: "script".into(),
+                scopes: Vec::new(),
+                expires_in_days: 30,
+            },
+        };
+        let personal_token = pool.handle(msg).await?;
+        let authenticated_before = PERSONAL_TOKENS.authenticate(&personal_token.token);
+
+        let msg = ChangePassword {
+            email: email.clone(),
+            password: "new password".into(),
+            session: None,
+        };
+        let changed = pool.handle(msg).await?;
+        let authenticated_after = PERSONAL_TOKENS.authenticate(&personal_token.token);
+
+        diesel::delete(users::table.filter(users::email.eq(&email))).execute(&pool.0.get()?)?;
+        assert!(authenticated_before.is_some());
+        assert!(changed);
+        assert!(authenticated_after.is_none());
+        Ok(())
     }
 }
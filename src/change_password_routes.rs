@@ -9,19 +9,20 @@ use maplit::hashmap;
 
 use crate::{
     change_password_handler::{ChangePassword, UserData},
-    logged_user::LoggedUser,
+    logged_user::SessionUser,
     models::{DbExecutor, HandleRequest},
 };
 
 pub async fn change_password_user(
-    logged_user: LoggedUser,
+    session_user: SessionUser,
     user_data: Json<UserData>,
     db: Data<DbExecutor>,
 ) -> Result<HttpResponse, Error> {
     let msg = ChangePassword {
         // into_inner() returns the inner string value from Path
-        email: logged_user.email,
+        email: session_user.email,
         password: user_data.password.clone(),
+        session: Some(session_user.session),
     };
 
     let db_response = db.handle(msg).await;
@@ -35,3 +36,63 @@ pub async fn change_password_user(
         Err(service_error) => Ok(service_error.error_response()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test::{self, TestRequest},
+        web, App,
+    };
+    use chrono::{Duration, Local};
+    use uuid::Uuid;
+
+    use crate::{
+        change_password_routes::change_password_user,
+        logged_user::{LoggedUser, AUTHORIZED_USERS, PERSONAL_TOKENS, PERSONAL_TOKEN_PREFIX},
+        models::PersonalAccessToken,
+        rust_auth_server::{get_pool, load_config},
+        utils::{get_random_string, hash_token},
+    };
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_personal_token_cannot_change_password() {
+        load_config();
+        let pool = get_pool();
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .route("/api/password_change", web::post().to(change_password_user)),
+        )
+        .await;
+
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        AUTHORIZED_USERS
+            .store_auth(LoggedUser::from_email(email.clone()), true)
+            .unwrap();
+        let token = format!("{}{}", PERSONAL_TOKEN_PREFIX, get_random_string());
+        let current_time = Local::now().naive_local();
+        let personal_token = PersonalAccessToken {
+            id: Uuid::new_v4(),
+            email,
+            name: "script".into(),
+            token_hash: hash_token(&token),
+            scopes: Vec::new(),
+            created_at: current_time,
+            expires_at: current_time + Duration::days(1),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        PERSONAL_TOKENS.insert(&personal_token, &[]);
+
+        let request = TestRequest::post()
+            .uri("/api/password_change")
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .set_json(&serde_json::json!({"password": "taken over"}))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        PERSONAL_TOKENS.remove(personal_token.id);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
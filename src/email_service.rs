@@ -2,7 +2,11 @@ use anyhow::{format_err, Error};
 use log::debug;
 use std::env;
 
-use crate::{errors::ServiceError, models::Invitation, ses_client::SesInstance};
+use crate::{
+    errors::ServiceError,
+    models::{Invitation, PasswordReset},
+    ses_client::SesInstance,
+};
 
 pub async fn send_invitation(
     invitation: &Invitation,
@@ -21,10 +25,7 @@ pub async fn send_invitation(
         callback_url,
         invitation.id,
         invitation.email,
-        invitation
-            .expires_at
-            .format("%I:%M %p %A, %-d %B, %C%y")
-            .to_string(),
+        invitation.expires_at.format("%I:%M %p %A, %-d %B, %C%y"),
         callback_url,
     );
 
@@ -39,6 +40,40 @@ pub async fn send_invitation(
     .map_err(|e| ServiceError::BadRequest(format!("Bad request {:?}", e)))
 }
 
+pub async fn send_password_reset(
+    password_reset: &PasswordReset,
+    callback_url: &str,
+) -> Result<(), ServiceError> {
+    let ses = SesInstance::new(None);
+
+    let sending_email =
+        env::var("SENDING_EMAIL_ADDRESS").expect("SENDING_EMAIL_ADDRESS must be set");
+
+    let email_body = format!(
+        "Please click on the link below to reset your password. <br/>
+         <a href=\"{}?id={}&email={}\">
+         {}</a> <br>
+         this link can only be used once and expires on <strong>{}</strong>",
+        callback_url,
+        password_reset.id,
+        password_reset.email,
+        callback_url,
+        password_reset
+            .expires_at
+            .format("%I:%M %p %A, %-d %B, %C%y"),
+    );
+
+    ses.send_email(
+        &sending_email,
+        &password_reset.email,
+        "Password reset for Simple-Auth-Server Rust",
+        &email_body,
+    )
+    .await
+    .map(|_| debug!("Success"))
+    .map_err(|e| ServiceError::BadRequest(format!("Bad request {:?}", e)))
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::{Duration, Local};
@@ -61,11 +96,7 @@ mod tests {
             dotenv::dotenv().ok();
         }
 
-        let new_invitation = Invitation {
-            id: Uuid::new_v4(),
-            email: "ddboline.im@gmail.com".to_string(),
-            expires_at: Local::now().naive_local() + Duration::hours(24),
-        };
+        let new_invitation = Invitation::from_email("ddboline.im@gmail.com".to_string(), None);
 
         send_invitation(&new_invitation, "test_url").await?;
         Ok(())
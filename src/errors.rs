@@ -4,6 +4,7 @@ use derive_more::Display;
 use diesel::result::{DatabaseErrorKind, Error as DBError};
 use openid::error::{ClientError as OpenIdClientError, Error as OpenIdError};
 use r2d2::Error as R2D2Error;
+use reqwest::Error as ReqwestError;
 use std::{convert::From, fmt::Debug};
 use thiserror::Error;
 use tokio::task::JoinError;
@@ -19,6 +20,8 @@ pub enum ServiceError {
     BadRequest(String),
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("Forbidden")]
+    Forbidden,
     #[error("DBError")]
     DbError(#[from] DBError),
     #[error("blocking error {0}")]
@@ -45,6 +48,7 @@ impl ResponseError for ServiceError {
                             .replace("main.js", "../auth/main.js"),
                     )
             }
+            Self::Forbidden => HttpResponse::Forbidden().json("Forbidden"),
             _ => {
                 HttpResponse::InternalServerError().json("Internal Server Error, Please try later")
             }
@@ -77,3 +81,9 @@ impl From<OpenIdClientError> for ServiceError {
         Self::BadRequest(format!("OpenIdClientError {:?}", err))
     }
 }
+
+impl From<ReqwestError> for ServiceError {
+    fn from(err: ReqwestError) -> Self {
+        Self::BadRequest(format!("OAuth request failed {:?}", err))
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::{env::var, fs, path::Path};
+
+use crate::{invitation_handler::email_domain, logged_user::LoggedUser};
+
+/// Who may access the hosts matching `host`, an exact name or `*.example.com` for its
+/// subdomains. A rule without roles, emails and domains admits any logged in user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardAuthRule {
+    pub host: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+impl ForwardAuthRule {
+    pub fn matches_host(&self, host: &str) -> bool {
+        // the forwarded host may include the port
+        let host = host.rsplitn(2, ':').last().unwrap_or(host);
+        match self.host.strip_prefix("*.") {
+            Some(suffix) => host
+                .to_lowercase()
+                .strip_suffix(&suffix.to_lowercase())
+                .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+            None => self.host.eq_ignore_ascii_case(host),
+        }
+    }
+
+    pub fn allows(&self, user: &LoggedUser) -> bool {
+        if self.roles.is_empty() && self.emails.is_empty() && self.domains.is_empty() {
+            return true;
+        }
+        self.roles.iter().any(|role| user.has_role(role))
+            || self
+                .emails
+                .iter()
+                .any(|email| email.eq_ignore_ascii_case(&user.email))
+            || email_domain(&user.email)
+                .is_some_and(|domain| self.domains.iter().any(|d| d.eq_ignore_ascii_case(&domain)))
+    }
+}
+
+/// Contents of the file at `FORWARD_AUTH_RULES`, e.g.
+/// ```json
+/// {
+///   "rules": [
+///     {"host": "grafana.example.com", "roles": ["grafana"]},
+///     {"host": "*.internal.example.com", "domains": ["example.com"]}
+///   ]
+/// }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ForwardAuthConfig {
+    // the first rule matching the host applies
+    pub rules: Vec<ForwardAuthRule>,
+    // whether any logged in user may access hosts without a rule
+    #[serde(default)]
+    pub allow_unlisted_hosts: bool,
+}
+
+impl ForwardAuthConfig {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        serde_json::from_slice(&fs::read(path)?).map_err(Into::into)
+    }
+
+    /// Without `FORWARD_AUTH_RULES` every logged in user may access every host
+    pub fn from_env() -> Result<Self, Error> {
+        match var("FORWARD_AUTH_RULES") {
+            Ok(path) => Self::read(Path::new(&path)),
+            Err(_) => Ok(Self {
+                rules: Vec::new(),
+                allow_unlisted_hosts: true,
+            }),
+        }
+    }
+
+    pub fn is_allowed(&self, host: Option<&str>, user: &LoggedUser) -> bool {
+        let rule = host.and_then(|host| self.rules.iter().find(|rule| rule.matches_host(host)));
+        match rule {
+            Some(rule) => rule.allows(user),
+            None => self.allow_unlisted_hosts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{forward_auth::ForwardAuthConfig, logged_user::LoggedUser};
+
+    #[test]
+    fn test_forward_auth_rules() {
+        let config: ForwardAuthConfig = serde_json::from_str(
+            r#"{"rules": [
+                {"host": "grafana.example.com", "roles": ["grafana"],
+                 "emails": ["ops@partner.com"]},
+                {"host": "*.internal.example.com", "domains": ["example.com"]},
+                {"host": "wiki.example.com"}
+            ]}"#,
+        )
+        .unwrap();
+        let mut user = LoggedUser::from_email("dev@example.com".into());
+        let partner = LoggedUser::from_email("OPS@partner.com".into());
+
+        assert!(!config.is_allowed(Some("grafana.example.com"), &user));
+        assert!(config.is_allowed(Some("grafana.example.com"), &partner));
+        user.roles.push("grafana".into());
+        assert!(config.is_allowed(Some("Grafana.example.com:443"), &user));
+
+        assert!(config.is_allowed(Some("ci.internal.example.com"), &user));
+        assert!(!config.is_allowed(Some("ci.internal.example.com"), &partner));
+        assert!(!config.is_allowed(Some("internal.example.com"), &user));
+        assert!(!config.is_allowed(Some("evilinternal.example.com"), &user));
+
+        assert!(config.is_allowed(Some("wiki.example.com"), &partner));
+        assert!(!config.is_allowed(Some("other.example.com"), &user));
+        assert!(!config.is_allowed(None, &user));
+
+        let config = ForwardAuthConfig {
+            rules: Vec::new(),
+            allow_unlisted_hosts: true,
+        };
+        assert!(config.is_allowed(Some("other.example.com"), &partner));
+        assert!(config.is_allowed(None, &partner));
+    }
+}
@@ -0,0 +1,220 @@
+use actix_web::{
+    http::header::LOCATION,
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::env::var;
+use url::Url;
+
+use crate::{
+    forward_auth::ForwardAuthConfig, logged_user::LoggedUser, openid_providers::ProviderRegistry,
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct ForwardAuthQuery {
+    // respond to anonymous requests with a redirect to the login page instead of a 401
+    #[serde(default)]
+    redirect: bool,
+}
+
+fn forwarded_header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+// the url the proxy is asked to serve, only used for the rules and to return after a login
+fn original_url(request: &HttpRequest) -> Option<Url> {
+    let proto = forwarded_header(request, "X-Forwarded-Proto").unwrap_or("https");
+    let host = forwarded_header(request, "X-Forwarded-Host")?;
+    let uri = forwarded_header(request, "X-Forwarded-Uri").unwrap_or("/");
+    format!("{}://{}{}", proto, host, uri).parse().ok()
+}
+
+pub fn login_url(original_url: Option<Url>, providers: &ProviderRegistry) -> String {
+    let domain = var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    let mut login_url = format!("https://{}/auth/login.html", domain);
+    let original_url = original_url.and_then(|url| providers.redirect_url(url.as_str()).ok());
+    if let Some(original_url) = original_url {
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("rd", original_url.as_str())
+            .finish();
+        login_url = format!("{}?{}", login_url, query);
+    }
+    login_url
+}
+
+/// For nginx `auth_request` (anonymous requests get a 401) and Traefik `forwardAuth` (with
+/// `?redirect=true`, anonymous requests are sent to the login page), the proxy passes the
+/// `auth` cookie and `X-Forwarded-Host`/`X-Forwarded-Uri`. Allowed users get a 200 with
+/// `X-Auth-User` and `X-Auth-Roles` to hand on to the application.
+pub async fn forward_auth(
+    user: Option<LoggedUser>,
+    query: Query<ForwardAuthQuery>,
+    request: HttpRequest,
+    rules: Data<ForwardAuthConfig>,
+    providers: Data<ProviderRegistry>,
+) -> HttpResponse {
+    let original_url = original_url(&request);
+    match user {
+        Some(user) if rules.is_allowed(original_url.as_ref().and_then(Url::host_str), &user) => {
+            HttpResponse::Ok()
+                .header("X-Auth-User", user.email.as_str())
+                .header("X-Auth-Roles", user.roles.join(","))
+                .finish()
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None if query.redirect => HttpResponse::Found()
+            .header(LOCATION, login_url(original_url, &providers))
+            .finish(),
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReturnQuery {
+    rd: String,
+}
+
+/// Where the login page sends the browser after a login started by `forward_auth`
+pub async fn forward_auth_return(
+    query: Query<ReturnQuery>,
+    providers: Data<ProviderRegistry>,
+) -> HttpResponse {
+    match providers.redirect_url(&query.rd) {
+        Ok(url) => HttpResponse::SeeOther()
+            .header(LOCATION, url.as_str())
+            .finish(),
+        Err(_) => HttpResponse::SeeOther()
+            .header(LOCATION, "/auth/index.html")
+            .finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{
+            header::{AUTHORIZATION, LOCATION},
+            StatusCode,
+        },
+        test::{self, TestRequest},
+        web, App,
+    };
+    use uuid::Uuid;
+
+    use crate::{
+        forward_auth::ForwardAuthConfig,
+        forward_auth_routes::{forward_auth, forward_auth_return},
+        logged_user::{LoggedUser, AUTHORIZED_USERS},
+        models::SlimUser,
+        openid_providers::{ProviderRegistry, ProvidersConfig},
+        utils::Token,
+    };
+
+    fn bearer(email: &str, roles: Vec<String>) -> String {
+        AUTHORIZED_USERS
+            .store_auth(LoggedUser::from_email(email.into()), true)
+            .unwrap();
+        let user = SlimUser {
+            email: email.into(),
+        };
+        let token: String = Token::create_token(&user, Uuid::new_v4(), roles)
+            .unwrap()
+            .into();
+        format!("Bearer {}", token)
+    }
+
+    #[actix_rt::test]
+    async fn test_forward_auth() {
+        let rules: ForwardAuthConfig = serde_json::from_str(
+            r#"{"rules": [{"host": "grafana.example.com", "roles": ["grafana"]}]}"#,
+        )
+        .unwrap();
+        let providers = ProviderRegistry::new(
+            ProvidersConfig {
+                default: None,
+                providers: Vec::new(),
+            },
+            "example.com",
+            vec![
+                "https://example.com/".parse().unwrap(),
+                "https://grafana.example.com/".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(rules)
+                .data(providers)
+                .route("/forward_auth", web::get().to(forward_auth))
+                .route("/forward_auth/return", web::get().to(forward_auth_return)),
+        )
+        .await;
+        let forwarded = |uri: &str| {
+            TestRequest::get()
+                .uri(uri)
+                .header("X-Forwarded-Proto", "https")
+                .header("X-Forwarded-Host", "grafana.example.com")
+                .header("X-Forwarded-Uri", "/d/home")
+        };
+
+        let request = forwarded("/forward_auth")
+            .header(
+                AUTHORIZATION,
+                bearer(
+                    "grafana@example.com",
+                    vec!["grafana".into(), "editor".into()],
+                ),
+            )
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("X-Auth-User").unwrap(),
+            "grafana@example.com"
+        );
+        assert_eq!(
+            response.headers().get("X-Auth-Roles").unwrap(),
+            "grafana,editor"
+        );
+
+        let request = forwarded("/forward_auth")
+            .header(AUTHORIZATION, bearer("viewer@example.com", Vec::new()))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // api clients get a 401, browsers are sent to the login page and back
+        let request = forwarded("/forward_auth").to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = forwarded("/forward_auth?redirect=true").to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(
+            location.ends_with("/auth/login.html?rd=https%3A%2F%2Fgrafana.example.com%2Fd%2Fhome")
+        );
+
+        let request = TestRequest::get()
+            .uri("/forward_auth/return?rd=https%3A%2F%2Fexample.com%2Fapp%3Fx%3D1")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap(),
+            "https://example.com/app?x=1"
+        );
+
+        let request = TestRequest::get()
+            .uri("/forward_auth/return?rd=https%3A%2F%2Fevil.com%2F")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap(),
+            "/auth/index.html"
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::Error;
+use log::debug;
+use reqwest::{header::ACCEPT, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    errors::ServiceError,
+    openid_providers::{ExternalIdentity, ProviderConfig},
+};
+
+const GITHUB_URL: &str = "https://github.com/";
+const GITHUB_API_URL: &str = "https://api.github.com/";
+const GITHUB_API_ACCEPT: &str = "application/vnd.github.v3+json";
+
+// Url::join replaces the last path segment unless the base ends with a slash
+fn as_base(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GithubMembership {
+    state: String,
+}
+
+/// GitHub (or GitHub Enterprise, with `issuer` set to its web url) is plain OAuth2 without an
+/// id token, the subject is the numeric user id and the email the primary verified one
+pub struct GithubClient {
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    scopes: Vec<String>,
+    // a login is only accepted from active members of at least one of these, if any
+    organizations: Vec<String>,
+    authorize_url: Url,
+    token_url: Url,
+    api_url: Url,
+    http_client: Client,
+}
+
+impl GithubClient {
+    pub fn new(config: &ProviderConfig, redirect_url: String) -> Result<Self, Error> {
+        let base_url = as_base(match &config.issuer {
+            Some(issuer) => issuer.clone(),
+            None => Url::parse(GITHUB_URL)?,
+        });
+        let api_url = as_base(match (&config.api_url, &config.issuer) {
+            (Some(api_url), _) => api_url.clone(),
+            (None, Some(_)) => base_url.join("api/v3/")?,
+            (None, None) => Url::parse(GITHUB_API_URL)?,
+        });
+        Ok(Self {
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            redirect_url,
+            scopes: config.scopes(),
+            organizations: config.organizations.clone(),
+            authorize_url: base_url.join("login/oauth/authorize")?,
+            token_url: base_url.join("login/oauth/access_token")?,
+            api_url,
+            http_client: Client::builder().user_agent("rust_auth_server").build()?,
+        })
+    }
+
+    pub fn auth_url(&self, state: &str, code_challenge: &str) -> Url {
+        let mut url = self.authorize_url.clone();
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scopes.join(" "))
+            .append_pair("state", state)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256")
+            .append_pair("allow_signup", "false");
+        url
+    }
+
+    async fn request_token(&self, code: &str, code_verifier: &str) -> Result<String, ServiceError> {
+        let response: AccessTokenResponse = self
+            .http_client
+            .post(self.token_url.clone())
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("code", code),
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // errors such as an expired code are reported with a 200
+        let AccessTokenResponse {
+            access_token,
+            error,
+            error_description,
+        } = response;
+        access_token.ok_or_else(|| {
+            ServiceError::BadRequest(format!(
+                "Github token request failed {} {}",
+                error.unwrap_or_default(),
+                error_description.unwrap_or_default()
+            ))
+        })
+    }
+
+    async fn api_get(&self, token: &str, path: &str) -> Result<reqwest::Response, ServiceError> {
+        let url = self
+            .api_url
+            .join(path)
+            .map_err(|e| ServiceError::BadRequest(format!("Invalid github api path {:?}", e)))?;
+        self.http_client
+            .get(url)
+            .header(ACCEPT, GITHUB_API_ACCEPT)
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn is_member(&self, token: &str, organization: &str) -> Result<bool, ServiceError> {
+        let response = self
+            .api_get(token, &format!("user/memberships/orgs/{}", organization))
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(false),
+            _ => {
+                let membership: GithubMembership = response.error_for_status()?.json().await?;
+                Ok(membership.state == "active")
+            }
+        }
+    }
+
+    /// Exchange the authorization code and look up the user
+    pub async fn get_identity(
+        &self,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<ExternalIdentity, ServiceError> {
+        let token = self.request_token(code, code_verifier).await?;
+
+        if !self.organizations.is_empty() {
+            let mut member = false;
+            for organization in &self.organizations {
+                if self.is_member(&token, organization).await? {
+                    member = true;
+                    break;
+                }
+            }
+            if !member {
+                return Err(ServiceError::BadRequest(
+                    "Not a member of an allowed organization".into(),
+                ));
+            }
+        }
+
+        let user: GithubUser = self
+            .api_get(&token, "user")
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let emails: Vec<GithubEmail> = self
+            .api_get(&token, "user/emails")
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        debug!("github user {} {} emails", user.login, emails.len());
+        Ok(ExternalIdentity {
+            subject: user.id.to_string(),
+            email: emails
+                .into_iter()
+                .find(|email| email.primary && email.verified)
+                .map(|email| email.email),
+            email_verified: true,
+            hosted_domain: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test,
+        web::{self, Form, Path},
+        App, HttpResponse,
+    };
+    use anyhow::Error;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use url::Url;
+
+    use crate::{
+        errors::ServiceError,
+        github_oauth::GithubClient,
+        openid_providers::{ProviderConfig, ProviderType},
+    };
+
+    async fn mock_token(form: Form<HashMap<String, String>>) -> HttpResponse {
+        if form.get("code").map(String::as_str) == Some("good-code")
+            && form.get("code_verifier").map(String::as_str) == Some("verifier")
+        {
+            HttpResponse::Ok().json(json!({"access_token": "mock-token", "token_type": "bearer"}))
+        } else {
+            HttpResponse::Ok().json(json!({"error": "bad_verification_code"}))
+        }
+    }
+
+    async fn mock_user() -> HttpResponse {
+        HttpResponse::Ok().json(json!({"id": 4242, "login": "dev"}))
+    }
+
+    async fn mock_emails() -> HttpResponse {
+        HttpResponse::Ok().json(json!([
+            {"email": "old@example.com", "primary": false, "verified": true},
+            {"email": "dev@example.com", "primary": true, "verified": true},
+        ]))
+    }
+
+    async fn mock_membership(organization: Path<String>) -> HttpResponse {
+        match organization.as_str() {
+            "example-org" => HttpResponse::Ok().json(json!({"state": "active"})),
+            "pending-org" => HttpResponse::Ok().json(json!({"state": "pending"})),
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    // a GitHub Enterprise server, the API lives under /api/v3
+    fn mock_github() -> test::TestServer {
+        test::start(|| {
+            App::new()
+                .route("/login/oauth/access_token", web::post().to(mock_token))
+                .route("/api/v3/user", web::get().to(mock_user))
+                .route("/api/v3/user/emails", web::get().to(mock_emails))
+                .route(
+                    "/api/v3/user/memberships/orgs/{org}",
+                    web::get().to(mock_membership),
+                )
+        })
+    }
+
+    fn github_client(server: &test::TestServer, organizations: &[&str]) -> GithubClient {
+        let config = ProviderConfig {
+            name: "github".into(),
+            provider_type: ProviderType::Github,
+            issuer: Some(Url::parse(&server.url("/")).unwrap()),
+            client_id: "mock-client".into(),
+            client_secret: "mock-secret".into(),
+            scopes: Vec::new(),
+            callback_path: None,
+            api_url: None,
+            organizations: organizations.iter().map(|s| s.to_string()).collect(),
+            link_by_email: false,
+            require_verified_email: true,
+            hosted_domains: Vec::new(),
+            provision_domains: Vec::new(),
+        };
+        GithubClient::new(
+            &config,
+            "https://auth.example.com/api/callback/github".into(),
+        )
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_github_login() -> Result<(), Error> {
+        let server = mock_github();
+
+        let client = github_client(&server, &[]);
+        let url = client.auth_url("state", "challenge");
+        assert!(url
+            .as_str()
+            .starts_with(&server.url("/login/oauth/authorize")));
+        assert!(url.as_str().contains("scope=user%3Aemail&state=state"));
+        assert!(url
+            .as_str()
+            .contains("code_challenge=challenge&code_challenge_method=S256"));
+        let identity = client.get_identity("good-code", "verifier").await?;
+        assert_eq!(identity.subject, "4242");
+        assert_eq!(identity.email.as_deref(), Some("dev@example.com"));
+        assert!(matches!(
+            client.get_identity("expired-code", "verifier").await,
+            Err(ServiceError::BadRequest(_))
+        ));
+        assert!(matches!(
+            client.get_identity("good-code", "other-verifier").await,
+            Err(ServiceError::BadRequest(_))
+        ));
+
+        let client = github_client(&server, &["other-org", "example-org"]);
+        assert!(client
+            .auth_url("state", "challenge")
+            .as_str()
+            .contains("read%3Aorg"));
+        assert_eq!(
+            client.get_identity("good-code", "verifier").await?.subject,
+            "4242"
+        );
+
+        // pending invitations to an organization do not count
+        let client = github_client(&server, &["other-org", "pending-org"]);
+        assert!(matches!(
+            client.get_identity("good-code", "verifier").await,
+            Err(ServiceError::BadRequest(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use async_trait::async_trait;
+use chrono::Local;
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    errors::ServiceError,
+    logged_user::TRIGGER_DB_UPDATE,
+    models::{DbExecutor, HandleRequest, SlimUser, User, UserIdentity},
+    openid_providers::ExternalIdentity,
+};
+
+fn find_identity(
+    conn: &PgConnection,
+    provider_: &str,
+    subject_: &str,
+) -> QueryResult<Option<UserIdentity>> {
+    use crate::schema::user_identities::dsl::{provider, subject, user_identities};
+
+    user_identities
+        .filter(provider.eq(provider_))
+        .filter(subject.eq(subject_))
+        .first(conn)
+        .optional()
+}
+
+fn has_identity(conn: &PgConnection, email_: &str, provider_: &str) -> QueryResult<bool> {
+    use crate::schema::user_identities::dsl::{email, provider, user_identities};
+
+    user_identities
+        .filter(email.eq(email_))
+        .filter(provider.eq(provider_))
+        .select(email)
+        .first::<String>(conn)
+        .optional()
+        .map(|found| found.is_some())
+}
+
+fn insert_identity(
+    conn: &PgConnection,
+    email: &str,
+    provider: &str,
+    identity: &ExternalIdentity,
+) -> QueryResult<UserIdentity> {
+    use crate::schema::user_identities::dsl::user_identities;
+
+    let current_time = Local::now().naive_local();
+    diesel::insert_into(user_identities)
+        .values(&UserIdentity {
+            provider: provider.into(),
+            subject: identity.subject.clone(),
+            email: email.into(),
+            provider_email: identity.email.clone(),
+            created_at: current_time,
+            last_login_at: Some(current_time),
+        })
+        .get_result(conn)
+}
+
+// a provider login, returns None if the identity does not belong to any user
+#[derive(Debug)]
+pub struct IdentityLogin {
+    pub provider: String,
+    pub identity: ExternalIdentity,
+    // see ProviderConfig::link_by_email
+    pub link_by_email: bool,
+    // create a user without a password if there is none with the email of the identity
+    pub provision: bool,
+}
+
+#[async_trait]
+impl HandleRequest<IdentityLogin> for DbExecutor {
+    type Result = Result<Option<SlimUser>, ServiceError>;
+
+    async fn handle(&self, msg: IdentityLogin) -> Self::Result {
+        use crate::schema::{user_identities::dsl, users::dsl::users};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let (user, provisioned) = conn.transaction(|| -> Result<_, ServiceError> {
+                if let Some(linked) = find_identity(&conn, &msg.provider, &msg.identity.subject)? {
+                    diesel::update(
+                        dsl::user_identities
+                            .filter(dsl::provider.eq(&linked.provider))
+                            .filter(dsl::subject.eq(&linked.subject)),
+                    )
+                    .set((
+                        dsl::provider_email.eq(&msg.identity.email),
+                        dsl::last_login_at.eq(Local::now().naive_local()),
+                    ))
+                    .execute(&conn)?;
+                    let user: User = users.find(&linked.email).first(&conn)?;
+                    return Ok((Some(user.into()), false));
+                }
+                let email = match &msg.identity.email {
+                    Some(email) if msg.identity.email_verified => email,
+                    _ => return Ok((None, false)),
+                };
+                let user: Option<User> = users.find(email).first(&conn).optional()?;
+                match user {
+                    // a user already linked to another account of this provider must log in
+                    // with that one
+                    Some(user)
+                        if msg.link_by_email
+                            && !has_identity(&conn, &user.email, &msg.provider)? =>
+                    {
+                        insert_identity(&conn, &user.email, &msg.provider, &msg.identity)?;
+                        Ok((Some(user.into()), false))
+                    }
+                    None if msg.provision => {
+                        // the empty hash never verifies, a password can be set by a reset
+                        let user = User::from_details(email.clone(), String::new());
+                        diesel::insert_into(users).values(&user).execute(&conn)?;
+                        insert_identity(&conn, &user.email, &msg.provider, &msg.identity)?;
+                        Ok((Some(user.into()), true))
+                    }
+                    _ => Ok((None, false)),
+                }
+            })?;
+            // the new user is authorized right away, like a registered one
+            if provisioned {
+                TRIGGER_DB_UPDATE.set();
+            }
+            Ok(user)
+        })
+        .await?
+    }
+}
+
+// link a provider account to the user of the current session
+#[derive(Debug)]
+pub struct LinkIdentity {
+    pub email: String,
+    pub provider: String,
+    pub identity: ExternalIdentity,
+}
+
+#[async_trait]
+impl HandleRequest<LinkIdentity> for DbExecutor {
+    type Result = Result<UserIdentity, ServiceError>;
+
+    async fn handle(&self, msg: LinkIdentity) -> Self::Result {
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                if let Some(linked) = find_identity(&conn, &msg.provider, &msg.identity.subject)? {
+                    return if linked.email == msg.email {
+                        Ok(linked)
+                    } else {
+                        Err(ServiceError::BadRequest(
+                            "Identity is linked to another account".into(),
+                        ))
+                    };
+                }
+                if has_identity(&conn, &msg.email, &msg.provider)? {
+                    return Err(ServiceError::BadRequest(format!(
+                        "Unlink the current {} identity first",
+                        msg.provider
+                    )));
+                }
+                insert_identity(&conn, &msg.email, &msg.provider, &msg.identity).map_err(Into::into)
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct ListIdentities {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<ListIdentities> for DbExecutor {
+    type Result = Result<Vec<UserIdentity>, ServiceError>;
+
+    async fn handle(&self, msg: ListIdentities) -> Self::Result {
+        use crate::schema::user_identities::dsl::{email, provider, user_identities};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            user_identities
+                .filter(email.eq(&msg.email))
+                .order(provider.asc())
+                .load(&conn)
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct UnlinkIdentity {
+    pub email: String,
+    pub provider: String,
+}
+
+#[async_trait]
+impl HandleRequest<UnlinkIdentity> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: UnlinkIdentity) -> Self::Result {
+        use crate::schema::user_identities::dsl::{email, provider, user_identities};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::delete(
+                user_identities
+                    .filter(email.eq(&msg.email))
+                    .filter(provider.eq(&msg.provider)),
+            )
+            .execute(&conn)
+            .map(|deleted| deleted > 0)
+            .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    use crate::{
+        identity_handler::IdentityLogin,
+        logged_user::{fill_auth_from_db, LoggedUser, AUTHORIZED_USERS},
+        models::HandleRequest,
+        openid_providers::ExternalIdentity,
+        rust_auth_server::{get_pool, load_config},
+        utils::get_random_string,
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_provisioned_user_is_authorized() -> Result<(), Error> {
+        use crate::schema::users::dsl::{email, users};
+
+        load_config();
+        let pool = get_pool();
+        // clears the pending refresh, only the provisioning may set it again
+        fill_auth_from_db(&pool)?;
+        let user_email = format!("{}@example.com", get_random_string().to_lowercase());
+        let msg = IdentityLogin {
+            provider: "google".into(),
+            identity: ExternalIdentity {
+                subject: get_random_string(),
+                email: Some(user_email.clone()),
+                email_verified: true,
+                hosted_domain: None,
+            },
+            link_by_email: false,
+            provision: true,
+        };
+        let user = pool.handle(msg).await?;
+        assert_eq!(user.map(|user| user.email), Some(user_email.clone()));
+
+        fill_auth_from_db(&pool)?;
+        let user = LoggedUser::from_email(user_email.clone());
+        let authorized = AUTHORIZED_USERS.is_authorized(&user);
+
+        diesel::delete(users.filter(email.eq(&user_email))).execute(&pool.0.get()?)?;
+        assert!(authorized);
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use actix_web::{
+    web::{Data, Json, Path, Query},
+    Error, HttpResponse, ResponseError,
+};
+use maplit::hashmap;
+
+use crate::{
+    identity_handler::{ListIdentities, UnlinkIdentity},
+    logged_user::{LoggedUser, SessionUser},
+    models::{DbExecutor, HandleRequest},
+    openid_providers::ProviderRegistry,
+    openid_routes::{start_login, GetAuthUrlData, ProviderQuery},
+};
+
+pub async fn list_identities(
+    logged_user: LoggedUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ListIdentities {
+        email: logged_user.email,
+    };
+    match db.handle(msg).await {
+        Ok(identities) => Ok(HttpResponse::Ok().json(identities)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+// returns the authorization url of the provider, its callback links the account to the
+// logged in user instead of starting a new session
+pub async fn link_identity(
+    session_user: SessionUser,
+    payload: Json<GetAuthUrlData>,
+    query: Query<ProviderQuery>,
+    db: Data<DbExecutor>,
+    providers: Data<ProviderRegistry>,
+) -> Result<HttpResponse, Error> {
+    let result = async {
+        let provider = providers.get(query.provider.as_deref())?;
+        let final_url = providers.redirect_url(&payload.final_url)?;
+        start_login(provider, &db, final_url, Some(session_user.email)).await
+    }
+    .await;
+    match result {
+        Ok(authorize_url) => Ok(HttpResponse::Ok().body(String::from(authorize_url))),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn unlink_identity(
+    session_user: SessionUser,
+    provider: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = UnlinkIdentity {
+        email: session_user.email,
+        provider: provider.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            let result = hashmap! { "status" => status };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
@@ -1,20 +1,85 @@
 use async_trait::async_trait;
-use chrono::{Duration, Local};
-use diesel::{self, PgConnection, RunQueryDsl};
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::{
+    self, pg::Pg, BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension,
+    PgConnection, QueryDsl, RunQueryDsl,
+};
 use serde::{Deserialize, Serialize};
 use std::env::var;
 use tokio::task::spawn_blocking;
 use uuid::Uuid;
 
 use crate::{
+    admin_handler::page_bounds,
     email_service::send_invitation,
     errors::ServiceError,
-    models::{DbExecutor, HandleRequest, Invitation},
+    logged_user::{Admin, Inviter, LoggedUser, Role},
+    models::{DbExecutor, HandleRequest, Invitation, InvitationStatus},
+    schema::invitations,
 };
 
-#[derive(Deserialize)]
+fn invitation_callback_url() -> String {
+    var("CALLBACK_URL").unwrap_or_else(|_| "http://localhost:3000/register.html".to_string())
+}
+
+/// Email domains whose addresses may request an invitation for themselves, e.g.
+/// `INVITATION_DOMAINS=example.com,example.org`. Empty means only inviters can invite.
+pub fn invitation_domains() -> Vec<String> {
+    var("INVITATION_DOMAINS")
+        .map(|domains| {
+            domains
+                .split(',')
+                .map(|domain| domain.trim().to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// invitations an inviter (or a self inviting address) may send per day, admins are exempt
+fn invitation_quota() -> i64 {
+    var("INVITATION_QUOTA")
+        .ok()
+        .and_then(|q| q.parse().ok())
+        .unwrap_or(10)
+}
+
+pub fn email_domain(email: &str) -> Option<String> {
+    match email.rsplitn(2, '@').collect::<Vec<_>>().as_slice() {
+        [domain, local] if !domain.is_empty() && !local.is_empty() => Some(domain.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Decide who an invitation is sent on behalf of: users with the inviter (or admin) role
+/// invite anyone, anybody else may only invite an address of an allowed domain, which is
+/// recorded as a self invitation
+fn check_inviter(
+    inviter: Option<&LoggedUser>,
+    email: &str,
+    domains: &[String],
+) -> Result<Option<String>, ServiceError> {
+    let domain = email_domain(email)
+        .ok_or_else(|| ServiceError::BadRequest("Invalid email address".into()))?;
+    match inviter {
+        Some(user) if user.has_role(Inviter::NAME) || user.has_role(Admin::NAME) => {
+            Ok(Some(user.email.clone()))
+        }
+        _ if domains.contains(&domain) => Ok(None),
+        _ => Err(ServiceError::Forbidden),
+    }
+}
+
+// InvitationData is used to extract data from a post request by the client
+#[derive(Debug, Deserialize)]
+pub struct InvitationData {
+    pub email: String,
+}
+
+#[derive(Debug)]
 pub struct CreateInvitation {
     pub email: String,
+    pub inviter: Option<LoggedUser>,
 }
 
 #[async_trait]
@@ -22,21 +87,36 @@ impl HandleRequest<CreateInvitation> for DbExecutor {
     type Result = Result<Invitation, ServiceError>;
 
     async fn handle(&self, msg: CreateInvitation) -> Self::Result {
-        use crate::schema::invitations::dsl::invitations;
+        use crate::schema::invitations::dsl::{created_at, email, invitations, invited_by};
 
-        // creating a new Invitation object with expired at time that is 24 hours from
-        // now
-        let new_invitation = Invitation {
-            id: Uuid::new_v4(),
-            email: msg.email,
-            expires_at: Local::now().naive_local() + Duration::hours(24),
-        };
+        let invited_by_ = check_inviter(msg.inviter.as_ref(), &msg.email, &invitation_domains())?;
+        let unlimited = msg
+            .inviter
+            .as_ref()
+            .is_some_and(|user| user.has_role(Admin::NAME));
+        let new_invitation = Invitation::from_email(msg.email, invited_by_);
 
         let dbex = self.clone();
 
         let new_invitation_ = new_invitation.clone();
         let inserted_invitation = spawn_blocking(move || {
             let conn = dbex.0.get().map_err(ServiceError::R2D2Error)?;
+            if !unlimited {
+                let since = new_invitation_.created_at - Duration::days(1);
+                let recent = invitations.filter(created_at.gt(since)).into_boxed();
+                // self invitations count against the invited address instead
+                let recent = match &new_invitation_.invited_by {
+                    Some(inviter) => recent.filter(invited_by.eq(inviter)),
+                    None => recent
+                        .filter(invited_by.is_null())
+                        .filter(email.eq(&new_invitation_.email)),
+                };
+                if recent.count().get_result::<i64>(&conn)? >= invitation_quota() {
+                    return Err(ServiceError::BadRequest(
+                        "Invitation quota exceeded, try again later".into(),
+                    ));
+                }
+            }
             diesel::insert_into(invitations)
                 .values(&new_invitation_)
                 .get_result(&conn)
@@ -44,10 +124,240 @@ impl HandleRequest<CreateInvitation> for DbExecutor {
         })
         .await??;
 
-        let callback_url = var("CALLBACK_URL")
-            .unwrap_or_else(|_| "http://localhost:3000/register.html".to_string());
-        send_invitation(&new_invitation, &callback_url).await?;
+        send_invitation(&new_invitation, &invitation_callback_url()).await?;
 
         Ok(inserted_invitation)
     }
 }
+
+#[derive(Debug, Serialize)]
+pub struct InvitationInfo {
+    pub id: Uuid,
+    pub email: String,
+    pub status: InvitationStatus,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub invited_by: Option<String>,
+}
+
+impl InvitationInfo {
+    fn new(invitation: Invitation, now: NaiveDateTime) -> Self {
+        Self {
+            status: invitation.status(now),
+            id: invitation.id,
+            email: invitation.email,
+            created_at: invitation.created_at,
+            expires_at: invitation.expires_at,
+            accepted_at: invitation.accepted_at,
+            revoked_at: invitation.revoked_at,
+            invited_by: invitation.invited_by,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvitationList {
+    pub total: i64,
+    pub offset: i64,
+    pub limit: i64,
+    pub invitations: Vec<InvitationInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListInvitations {
+    pub status: Option<InvitationStatus>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// the same conditions as Invitation::status, evaluated in the database
+fn filter_status(
+    status: Option<InvitationStatus>,
+    now: NaiveDateTime,
+) -> invitations::BoxedQuery<'static, Pg> {
+    use crate::schema::invitations::dsl::{accepted_at, expires_at, invitations, revoked_at};
+
+    let query = invitations.into_boxed();
+    match status {
+        None => query,
+        Some(InvitationStatus::Accepted) => query.filter(accepted_at.is_not_null()),
+        Some(InvitationStatus::Revoked) => query
+            .filter(accepted_at.is_null())
+            .filter(revoked_at.is_not_null()),
+        Some(InvitationStatus::Expired) => query
+            .filter(accepted_at.is_null())
+            .filter(revoked_at.is_null())
+            .filter(expires_at.le(now)),
+        Some(InvitationStatus::Pending) => query
+            .filter(accepted_at.is_null())
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(now)),
+    }
+}
+
+#[async_trait]
+impl HandleRequest<ListInvitations> for DbExecutor {
+    type Result = Result<InvitationList, ServiceError>;
+
+    async fn handle(&self, msg: ListInvitations) -> Self::Result {
+        use crate::schema::invitations::dsl::created_at;
+
+        let (offset, limit) = page_bounds(msg.offset, msg.limit);
+        let current_time = Local::now().naive_local();
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let total = filter_status(msg.status, current_time)
+                .count()
+                .get_result(&conn)?;
+            let page: Vec<Invitation> = filter_status(msg.status, current_time)
+                .order(created_at.desc())
+                .offset(offset)
+                .limit(limit)
+                .load(&conn)?;
+            Ok(InvitationList {
+                total,
+                offset,
+                limit,
+                invitations: page
+                    .into_iter()
+                    .map(|invitation| InvitationInfo::new(invitation, current_time))
+                    .collect(),
+            })
+        })
+        .await?
+    }
+}
+
+// send the invitation email again, the link is valid for another 24 hours
+#[derive(Debug)]
+pub struct ResendInvitation {
+    pub id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<ResendInvitation> for DbExecutor {
+    type Result = Result<InvitationInfo, ServiceError>;
+
+    async fn handle(&self, msg: ResendInvitation) -> Self::Result {
+        use crate::schema::invitations::dsl::{expires_at, invitations};
+
+        let current_time = Local::now().naive_local();
+        let dbex = self.clone();
+        let invitation = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| -> Result<Invitation, ServiceError> {
+                let invitation: Invitation = invitations
+                    .find(msg.id)
+                    .for_update()
+                    .first(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest("Unknown invitation".into()))?;
+                match invitation.status(current_time) {
+                    InvitationStatus::Pending | InvitationStatus::Expired => {}
+                    InvitationStatus::Accepted => {
+                        return Err(ServiceError::BadRequest("Invitation already used".into()))
+                    }
+                    InvitationStatus::Revoked => {
+                        return Err(ServiceError::BadRequest("Invitation revoked".into()))
+                    }
+                }
+                diesel::update(invitations.find(msg.id))
+                    .set(expires_at.eq(current_time + Duration::hours(24)))
+                    .get_result(&conn)
+                    .map_err(Into::into)
+            })
+        })
+        .await??;
+
+        send_invitation(&invitation, &invitation_callback_url()).await?;
+
+        Ok(InvitationInfo::new(invitation, current_time))
+    }
+}
+
+#[derive(Debug)]
+pub struct RevokeInvitation {
+    pub id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<RevokeInvitation> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: RevokeInvitation) -> Self::Result {
+        use crate::schema::invitations::dsl::{accepted_at, id, invitations, revoked_at};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::update(
+                invitations
+                    .filter(id.eq(msg.id))
+                    .filter(accepted_at.is_null())
+                    .filter(revoked_at.is_null()),
+            )
+            .set(revoked_at.eq(Local::now().naive_local()))
+            .execute(&conn)
+            .map(|revoked| revoked > 0)
+            .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+// accepted invitations are kept as a record of how the user signed up
+pub fn cleanup_invitations(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    use crate::schema::invitations::dsl::{accepted_at, expires_at, invitations, revoked_at};
+
+    let conn = pool.0.get()?;
+    let cutoff = Local::now().naive_local() - Duration::days(30);
+    diesel::delete(
+        invitations
+            .filter(accepted_at.is_null())
+            .filter(expires_at.lt(cutoff).or(revoked_at.lt(cutoff))),
+    )
+    .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::ServiceError,
+        invitation_handler::check_inviter,
+        logged_user::{Inviter, LoggedUser, Role},
+    };
+
+    #[test]
+    fn test_check_inviter() {
+        let domains = vec!["example.com".to_string()];
+        let mut user = LoggedUser::from_email("user@example.org".into());
+
+        // self invitations only for allowed domains
+        assert_eq!(
+            check_inviter(None, "new@Example.com", &domains).unwrap(),
+            None
+        );
+        assert!(matches!(
+            check_inviter(None, "new@example.org", &domains),
+            Err(ServiceError::Forbidden)
+        ));
+        assert!(matches!(
+            check_inviter(Some(&user), "new@example.org", &domains),
+            Err(ServiceError::Forbidden)
+        ));
+        assert!(matches!(
+            check_inviter(None, "example.com", &domains),
+            Err(ServiceError::BadRequest(_))
+        ));
+
+        user.roles.push(Inviter::NAME.into());
+        assert_eq!(
+            check_inviter(Some(&user), "new@example.org", &[]).unwrap(),
+            Some("user@example.org".to_string())
+        );
+    }
+}
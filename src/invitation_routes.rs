@@ -9,17 +9,24 @@ use serde::{Deserialize, Serialize};
 use std::env;
 
 use crate::{
-    invitation_handler::CreateInvitation,
+    invitation_handler::{CreateInvitation, InvitationData},
+    logged_user::LoggedUser,
     models::{DbExecutor, HandleRequest},
 };
 
 pub async fn register_email(
-    signup_invitation: Json<CreateInvitation>,
+    invitation_data: Json<InvitationData>,
+    inviter: Option<LoggedUser>,
     db: Data<DbExecutor>,
 ) -> Result<HttpResponse, Error> {
-    let db_response = db.handle(signup_invitation.into_inner()).await;
-    match db_response {
-        Ok(x) => Ok(HttpResponse::Ok().json(x)),
+    let msg = CreateInvitation {
+        email: invitation_data.into_inner().email,
+        inviter,
+    };
+    // the invitation id is only sent by email, so that self invitations prove ownership of
+    // the address
+    match db.handle(msg).await {
+        Ok(invitation) => Ok(HttpResponse::Ok().json(invitation.email)),
         Err(err) => Ok(err.error_response()),
     }
 }
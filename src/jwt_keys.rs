@@ -0,0 +1,777 @@
+use anyhow::{format_err, Error};
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, Jwk,
+        JwkSet, OctetKeyPairParameters, PublicKeyUse, RSAKeyParameters,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use lazy_static::lazy_static;
+use log::error;
+use parking_lot::RwLock;
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING,
+    },
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, fs, io::Write, os::unix::fs::OpenOptionsExt, path::Path, time::UNIX_EPOCH};
+
+use crate::utils::access_token_lifetime;
+
+const MANIFEST_FILE: &str = "keys.json";
+
+lazy_static! {
+    pub static ref JWT_KEYS: RwLock<KeyRing> =
+        RwLock::new(KeyRing::from_env().expect("Invalid JWT signing key configuration"));
+}
+
+/// Key used to sign access tokens. Asymmetric keys are published in the JWKS so that other
+/// services can verify tokens with only the public key.
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    // only set for asymmetric keys, a shared secret is never published
+    jwk: Option<Jwk>,
+}
+
+impl JwtKey {
+    /// Read the key from `JWT_ALGORITHM` (HS256, RS256, ES256 or EdDSA, default HS256) and
+    /// either `JWT_SECRET` or the PEM file at `JWT_PRIVATE_KEY`. `JWT_KEY_ID` overrides the
+    /// default kid, the RFC 7638 thumbprint of the public key.
+    pub fn from_env() -> Result<Self, Error> {
+        let algorithm = match env::var("JWT_ALGORITHM") {
+            Ok(algorithm) => algorithm.parse()?,
+            Err(_) => Algorithm::HS256,
+        };
+        let key = if algorithm == Algorithm::HS256 {
+            let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "my secret".into());
+            Self::from_secret(secret.as_bytes())
+        } else {
+            let path = env::var("JWT_PRIVATE_KEY")
+                .map_err(|_| format_err!("JWT_PRIVATE_KEY must be set for {:?}", algorithm))?;
+            Self::from_pem(algorithm, &fs::read(&path)?)?
+        };
+        Ok(match env::var("JWT_KEY_ID") {
+            Ok(kid) => key.with_kid(kid),
+            Err(_) => key,
+        })
+    }
+
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            kid: "hs256".into(),
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            jwk: None,
+        }
+    }
+
+    /// Load a private key, PKCS#1 (`RSA PRIVATE KEY`) for RS256 and PKCS#8 (`PRIVATE KEY`)
+    /// for ES256 and EdDSA
+    pub fn from_pem(algorithm: Algorithm, private_pem: &[u8]) -> Result<Self, Error> {
+        let der = pem::parse(private_pem)?.contents;
+        let (encoding_key, decoding_key, params) = match algorithm {
+            Algorithm::RS256 => {
+                let key_pair = RsaKeyPair::from_der(&der)
+                    .map_err(|e| format_err!("Invalid PKCS#1 RSA key: {}", e))?;
+                let public_key = key_pair.public_key();
+                let n = public_key.modulus().big_endian_without_leading_zero();
+                let e = public_key.exponent().big_endian_without_leading_zero();
+                let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: Default::default(),
+                    n: encode_config(n, URL_SAFE_NO_PAD),
+                    e: encode_config(e, URL_SAFE_NO_PAD),
+                });
+                (
+                    EncodingKey::from_rsa_der(&der),
+                    DecodingKey::from_rsa_raw_components(n, e),
+                    params,
+                )
+            }
+            Algorithm::ES256 => {
+                let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der)
+                    .map_err(|e| format_err!("Invalid PKCS#8 P-256 key: {}", e))?;
+                // uncompressed point: 0x04 || x || y
+                let point = key_pair.public_key().as_ref();
+                let x = encode_config(&point[1..33], URL_SAFE_NO_PAD);
+                let y = encode_config(&point[33..], URL_SAFE_NO_PAD);
+                let decoding_key = DecodingKey::from_ec_components(&x, &y)?;
+                let params = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                    key_type: Default::default(),
+                    curve: EllipticCurve::P256,
+                    x,
+                    y,
+                });
+                (EncodingKey::from_ec_der(&der), decoding_key, params)
+            }
+            Algorithm::EdDSA => {
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+                    .map_err(|e| format_err!("Invalid PKCS#8 Ed25519 key: {}", e))?;
+                let x = encode_config(key_pair.public_key().as_ref(), URL_SAFE_NO_PAD);
+                let decoding_key = DecodingKey::from_ed_components(&x)?;
+                let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: Default::default(),
+                    curve: EllipticCurve::Ed25519,
+                    x,
+                });
+                (EncodingKey::from_ed_der(&der), decoding_key, params)
+            }
+            _ => return Err(format_err!("Unsupported JWT algorithm {:?}", algorithm)),
+        };
+        let kid = jwk_thumbprint(&params);
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(algorithm),
+                key_id: Some(kid.clone()),
+                ..CommonParameters::default()
+            },
+            algorithm: params,
+        };
+        Ok(Self {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+        })
+    }
+
+    pub fn with_kid(mut self, kid: String) -> Self {
+        if let Some(jwk) = self.jwk.as_mut() {
+            jwk.common.key_id = Some(kid.clone());
+        }
+        self.kid = kid;
+        self
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding_key
+    }
+
+    pub fn jwk(&self) -> Option<&Jwk> {
+        self.jwk.as_ref()
+    }
+}
+
+/// Public keys to publish at `/.well-known/jwks.json`
+pub fn get_jwks() -> JwkSet {
+    JWT_KEYS.read().jwks(Utc::now())
+}
+
+/// Algorithm of the current signing key if it is asymmetric, for the OpenID Connect discovery
+/// document. ID tokens are only issued then: clients verify them with the JWKS, a shared secret
+/// can't be handed out without letting them forge access tokens.
+pub fn id_token_signing_algorithm() -> Option<Algorithm> {
+    JWT_KEYS
+        .read()
+        .signing_algorithm(Utc::now())
+        .filter(|algorithm| *algorithm != Algorithm::HS256)
+}
+
+/// Pick up keys promoted or retired with the `keys` command since the last load
+pub fn reload_jwt_keys() {
+    if let Ok(dir) = env::var("JWT_KEY_DIR") {
+        match KeyRing::load(Path::new(&dir)) {
+            Ok(ring) => *JWT_KEYS.write() = ring,
+            Err(e) => error!("Failed to reload JWT keys, keeping the current ones: {}", e),
+        }
+    }
+}
+
+/// Entry of the key manifest kept next to the PEM files in `JWT_KEY_DIR`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyEntry {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    // PEM file, relative to the key directory
+    pub file: String,
+    pub created_at: DateTime<Utc>,
+    // the key signs from this time on, until it is retired or a later key is activated
+    #[serde(default)]
+    pub activate_at: Option<DateTime<Utc>>,
+    // no new tokens are signed after this, existing ones verify until their expiry
+    #[serde(default)]
+    pub retire_at: Option<DateTime<Utc>>,
+    // tokens signed before retire_at verify until this time, recorded when the key is retired
+    // so a later change of ACCESS_TOKEN_MINUTES doesn't cut their lifetime short
+    #[serde(default)]
+    pub verify_until: Option<DateTime<Utc>>,
+}
+
+impl KeyEntry {
+    fn signs_at(&self, now: DateTime<Utc>) -> bool {
+        self.activate_at.is_some_and(|activate| activate <= now)
+            && self.retire_at.is_none_or(|retire| now < retire)
+    }
+
+    fn verifies_at(&self, now: DateTime<Utc>) -> bool {
+        self.retire_at
+            .map(|retire| {
+                self.verify_until
+                    .unwrap_or_else(|| retire + access_token_lifetime())
+            })
+            .is_none_or(|until| now < until)
+    }
+
+    fn set_retire_at(&mut self, at: Option<DateTime<Utc>>) {
+        self.retire_at = at;
+        // every token of this key expires one token lifetime after it stopped signing at the latest
+        self.verify_until = at.map(|at| at + access_token_lifetime());
+    }
+
+    fn status(&self, now: DateTime<Utc>) -> &'static str {
+        if self.signs_at(now) {
+            "signing"
+        } else if !self.verifies_at(now) {
+            "expired"
+        } else if self.retire_at.is_some_and(|retire| retire <= now) {
+            "retired"
+        } else {
+            "verify-only"
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyManifest {
+    pub keys: Vec<KeyEntry>,
+}
+
+impl KeyManifest {
+    pub fn read(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(&fs::read(&path)?).map_err(Into::into)
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), Error> {
+        // write to a temporary file first so a running server never reads a partial manifest
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    fn get_mut(&mut self, kid: &str) -> Result<&mut KeyEntry, Error> {
+        self.keys
+            .iter_mut()
+            .find(|entry| entry.kid == kid)
+            .ok_or_else(|| format_err!("No key with kid {}", kid))
+    }
+
+    /// Make `kid` the signing key from `at` on, retiring every other key still signing then
+    pub fn promote(&mut self, kid: &str, at: DateTime<Utc>) -> Result<(), Error> {
+        let entry = self.get_mut(kid)?;
+        if entry.retire_at.is_some_and(|retire| retire <= at) {
+            return Err(format_err!("Key {} is retired", kid));
+        }
+        entry.activate_at = Some(at);
+        entry.set_retire_at(None);
+        for entry in self.keys.iter_mut().filter(|entry| entry.kid != kid) {
+            if entry.activate_at.is_some() && entry.retire_at.is_none_or(|retire| retire > at) {
+                entry.set_retire_at(Some(at));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn retire(&mut self, kid: &str, at: DateTime<Utc>) -> Result<(), Error> {
+        self.get_mut(kid)?.set_retire_at(Some(at));
+        Ok(())
+    }
+}
+
+struct RingKey {
+    entry: KeyEntry,
+    key: JwtKey,
+}
+
+/// All keys that may sign or verify access tokens: at any time at most one signing key, the
+/// most recently activated one, plus verification-only keys selected by kid
+pub struct KeyRing {
+    keys: Vec<RingKey>,
+}
+
+impl KeyRing {
+    /// Load the key directory at `JWT_KEY_DIR`, or fall back to the single key configured by
+    /// `JWT_ALGORITHM`, see `JwtKey::from_env`
+    pub fn from_env() -> Result<Self, Error> {
+        match env::var("JWT_KEY_DIR") {
+            Ok(dir) => Self::load(Path::new(&dir)),
+            Err(_) => Ok(Self::single(JwtKey::from_env()?)),
+        }
+    }
+
+    pub fn single(key: JwtKey) -> Self {
+        let epoch = DateTime::<Utc>::from(UNIX_EPOCH);
+        let entry = KeyEntry {
+            kid: key.kid.clone(),
+            algorithm: key.algorithm,
+            file: String::new(),
+            created_at: epoch,
+            activate_at: Some(epoch),
+            retire_at: None,
+            verify_until: None,
+        };
+        Self {
+            keys: vec![RingKey { entry, key }],
+        }
+    }
+
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let now = Utc::now();
+        let keys = KeyManifest::read(dir)?
+            .keys
+            .into_iter()
+            .filter(|entry| entry.verifies_at(now))
+            .map(|entry| {
+                let pem = fs::read(dir.join(&entry.file))?;
+                let key = JwtKey::from_pem(entry.algorithm, &pem)?.with_kid(entry.kid.clone());
+                Ok(RingKey { entry, key })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let ring = Self { keys };
+        if ring.signing_key(now).is_none() {
+            return Err(format_err!("No active signing key in {}", dir.display()));
+        }
+        Ok(ring)
+    }
+
+    fn signing_key(&self, now: DateTime<Utc>) -> Option<&RingKey> {
+        self.keys
+            .iter()
+            .filter(|ring_key| ring_key.entry.signs_at(now))
+            .max_by_key(|ring_key| ring_key.entry.activate_at)
+    }
+
+    pub fn signing_algorithm(&self, now: DateTime<Utc>) -> Option<Algorithm> {
+        self.signing_key(now).map(|ring_key| ring_key.key.algorithm)
+    }
+
+    fn verification_key(&self, kid: Option<&str>, now: DateTime<Utc>) -> Option<&RingKey> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|ring_key| ring_key.key.kid == kid && ring_key.entry.verifies_at(now)),
+            // tokens from before kids were added can only come from the signing key
+            None => self.signing_key(now),
+        }
+    }
+
+    /// Sign with the current signing key, setting the `kid` header
+    pub fn encode<T: Serialize>(&self, claims: &T, now: DateTime<Utc>) -> Result<String, Error> {
+        let ring_key = self
+            .signing_key(now)
+            .ok_or_else(|| format_err!("No active signing key"))?;
+        let mut header = Header::new(ring_key.key.algorithm);
+        header.kid = Some(ring_key.key.kid.clone());
+        encode(&header, claims, ring_key.key.encoding_key()).map_err(Into::into)
+    }
+
+    /// Verify with the key named by the `kid` header, returning the claims and the retirement
+    /// time of that key so the caller can reject tokens issued after it
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(T, Option<DateTime<Utc>>), Error> {
+        let header = decode_header(token)?;
+        let ring_key = self
+            .verification_key(header.kid.as_deref(), now)
+            .ok_or_else(|| format_err!("Unknown kid {:?}", header.kid))?;
+        let data = decode::<T>(
+            token,
+            ring_key.key.decoding_key(),
+            &Validation::new(ring_key.key.algorithm),
+        )?;
+        Ok((data.claims, ring_key.entry.retire_at))
+    }
+
+    pub fn jwks(&self, now: DateTime<Utc>) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .iter()
+                .filter(|ring_key| ring_key.entry.verifies_at(now))
+                .filter_map(|ring_key| ring_key.key.jwk().cloned())
+                .collect(),
+        }
+    }
+}
+
+fn generate_pkcs8(algorithm: Algorithm) -> Result<Vec<u8>, Error> {
+    let rng = SystemRandom::new();
+    let pkcs8 = match algorithm {
+        Algorithm::ES256 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng),
+        Algorithm::EdDSA => Ed25519KeyPair::generate_pkcs8(&rng),
+        _ => {
+            return Err(format_err!(
+                "Can only generate ES256 and EdDSA keys, import {:?} keys instead",
+                algorithm
+            ))
+        }
+    }
+    .map_err(|_| format_err!("Key generation failed"))?;
+    Ok(pkcs8.as_ref().to_vec())
+}
+
+fn add_key(
+    manifest: &mut KeyManifest,
+    dir: &Path,
+    algorithm: Algorithm,
+    pem: &[u8],
+) -> Result<String, Error> {
+    let kid = JwtKey::from_pem(algorithm, pem)?.kid;
+    if manifest.keys.iter().any(|entry| entry.kid == kid) {
+        return Err(format_err!("Key {} already exists", kid));
+    }
+    let file = format!("{}.pem", kid);
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(dir.join(&file))?
+        .write_all(pem)?;
+    manifest.keys.push(KeyEntry {
+        kid: kid.clone(),
+        algorithm,
+        file,
+        created_at: Utc::now(),
+        activate_at: None,
+        retire_at: None,
+        verify_until: None,
+    });
+    Ok(kid)
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, Error> {
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc))
+}
+
+const KEYS_USAGE: &str = "usage: keys list
+       keys generate <ES256|EdDSA>
+       keys import <RS256|ES256|EdDSA> <private key pem>
+       keys promote <kid> [rfc3339 time]
+       keys retire <kid> [rfc3339 time]
+       keys prune";
+
+/// Admin command managing the key directory at `JWT_KEY_DIR`. New keys are only published
+/// for verification until promoted, so downstream JWKS caches can pick them up first.
+pub fn run_keys_command(args: &[String]) -> Result<(), Error> {
+    let dir = env::var("JWT_KEY_DIR").map_err(|_| format_err!("JWT_KEY_DIR must be set"))?;
+    let dir = Path::new(&dir);
+    fs::create_dir_all(dir)?;
+    let mut manifest = KeyManifest::read(dir)?;
+    let now = Utc::now();
+
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["list"] => {
+            for entry in &manifest.keys {
+                println!(
+                    "{} {:?} {} activate_at={:?} retire_at={:?} verify_until={:?}",
+                    entry.kid,
+                    entry.algorithm,
+                    entry.status(now),
+                    entry.activate_at,
+                    entry.retire_at,
+                    entry.verify_until
+                );
+            }
+            return Ok(());
+        }
+        ["generate", algorithm] => {
+            let algorithm = algorithm.parse()?;
+            let pem = pem::encode(&pem::Pem {
+                tag: "PRIVATE KEY".into(),
+                contents: generate_pkcs8(algorithm)?,
+            });
+            println!(
+                "{}",
+                add_key(&mut manifest, dir, algorithm, pem.as_bytes())?
+            );
+        }
+        ["import", algorithm, path] => {
+            let kid = add_key(&mut manifest, dir, algorithm.parse()?, &fs::read(path)?)?;
+            println!("{}", kid);
+        }
+        ["promote", kid] => manifest.promote(kid, now)?,
+        ["promote", kid, at] => manifest.promote(kid, parse_time(at)?)?,
+        ["retire", kid] => manifest.retire(kid, now)?,
+        ["retire", kid, at] => manifest.retire(kid, parse_time(at)?)?,
+        ["prune"] => {
+            let (keep, expired): (Vec<_>, Vec<_>) = manifest
+                .keys
+                .into_iter()
+                .partition(|entry| entry.verifies_at(now));
+            for entry in expired {
+                fs::remove_file(dir.join(&entry.file))?;
+                println!("removed {}", entry.kid);
+            }
+            manifest.keys = keep;
+        }
+        _ => return Err(format_err!("{}", KEYS_USAGE)),
+    }
+    if !manifest.keys.iter().any(|entry| entry.signs_at(now)) {
+        eprintln!("warning: no key is signing now, promote one before starting the server");
+    }
+    manifest.write(dir)
+}
+
+/// RFC 7638 JWK thumbprint: SHA-256 over the required members in lexicographic order
+fn jwk_thumbprint(params: &AlgorithmParameters) -> String {
+    let canonical = match params {
+        AlgorithmParameters::RSA(rsa) => {
+            format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+        }
+        AlgorithmParameters::EllipticCurve(ec) => format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            ec.x, ec.y
+        ),
+        AlgorithmParameters::OctetKeyPair(okp) => {
+            format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, okp.x)
+        }
+        AlgorithmParameters::OctetKey(oct) => format!(r#"{{"k":"{}","kty":"oct"}}"#, oct.value),
+    };
+    encode_config(
+        digest(&SHA256, canonical.as_bytes()).as_ref(),
+        URL_SAFE_NO_PAD,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+    use jsonwebtoken::{
+        decode, decode_header, encode,
+        jwk::{AlgorithmParameters, RSAKeyParameters},
+        Algorithm, Header, Validation,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde::{Deserialize, Serialize};
+
+    use crate::jwt_keys::{jwk_thumbprint, JwtKey, KeyEntry, KeyManifest, KeyRing, RingKey};
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct TestClaim {
+        sub: String,
+        exp: i64,
+    }
+
+    fn private_pem(pkcs8: &[u8]) -> Vec<u8> {
+        pem::encode(&pem::Pem {
+            tag: "PRIVATE KEY".into(),
+            contents: pkcs8.to_vec(),
+        })
+        .into_bytes()
+    }
+
+    fn entry(
+        kid: &str,
+        activate_at: Option<DateTime<Utc>>,
+        retire_at: Option<DateTime<Utc>>,
+    ) -> KeyEntry {
+        KeyEntry {
+            kid: kid.into(),
+            algorithm: Algorithm::EdDSA,
+            file: format!("{}.pem", kid),
+            created_at: Utc::now(),
+            activate_at,
+            retire_at,
+            verify_until: None,
+        }
+    }
+
+    fn ring_key(entry: KeyEntry) -> RingKey {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = JwtKey::from_pem(Algorithm::EdDSA, &private_pem(pkcs8.as_ref()))
+            .unwrap()
+            .with_kid(entry.kid.clone());
+        RingKey { entry, key }
+    }
+
+    fn roundtrip(key: &JwtKey) {
+        let claim = TestClaim {
+            sub: "auth".into(),
+            exp: 32_503_680_000,
+        };
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        let token = encode(&header, &claim, key.encoding_key()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid, Some(key.kid.clone()));
+        let decoded =
+            decode::<TestClaim>(&token, key.decoding_key(), &Validation::new(key.algorithm))
+                .unwrap();
+        assert_eq!(decoded.claims, claim);
+    }
+
+    #[test]
+    fn test_rfc7638_thumbprint() {
+        // example key from RFC 7638 section 3.1
+        let params = AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: Default::default(),
+            n: "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6\
+                tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-\
+                65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNL\
+                yrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJ\
+                zKnqDKgw"
+                .into(),
+            e: "AQAB".into(),
+        });
+        assert_eq!(
+            jwk_thumbprint(&params),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn test_es256_key() {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let key = JwtKey::from_pem(Algorithm::ES256, &private_pem(pkcs8.as_ref())).unwrap();
+        roundtrip(&key);
+        let jwk = serde_json::to_value(key.jwk().unwrap()).unwrap();
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(jwk["crv"], "P-256");
+        assert_eq!(jwk["alg"], "ES256");
+        assert_eq!(jwk["use"], "sig");
+        assert_eq!(jwk["kid"], key.kid.as_str());
+    }
+
+    #[test]
+    fn test_eddsa_key() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = JwtKey::from_pem(Algorithm::EdDSA, &private_pem(pkcs8.as_ref()))
+            .unwrap()
+            .with_kid("ed-1".into());
+        roundtrip(&key);
+        let jwk = serde_json::to_value(key.jwk().unwrap()).unwrap();
+        assert_eq!(jwk["kty"], "OKP");
+        assert_eq!(jwk["crv"], "Ed25519");
+        assert_eq!(jwk["kid"], "ed-1");
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pem = private_pem(pkcs8.as_ref());
+        assert!(JwtKey::from_pem(Algorithm::ES256, &pem).is_err());
+        assert!(JwtKey::from_pem(Algorithm::RS256, &pem).is_err());
+        assert!(JwtKey::from_pem(Algorithm::HS512, &pem).is_err());
+        assert!(JwtKey::from_secret(b"secret").jwk().is_none());
+    }
+
+    #[test]
+    fn test_manifest_promote() {
+        let now = Utc::now();
+        let mut manifest = KeyManifest {
+            keys: vec![
+                entry(
+                    "old",
+                    Some(now - Duration::days(60)),
+                    Some(now - Duration::days(30)),
+                ),
+                entry("current", Some(now - Duration::days(30)), None),
+                entry("next", None, None),
+            ],
+        };
+        let at = now + Duration::days(1);
+        manifest.promote("next", at).unwrap();
+        assert_eq!(manifest.keys[0].retire_at, Some(now - Duration::days(30)));
+        assert_eq!(manifest.keys[1].retire_at, Some(at));
+        assert_eq!(manifest.keys[2].activate_at, Some(at));
+        assert_eq!(manifest.keys[2].status(now), "verify-only");
+        assert_eq!(manifest.keys[2].status(at), "signing");
+        assert_eq!(manifest.keys[1].status(at), "retired");
+        assert!(manifest.promote("old", now).is_err());
+        assert!(manifest.promote("missing", now).is_err());
+    }
+
+    #[test]
+    fn test_manifest_verify_until() {
+        let now = Utc::now();
+        let mut manifest = KeyManifest {
+            keys: vec![entry("current", Some(now - Duration::days(30)), None)],
+        };
+        manifest.retire("current", now).unwrap();
+        let verify_until = manifest.keys[0].verify_until.unwrap();
+        assert!(verify_until > now);
+
+        // the grace period stays as recorded, whatever token lifetime is configured later
+        manifest.keys[0].verify_until = Some(now + Duration::days(1));
+        assert_eq!(
+            manifest.keys[0].status(now + Duration::hours(12)),
+            "retired"
+        );
+        assert_eq!(manifest.keys[0].status(now + Duration::days(1)), "expired");
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        let manifest: KeyManifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(manifest.keys[0].verify_until, Some(now + Duration::days(1)));
+
+        // manifests written before verify_until existed fall back to the access token lifetime
+        let legacy = entry("legacy", Some(now - Duration::days(30)), Some(now));
+        assert_eq!(legacy.status(now + Duration::minutes(1)), "retired");
+        assert_eq!(legacy.status(now + Duration::days(1)), "expired");
+    }
+
+    #[test]
+    fn test_key_ring_rotation() {
+        let now = Utc::now();
+        let rotated_at = now + Duration::hours(1);
+        let ring = KeyRing {
+            keys: vec![
+                ring_key(entry("a", Some(now - Duration::days(1)), Some(rotated_at))),
+                ring_key(entry("b", Some(rotated_at), None)),
+            ],
+        };
+        let claim = TestClaim {
+            sub: "auth".into(),
+            exp: 32_503_680_000,
+        };
+
+        let before = ring.encode(&claim, now).unwrap();
+        assert_eq!(decode_header(&before).unwrap().kid.as_deref(), Some("a"));
+        let after = ring.encode(&claim, rotated_at).unwrap();
+        assert_eq!(decode_header(&after).unwrap().kid.as_deref(), Some("b"));
+        // the next key is published before it starts signing
+        assert_eq!(ring.jwks(now).keys.len(), 2);
+
+        // tokens of the retired key keep verifying for one access token lifetime
+        let later = rotated_at + Duration::minutes(5);
+        let (decoded, retire_at) = ring.decode::<TestClaim>(&before, later).unwrap();
+        assert_eq!(decoded, claim);
+        assert_eq!(retire_at, Some(rotated_at));
+        assert!(ring.decode::<TestClaim>(&after, later).is_ok());
+
+        let expired = rotated_at + Duration::days(1);
+        assert!(ring.decode::<TestClaim>(&before, expired).is_err());
+        assert!(ring.decode::<TestClaim>(&after, expired).is_ok());
+        assert_eq!(ring.jwks(expired).keys.len(), 1);
+    }
+}
@@ -10,25 +10,55 @@
 #![allow(clippy::shadow_unrelated)]
 #![allow(clippy::missing_errors_doc)]
 #![allow(clippy::used_underscore_binding)]
+#![allow(non_local_definitions)]
 
 #[macro_use]
 extern crate diesel;
 
+mod admin_handler;
+mod admin_routes;
 mod auth_handler;
 mod auth_routes;
 mod change_password_handler;
 mod change_password_routes;
 mod email_service;
 mod errors;
-mod google_openid;
+mod forward_auth;
+mod forward_auth_routes;
+mod github_oauth;
+mod identity_handler;
+mod identity_routes;
 mod invitation_handler;
 mod invitation_routes;
+pub mod jwt_keys;
 pub mod logged_user;
+mod mfa_handler;
+mod mfa_routes;
 mod models;
+mod oauth_client_handler;
+mod oauth_handler;
+mod oauth_routes;
+mod openid_providers;
+mod openid_routes;
+mod password_reset_handler;
+mod password_reset_routes;
+mod pending_auth_handler;
+mod personal_token_handler;
+mod personal_token_routes;
+mod refresh_token_handler;
+mod refresh_token_routes;
 mod register_handler;
 mod register_routes;
+mod role_handler;
 pub mod rust_auth_server;
 mod schema;
+mod service_account_handler;
 mod ses_client;
+mod session_handler;
+mod session_routes;
 pub mod static_files;
+mod totp;
 pub mod utils;
+mod webauthn;
+mod webauthn_handler;
+mod webauthn_routes;
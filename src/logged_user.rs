@@ -1,6 +1,11 @@
 use actix_identity::Identity;
-use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
-use chrono::{DateTime, Utc};
+use actix_web::{
+    dev::Payload,
+    error::InternalError,
+    http::header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    Error, FromRequest, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
 use futures::{
     executor::block_on,
     future::{ready, Ready},
@@ -10,51 +15,123 @@ use log::debug;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
+    marker::PhantomData,
     sync::atomic::{AtomicBool, Ordering},
 };
 use tokio::sync::mpsc::Receiver;
+use uuid::Uuid;
 
 use crate::{
     errors::ServiceError,
-    models::{DbExecutor, User},
-    utils::{Claim, Token},
+    models::{DbExecutor, PersonalAccessToken, Session, User},
+    utils::{access_token_lifetime, hash_token, Claim, Token},
 };
 
 lazy_static! {
     pub static ref AUTHORIZED_USERS: AuthorizedUsers = AuthorizedUsers::new();
     pub static ref TRIGGER_DB_UPDATE: AuthTrigger = AuthTrigger::new();
+    pub static ref SESSIONS: SessionRegistry = SessionRegistry::new();
+    pub static ref PERSONAL_TOKENS: PersonalTokenRegistry = PersonalTokenRegistry::new();
 }
 
+/// Personal access tokens start with this, so they are told apart from JWTs without a lookup
+pub const PERSONAL_TOKEN_PREFIX: &str = "pat_";
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct LoggedUser {
     pub email: String,
+    // session the access token was issued for
+    pub session: Option<Uuid>,
+    #[serde(default)]
+    pub roles: Vec<String>,
 }
 
-impl<'a> From<Claim> for LoggedUser {
-    fn from(claim: Claim) -> Self {
+impl LoggedUser {
+    pub fn from_email(email: String) -> Self {
         Self {
-            email: claim.get_email(),
+            email,
+            session: None,
+            roles: Vec::new(),
+        }
+    }
+
+    // None for tokens of oauth clients and service accounts, they only grant their scope
+    fn from_claim(claim: Claim) -> Option<Self> {
+        if !claim.is_first_party() {
+            return None;
         }
+        let session = claim.get_session_id();
+        let roles = claim.get_roles().to_vec();
+        Some(Self {
+            email: claim.get_email()?,
+            session: Some(session),
+            roles,
+        })
     }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// The token of an `Authorization: Bearer` header
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    header.strip_prefix("Bearer ").map(str::trim)
+}
+
+// the user of an access token, unless its session was revoked or the user disabled since.
+// Sessions of oauth clients only grant the scope of the client, not access to this server.
+fn user_from_token(token: String) -> Option<LoggedUser> {
+    let user = LoggedUser::from_claim(Token::decode_token(&token.into()).ok()?)?;
+    if let Some(session) = user.session {
+        if SESSIONS.is_revoked(&session) || SESSIONS.is_client_session(&session) {
+            return None;
+        }
+    }
+    if AUTHORIZED_USERS.is_authorized(&user) {
+        if let Some(session) = user.session {
+            SESSIONS.touch(session);
+        }
+        return Some(user);
+    }
+    None
+}
+
+// api clients get a plain 401 rather than the login page
+fn bearer_unauthorized() -> actix_web::Error {
+    InternalError::from_response(
+        "Invalid bearer token",
+        HttpResponse::Unauthorized()
+            .header(WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)
+            .finish(),
+    )
+    .into()
 }
 
 fn _from_request(req: &HttpRequest, pl: &mut Payload) -> Result<LoggedUser, actix_web::Error> {
     if let Ok(s) = env::var("TESTENV") {
         if &s == "true" {
-            return Ok(LoggedUser {
-                email: "user@test".to_string(),
-            });
+            return Ok(LoggedUser::from_email("user@test".to_string()));
         }
     }
-    if let Some(identity) = block_on(Identity::from_request(req, pl))?.identity() {
-        let user: LoggedUser = Token::decode_token(&identity.into())?.into();
-        if AUTHORIZED_USERS.is_authorized(&user) {
-            return Ok(user);
-        }
+    // api clients send a bearer token, browsers the auth cookie
+    if let Some(token) = bearer_token(req) {
+        let user = if token.starts_with(PERSONAL_TOKEN_PREFIX) {
+            PERSONAL_TOKENS
+                .authenticate(token)
+                .filter(|user| AUTHORIZED_USERS.is_authorized(user))
+        } else {
+            user_from_token(token.to_string())
+        };
+        return user.ok_or_else(bearer_unauthorized);
     }
-    Err(ServiceError::Unauthorized.into())
+    block_on(Identity::from_request(req, pl))?
+        .identity()
+        .and_then(user_from_token)
+        .ok_or_else(|| ServiceError::Unauthorized.into())
 }
 
 impl FromRequest for LoggedUser {
@@ -67,6 +144,90 @@ impl FromRequest for LoggedUser {
     }
 }
 
+/// A role that handlers can require with `RequireRole`, e.g.
+///
+/// ```
+/// use rust_auth_server::logged_user::{RequireRole, Role};
+///
+/// struct Editor;
+///
+/// impl Role for Editor {
+///     const NAME: &'static str = "editor";
+/// }
+///
+/// async fn edit(editor: RequireRole<Editor>) -> String {
+///     editor.user.email
+/// }
+/// ```
+pub trait Role {
+    const NAME: &'static str;
+}
+
+pub struct Admin;
+
+impl Role for Admin {
+    const NAME: &'static str = "admin";
+}
+
+pub struct Inviter;
+
+impl Role for Inviter {
+    const NAME: &'static str = "inviter";
+}
+
+/// Extractor for a `LoggedUser` holding the role `R`, responds with 403 Forbidden otherwise
+#[derive(Debug)]
+pub struct RequireRole<R: Role> {
+    pub user: LoggedUser,
+    role: PhantomData<R>,
+}
+
+impl<R: Role> FromRequest for RequireRole<R> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, actix_web::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
+        ready(_from_request(req, pl).and_then(|user| {
+            if user.has_role(R::NAME) {
+                Ok(Self {
+                    user,
+                    role: PhantomData,
+                })
+            } else {
+                Err(ServiceError::Forbidden.into())
+            }
+        }))
+    }
+}
+
+/// Extractor for a user logged in with a session, the cookie or an access token of this
+/// server. Personal access tokens are refused with 403 Forbidden, they must not manage the
+/// credentials or sessions of their user.
+#[derive(Debug)]
+pub struct SessionUser {
+    pub email: String,
+    pub session: Uuid,
+    pub roles: Vec<String>,
+}
+
+impl FromRequest for SessionUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, actix_web::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
+        ready(_from_request(req, pl).and_then(|user| match user.session {
+            Some(session) => Ok(Self {
+                email: user.email,
+                session,
+                roles: user.roles,
+            }),
+            None => Err(ServiceError::Forbidden.into()),
+        }))
+    }
+}
+
 #[derive(Clone, Debug, Copy)]
 enum AuthStatus {
     Authorized(DateTime<Utc>),
@@ -74,7 +235,7 @@ enum AuthStatus {
 }
 
 #[derive(Debug, Default)]
-pub struct AuthorizedUsers(RwLock<HashMap<LoggedUser, AuthStatus>>);
+pub struct AuthorizedUsers(RwLock<HashMap<String, AuthStatus>>);
 
 impl AuthorizedUsers {
     pub fn new() -> Self {
@@ -82,7 +243,7 @@ impl AuthorizedUsers {
     }
 
     pub fn is_authorized(&self, user: &LoggedUser) -> bool {
-        if let Some(AuthStatus::Authorized(last_time)) = self.0.read().get(user) {
+        if let Some(AuthStatus::Authorized(last_time)) = self.0.read().get(&user.email) {
             let current_time = Utc::now();
             if (current_time - *last_time).num_minutes() < 15 {
                 return true;
@@ -98,14 +259,15 @@ impl AuthorizedUsers {
         } else {
             AuthStatus::NotAuthorized
         };
-        self.0.write().insert(user, status);
+        self.0.write().insert(user.email, status);
         Ok(())
     }
 
     pub fn merge_users(&self, users: &[LoggedUser]) -> Result<(), anyhow::Error> {
-        for user in self.0.read().keys() {
-            if !users.contains(&user) {
-                self.store_auth(user.clone(), false)?;
+        let emails: Vec<String> = self.0.read().keys().cloned().collect();
+        for email in emails {
+            if !users.iter().any(|user| user.email == email) {
+                self.store_auth(LoggedUser::from_email(email), false)?;
             }
         }
         for user in users {
@@ -115,7 +277,12 @@ impl AuthorizedUsers {
     }
 
     pub fn get_users(&self) -> Vec<LoggedUser> {
-        self.0.read().keys().cloned().collect()
+        self.0
+            .read()
+            .keys()
+            .cloned()
+            .map(LoggedUser::from_email)
+            .collect()
     }
 }
 
@@ -128,7 +295,9 @@ impl AuthTrigger {
     }
 
     pub fn check(&self) -> bool {
-        self.0.compare_and_swap(true, false, Ordering::SeqCst)
+        self.0
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
     }
 
     pub fn set(&self) {
@@ -141,7 +310,7 @@ pub fn fill_auth_from_db(pool: &DbExecutor) -> Result<(), anyhow::Error> {
     let users: Vec<LoggedUser> = if TRIGGER_DB_UPDATE.check() {
         User::get_authorized_users(pool)?
             .into_iter()
-            .map(|user| LoggedUser { email: user.email })
+            .map(|user| LoggedUser::from_email(user.email))
             .collect()
     } else {
         AUTHORIZED_USERS.get_users()
@@ -150,3 +319,315 @@ pub fn fill_auth_from_db(pool: &DbExecutor) -> Result<(), anyhow::Error> {
     debug!("{:?}", *AUTHORIZED_USERS);
     Ok(())
 }
+
+/// Sessions revoked recently enough that their access tokens may still be valid, the active
+/// sessions of oauth clients, whose tokens are not accepted as a `LoggedUser`, and the last
+/// time each active session was seen, written back to the db periodically
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    revoked: RwLock<HashMap<Uuid, NaiveDateTime>>,
+    client_sessions: RwLock<HashSet<Uuid>>,
+    last_seen: RwLock<HashMap<Uuid, NaiveDateTime>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_revoked(&self, session: &Uuid) -> bool {
+        self.revoked.read().contains_key(session)
+    }
+
+    pub fn revoke(&self, sessions: &[Uuid]) {
+        let current_time = Local::now().naive_local();
+        let mut revoked = self.revoked.write();
+        let mut last_seen = self.last_seen.write();
+        for session in sessions {
+            last_seen.remove(session);
+            revoked.insert(*session, current_time);
+        }
+    }
+
+    pub fn is_client_session(&self, session: &Uuid) -> bool {
+        self.client_sessions.read().contains(session)
+    }
+
+    pub fn add_client_session(&self, session: Uuid) {
+        self.client_sessions.write().insert(session);
+    }
+
+    fn replace_client_sessions(&self, sessions: Vec<Uuid>) {
+        *self.client_sessions.write() = sessions.into_iter().collect();
+    }
+
+    pub fn touch(&self, session: Uuid) {
+        self.last_seen
+            .write()
+            .insert(session, Local::now().naive_local());
+    }
+
+    fn merge_revoked(&self, sessions: Vec<(Uuid, NaiveDateTime)>, since: NaiveDateTime) {
+        let mut revoked = self.revoked.write();
+        revoked.extend(sessions);
+        revoked.retain(|_, revoked_at| *revoked_at > since);
+    }
+
+    fn take_last_seen(&self) -> HashMap<Uuid, NaiveDateTime> {
+        std::mem::take(&mut *self.last_seen.write())
+    }
+}
+
+pub fn fill_sessions_from_db(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    Session::update_last_seen(&SESSIONS.take_last_seen(), pool)?;
+    // access tokens of sessions revoked before this are expired anyway
+    let since = Local::now().naive_local() - access_token_lifetime();
+    SESSIONS.merge_revoked(Session::get_revoked_since(since, pool)?, since);
+    SESSIONS.replace_client_sessions(Session::get_client_sessions(pool)?);
+    Ok(())
+}
+
+// what the extractor needs to know of a personal access token
+#[derive(Debug, Clone)]
+struct PersonalTokenEntry {
+    id: Uuid,
+    email: String,
+    // the scopes of the token the user still holds
+    roles: Vec<String>,
+    expires_at: NaiveDateTime,
+}
+
+impl PersonalTokenEntry {
+    fn new(token: &PersonalAccessToken, user_roles: &[String]) -> Self {
+        Self {
+            id: token.id,
+            email: token.email.clone(),
+            roles: token
+                .scopes
+                .iter()
+                .filter(|scope| user_roles.contains(scope))
+                .cloned()
+                .collect(),
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+/// Active personal access tokens by hash, reloaded from the db periodically, and the last time
+/// each token was used, written back on reload. Tokens created or revoked on another instance
+/// are picked up with the next reload.
+#[derive(Debug, Default)]
+pub struct PersonalTokenRegistry {
+    tokens: RwLock<HashMap<String, PersonalTokenEntry>>,
+    last_used: RwLock<HashMap<Uuid, NaiveDateTime>>,
+}
+
+impl PersonalTokenRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, token: &PersonalAccessToken, user_roles: &[String]) {
+        self.tokens.write().insert(
+            token.token_hash.clone(),
+            PersonalTokenEntry::new(token, user_roles),
+        );
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        self.tokens.write().retain(|_, entry| entry.id != id);
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<LoggedUser> {
+        let current_time = Local::now().naive_local();
+        let entry = self
+            .tokens
+            .read()
+            .get(&hash_token(token))
+            .filter(|entry| entry.expires_at > current_time)
+            .cloned()?;
+        self.last_used.write().insert(entry.id, current_time);
+        Some(LoggedUser {
+            email: entry.email,
+            session: None,
+            roles: entry.roles,
+        })
+    }
+
+    fn replace(&self, tokens: &[(PersonalAccessToken, Vec<String>)]) {
+        *self.tokens.write() = tokens
+            .iter()
+            .map(|(token, roles)| {
+                (
+                    token.token_hash.clone(),
+                    PersonalTokenEntry::new(token, roles),
+                )
+            })
+            .collect();
+    }
+
+    fn take_last_used(&self) -> HashMap<Uuid, NaiveDateTime> {
+        std::mem::take(&mut *self.last_used.write())
+    }
+}
+
+pub fn fill_personal_tokens_from_db(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    PersonalAccessToken::update_last_used(&PERSONAL_TOKENS.take_last_used(), pool)?;
+    PERSONAL_TOKENS.replace(&PersonalAccessToken::get_active(pool)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Local};
+    use uuid::Uuid;
+
+    use actix_web::{
+        dev::Payload, http::header::AUTHORIZATION, http::StatusCode, test::TestRequest, FromRequest,
+    };
+
+    use crate::{
+        logged_user::{LoggedUser, PersonalTokenRegistry, SessionRegistry, AUTHORIZED_USERS},
+        models::{PersonalAccessToken, SlimUser},
+        utils::{hash_token, issuer, Token},
+    };
+
+    #[test]
+    fn test_session_registry() {
+        let registry = SessionRegistry::new();
+        let (revoked, active, stale) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        registry.touch(revoked);
+        registry.touch(active);
+        registry.revoke(&[revoked]);
+        assert!(registry.is_revoked(&revoked));
+        assert!(!registry.is_revoked(&active));
+        assert_eq!(
+            registry.take_last_seen().keys().collect::<Vec<_>>(),
+            vec![&active]
+        );
+        assert!(registry.take_last_seen().is_empty());
+
+        // revocations older than the access token lifetime are dropped on merge
+        let now = Local::now().naive_local();
+        registry.merge_revoked(
+            vec![(stale, now - Duration::hours(1))],
+            now - Duration::minutes(15),
+        );
+        assert!(registry.is_revoked(&revoked));
+        assert!(!registry.is_revoked(&stale));
+
+        // sessions of oauth clients created on this instance count before the next reload
+        let client_session = Uuid::new_v4();
+        registry.add_client_session(client_session);
+        assert!(registry.is_client_session(&client_session));
+        registry.replace_client_sessions(vec![stale]);
+        assert!(!registry.is_client_session(&client_session));
+        assert!(registry.is_client_session(&stale));
+        assert!(!registry.is_client_session(&active));
+    }
+
+    #[test]
+    fn test_personal_token_registry() {
+        let now = Local::now().naive_local();
+        let token = PersonalAccessToken {
+            id: Uuid::new_v4(),
+            email: "user@example.com".into(),
+            name: "deploy script".into(),
+            token_hash: hash_token("pat_secret"),
+            scopes: vec!["admin".into(), "editor".into()],
+            created_at: now,
+            expires_at: now + Duration::days(1),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        let expired = PersonalAccessToken {
+            id: Uuid::new_v4(),
+            token_hash: hash_token("pat_expired"),
+            expires_at: now - Duration::days(1),
+            ..token.clone()
+        };
+        let registry = PersonalTokenRegistry::new();
+        // the admin role was taken from the user since the token was created
+        registry.replace(&[
+            (token.clone(), vec!["editor".into()]),
+            (expired, vec!["editor".into()]),
+        ]);
+
+        let user = registry.authenticate("pat_secret").unwrap();
+        assert_eq!(user.email, "user@example.com");
+        assert_eq!(user.roles, vec!["editor".to_string()]);
+        assert_eq!(user.session, None);
+        assert!(registry.authenticate("pat_expired").is_none());
+        assert!(registry.authenticate("pat_unknown").is_none());
+        assert_eq!(
+            registry.take_last_used().keys().collect::<Vec<_>>(),
+            vec![&token.id]
+        );
+
+        registry.remove(token.id);
+        assert!(registry.authenticate("pat_secret").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_bearer_token() {
+        let user = SlimUser {
+            email: "bearer@example.com".into(),
+        };
+        AUTHORIZED_USERS
+            .store_auth(LoggedUser::from_email(user.email.clone()), true)
+            .unwrap();
+        let token: String = Token::create_token(&user, Uuid::new_v4(), vec!["editor".into()])
+            .unwrap()
+            .into();
+
+        let request = TestRequest::default()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .to_http_request();
+        let logged_user = LoggedUser::from_request(&request, &mut Payload::None)
+            .await
+            .unwrap();
+        assert_eq!(logged_user.email, user.email);
+        assert_eq!(logged_user.roles, vec!["editor".to_string()]);
+        // access tokens share the issuer of id tokens and the discovery document
+        let claim = Token::decode_token(&token.clone().into()).unwrap();
+        assert_eq!(claim.get_issuer(), issuer());
+
+        // a bad bearer token is a plain 401, not the login page served for missing cookies
+        let request = TestRequest::default()
+            .header(AUTHORIZATION, "Bearer not-a-jwt")
+            .to_http_request();
+        let error = LoggedUser::from_request(&request, &mut Payload::None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_client_token_is_refused() {
+        let user = SlimUser {
+            email: "client-bearer@example.com".into(),
+        };
+        AUTHORIZED_USERS
+            .store_auth(LoggedUser::from_email(user.email.clone()), true)
+            .unwrap();
+        // a token issued to an oauth client, for a session the registry doesn't know yet
+        let token: String =
+            Token::create_client_token(&user, Uuid::new_v4(), "some-client", "openid".into())
+                .unwrap()
+                .into();
+
+        let request = TestRequest::default()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .to_http_request();
+        let error = LoggedUser::from_request(&request, &mut Payload::None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -1,13 +1,28 @@
 use anyhow::Error;
-use std::env::var;
+use std::env::{args, var};
 
-use rust_auth_server::rust_auth_server::run_auth_server;
+use rust_auth_server::{
+    jwt_keys::run_keys_command,
+    rust_auth_server::{load_config, run_auth_server, run_roles_command},
+};
 
 #[actix_rt::main]
 async fn main() -> Result<(), Error> {
+    let args: Vec<String> = args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("keys") => {
+            load_config();
+            return run_keys_command(&args[1..]);
+        }
+        Some("roles") => {
+            load_config();
+            return run_roles_command(&args[1..]);
+        }
+        _ => (),
+    }
     let port = var("PORT")
         .ok()
         .and_then(|port| port.parse::<u32>().ok())
         .unwrap_or(3000);
-    run_auth_server(port).await.map_err(Into::into)
+    run_auth_server(port).await
 }
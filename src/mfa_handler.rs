@@ -0,0 +1,304 @@
+use async_trait::async_trait;
+use bcrypt::verify;
+use chrono::{DateTime, Local, Utc};
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, HandleRequest, SlimUser, User, UserMfa},
+    totp::{
+        generate_recovery_codes, generate_secret, hash_recovery_code, otpauth_uri, verify_code,
+    },
+    utils::get_random_string,
+};
+
+// how long a user has to enter their code after the password has been verified
+const MFA_PENDING_SECONDS: i64 = 300;
+// pending logins are dropped after this many wrong codes
+const MFA_MAX_ATTEMPTS: u32 = 5;
+
+lazy_static! {
+    static ref MFA_PENDING: RwLock<HashMap<String, MfaPending>> = RwLock::new(HashMap::new());
+}
+
+struct MfaPending {
+    email: String,
+    attempts: u32,
+    timestamp: DateTime<Utc>,
+}
+
+/// Register a login whose password has been verified but still needs a second factor,
+/// returns the opaque token the client must send back along with the code.
+pub fn start_mfa_login(email: String) -> String {
+    let mfa_token = get_random_string();
+    MFA_PENDING.write().insert(
+        mfa_token.clone(),
+        MfaPending {
+            email,
+            attempts: 0,
+            timestamp: Utc::now(),
+        },
+    );
+    mfa_token
+}
+
+fn get_pending_email(mfa_token: &str) -> Option<String> {
+    MFA_PENDING.read().get(mfa_token).and_then(|pending| {
+        if (Utc::now() - pending.timestamp).num_seconds() <= MFA_PENDING_SECONDS {
+            Some(pending.email.clone())
+        } else {
+            None
+        }
+    })
+}
+
+fn record_failed_attempt(mfa_token: &str) {
+    let mut pending_map = MFA_PENDING.write();
+    let exhausted = pending_map.get_mut(mfa_token).is_some_and(|pending| {
+        pending.attempts += 1;
+        pending.attempts >= MFA_MAX_ATTEMPTS
+    });
+    if exhausted {
+        pending_map.remove(mfa_token);
+    }
+}
+
+pub fn cleanup_mfa_pending() {
+    MFA_PENDING
+        .write()
+        .retain(|_, pending| (Utc::now() - pending.timestamp).num_seconds() <= MFA_PENDING_SECONDS);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaCode {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordData {
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub struct EnrollMfa {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[async_trait]
+impl HandleRequest<EnrollMfa> for DbExecutor {
+    type Result = Result<MfaEnrollment, ServiceError>;
+
+    async fn handle(&self, msg: EnrollMfa) -> Self::Result {
+        use crate::schema::user_mfa::dsl::{
+            created_at, email, enabled, last_used_step, recovery_codes, totp_secret, user_mfa,
+        };
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let existing = user_mfa
+                    .find(&msg.email)
+                    .for_update()
+                    .first::<UserMfa>(&conn)
+                    .optional()?;
+                if existing.is_some_and(|mfa| mfa.enabled) {
+                    return Err(ServiceError::BadRequest("MFA already enabled".into()));
+                }
+                // an unconfirmed enrollment is simply replaced with a new secret
+                let new_mfa = UserMfa {
+                    email: msg.email.clone(),
+                    totp_secret: generate_secret(),
+                    enabled: false,
+                    last_used_step: None,
+                    recovery_codes: Vec::new(),
+                    created_at: Local::now().naive_local(),
+                };
+                diesel::insert_into(user_mfa)
+                    .values(&new_mfa)
+                    .on_conflict(email)
+                    .do_update()
+                    .set((
+                        totp_secret.eq(&new_mfa.totp_secret),
+                        enabled.eq(false),
+                        last_used_step.eq(None::<i64>),
+                        recovery_codes.eq(Vec::<String>::new()),
+                        created_at.eq(new_mfa.created_at),
+                    ))
+                    .execute(&conn)?;
+
+                let issuer = env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+                let uri = otpauth_uri(&new_mfa.totp_secret, &msg.email, &issuer);
+                Ok(MfaEnrollment {
+                    secret: new_mfa.totp_secret,
+                    otpauth_uri: uri,
+                })
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfirmMfa {
+    pub email: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[async_trait]
+impl HandleRequest<ConfirmMfa> for DbExecutor {
+    type Result = Result<RecoveryCodes, ServiceError>;
+
+    async fn handle(&self, msg: ConfirmMfa) -> Self::Result {
+        use crate::schema::user_mfa::dsl::{enabled, last_used_step, recovery_codes, user_mfa};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let mfa = user_mfa
+                    .find(&msg.email)
+                    .filter(enabled.eq(false))
+                    .for_update()
+                    .first::<UserMfa>(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest("No pending MFA enrollment".into()))?;
+                let step = verify_code(&mfa.totp_secret, &msg.code, Utc::now().timestamp(), None)
+                    .ok_or_else(|| ServiceError::BadRequest("Invalid MFA code".into()))?;
+
+                let codes = generate_recovery_codes();
+                let hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+                diesel::update(user_mfa.find(&msg.email))
+                    .set((
+                        enabled.eq(true),
+                        last_used_step.eq(step),
+                        recovery_codes.eq(hashes),
+                    ))
+                    .execute(&conn)?;
+                Ok(RecoveryCodes {
+                    recovery_codes: codes,
+                })
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaLogin {
+    pub mfa_token: String,
+    pub code: String,
+    // see `AuthData`
+    #[serde(default)]
+    pub return_token: bool,
+}
+
+#[async_trait]
+impl HandleRequest<MfaLogin> for DbExecutor {
+    type Result = Result<SlimUser, ServiceError>;
+
+    async fn handle(&self, msg: MfaLogin) -> Self::Result {
+        use crate::schema::user_mfa::dsl::{enabled, last_used_step, recovery_codes, user_mfa};
+
+        let email_ = get_pending_email(&msg.mfa_token)
+            .ok_or_else(|| ServiceError::BadRequest("Invalid or expired MFA token".into()))?;
+
+        let dbex = self.clone();
+        let code = msg.code.clone();
+        let user_email = email_.clone();
+        let verified = spawn_blocking(move || -> Result<bool, ServiceError> {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let mfa = match user_mfa
+                    .find(&user_email)
+                    .filter(enabled.eq(true))
+                    .for_update()
+                    .first::<UserMfa>(&conn)
+                    .optional()?
+                {
+                    Some(mfa) => mfa,
+                    None => return Ok(false),
+                };
+                if let Some(step) = verify_code(
+                    &mfa.totp_secret,
+                    &code,
+                    Utc::now().timestamp(),
+                    mfa.last_used_step,
+                ) {
+                    diesel::update(user_mfa.find(&user_email))
+                        .set(last_used_step.eq(step))
+                        .execute(&conn)?;
+                    return Ok(true);
+                }
+                // fall back to a single-use recovery code
+                let code_hash = hash_recovery_code(&code);
+                if mfa.recovery_codes.contains(&code_hash) {
+                    let remaining: Vec<String> = mfa
+                        .recovery_codes
+                        .into_iter()
+                        .filter(|h| h != &code_hash)
+                        .collect();
+                    diesel::update(user_mfa.find(&user_email))
+                        .set(recovery_codes.eq(remaining))
+                        .execute(&conn)?;
+                    return Ok(true);
+                }
+                Ok(false)
+            })
+        })
+        .await??;
+
+        if verified {
+            MFA_PENDING.write().remove(&msg.mfa_token);
+            Ok(SlimUser { email: email_ })
+        } else {
+            record_failed_attempt(&msg.mfa_token);
+            Err(ServiceError::BadRequest("Invalid MFA code".into()))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DisableMfa {
+    pub email: String,
+    pub password: String,
+}
+
+#[async_trait]
+impl HandleRequest<DisableMfa> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: DisableMfa) -> Self::Result {
+        use crate::schema::{user_mfa::dsl::user_mfa, users::dsl::users};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let user = users.find(&msg.email).first::<User>(&conn)?;
+            match verify(&msg.password, &user.password) {
+                Ok(true) => diesel::delete(user_mfa.find(&msg.email))
+                    .execute(&conn)
+                    .map(|deleted| deleted > 0)
+                    .map_err(Into::into),
+                _ => Err(ServiceError::BadRequest("Invalid password".into())),
+            }
+        })
+        .await?
+    }
+}
@@ -0,0 +1,80 @@
+use actix_identity::Identity;
+use actix_web::{
+    web::{Data, Json},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use maplit::hashmap;
+
+use crate::{
+    auth_routes::complete_login,
+    logged_user::SessionUser,
+    mfa_handler::{ConfirmMfa, DisableMfa, EnrollMfa, MfaCode, MfaLogin, PasswordData},
+    models::{DbExecutor, HandleRequest},
+};
+
+pub async fn login(
+    mfa_data: Json<MfaLogin>,
+    request: HttpRequest,
+    id: Identity,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let return_token = mfa_data.return_token;
+    let user = db.handle(mfa_data.into_inner()).await?;
+    complete_login(
+        &user,
+        &["pwd", "otp", "mfa"],
+        return_token,
+        &request,
+        &id,
+        &db,
+    )
+    .await
+    .map_err(Into::into)
+}
+
+pub async fn enroll(
+    session_user: SessionUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = EnrollMfa {
+        email: session_user.email,
+    };
+    match db.handle(msg).await {
+        Ok(enrollment) => Ok(HttpResponse::Ok().json(enrollment)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn confirm(
+    session_user: SessionUser,
+    mfa_code: Json<MfaCode>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ConfirmMfa {
+        email: session_user.email,
+        code: mfa_code.into_inner().code,
+    };
+    match db.handle(msg).await {
+        Ok(recovery_codes) => Ok(HttpResponse::Ok().json(recovery_codes)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn disable(
+    session_user: SessionUser,
+    password_data: Json<PasswordData>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = DisableMfa {
+        email: session_user.email,
+        password: password_data.into_inner().password,
+    };
+    match db.handle(msg).await {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            let result = hashmap! { "status" => status };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
@@ -1,17 +1,21 @@
 use actix::{Actor, SyncContext};
 use anyhow::Error;
 use async_trait::async_trait;
-use chrono::{Local, NaiveDateTime};
+use chrono::{Duration, Local, NaiveDateTime};
 use diesel::{
     pg::PgConnection,
     r2d2::{ConnectionManager, Pool},
     ExpressionMethods, QueryDsl, RunQueryDsl,
 };
 use serde::{Deserialize, Serialize};
-use std::convert::From;
+use std::{collections::HashMap, convert::From};
 use uuid::Uuid;
 
-use crate::schema::{invitations, users};
+use crate::schema::{
+    invitations, oauth_clients, oauth_codes, oauth_consents, oauth_device_codes, password_resets,
+    pending_auths, personal_access_tokens, refresh_tokens, roles, service_account_secrets,
+    service_accounts, sessions, user_identities, user_mfa, user_roles, users, webauthn_credentials,
+};
 
 /// This is db executor actor. can be run in parallel
 #[derive(Clone)]
@@ -32,13 +36,18 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub created_at: NaiveDateTime,
+    // disabled users can neither log in nor use existing tokens
+    pub disabled_at: Option<NaiveDateTime>,
 }
 
 impl User {
     pub fn get_authorized_users(pool: &DbExecutor) -> Result<Vec<Self>, Error> {
-        use crate::schema::users::dsl::users;
+        use crate::schema::users::dsl::{disabled_at, users};
         let conn = pool.0.get()?;
-        users.load(&conn).map_err(Into::into)
+        users
+            .filter(disabled_at.is_null())
+            .load(&conn)
+            .map_err(Into::into)
     }
 
     pub fn from_details(email: String, password: String) -> Self {
@@ -46,6 +55,7 @@ impl User {
             email,
             password,
             created_at: Local::now().naive_local(),
+            disabled_at: None,
         }
     }
 
@@ -65,6 +75,353 @@ pub struct Invitation {
     pub id: Uuid,
     pub email: String,
     pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    // set in the transaction that registers the user, an invitation can only be used once
+    pub accepted_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    // None for self invitations from an allowed domain
+    pub invited_by: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InvitationStatus {
+    Pending,
+    Accepted,
+    Revoked,
+    Expired,
+}
+
+impl Invitation {
+    // invitations are valid for 24 hours
+    pub fn from_email(email: String, invited_by: Option<String>) -> Self {
+        let current_time = Local::now().naive_local();
+        Self {
+            id: Uuid::new_v4(),
+            email,
+            expires_at: current_time + Duration::hours(24),
+            created_at: current_time,
+            accepted_at: None,
+            revoked_at: None,
+            invited_by,
+        }
+    }
+
+    pub fn status(&self, now: NaiveDateTime) -> InvitationStatus {
+        if self.accepted_at.is_some() {
+            InvitationStatus::Accepted
+        } else if self.revoked_at.is_some() {
+            InvitationStatus::Revoked
+        } else if self.expires_at <= now {
+            InvitationStatus::Expired
+        } else {
+            InvitationStatus::Pending
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "password_resets"]
+pub struct PasswordReset {
+    pub id: Uuid,
+    pub email: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+// an application allowed to use the oauth endpoints, the secret is only shown on creation
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "oauth_clients"]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub secret_hash: String,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+    // exact urls an authorization code may be sent to
+    pub redirect_uris: Vec<String>,
+    // clients that cannot keep a secret, e.g. native apps, authenticate with PKCE only
+    pub public_client: bool,
+}
+
+// a pending device authorization (RFC 8628), approved by a user entering the user code
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "oauth_device_codes"]
+pub struct OAuthDeviceCode {
+    pub device_code_hash: String,
+    pub user_code: String,
+    pub client_id: String,
+    pub scope: String,
+    pub poll_interval: i32,
+    pub last_polled_at: Option<NaiveDateTime>,
+    pub email: Option<String>,
+    pub auth_time: Option<NaiveDateTime>,
+    pub auth_methods: Vec<String>,
+    pub denied: bool,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+// a machine identity for the client credentials grant, not a user
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "service_accounts"]
+pub struct ServiceAccount {
+    pub account_id: String,
+    pub description: String,
+    // the most a token of this account may be granted
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "service_account_secrets"]
+pub struct ServiceAccountSecret {
+    pub secret_hash: String,
+    pub account_id: String,
+    pub created_at: NaiveDateTime,
+    // set when the secret is rotated out
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+// a single use authorization code, redeemed at the token endpoint
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "oauth_codes"]
+pub struct OAuthCode {
+    pub code_hash: String,
+    pub client_id: String,
+    pub email: String,
+    // as sent to the authorization endpoint, the token request has to repeat it exactly
+    pub redirect_uri: Option<String>,
+    pub scope: String,
+    // PKCE S256 challenge
+    pub code_challenge: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    // OpenID Connect request nonce, repeated in the id token
+    pub nonce: Option<String>,
+    // when and how the user logged in, from the browser session
+    pub auth_time: NaiveDateTime,
+    pub auth_methods: Vec<String>,
+}
+
+// scopes a user already approved for a client, not asked again
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "oauth_consents"]
+pub struct OAuthConsent {
+    pub email: String,
+    pub client_id: String,
+    pub scope: String,
+    pub created_at: NaiveDateTime,
+}
+
+// a login started at a provider, kept in the database so that the callback may reach any
+// instance
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "pending_auths"]
+pub struct PendingAuth {
+    pub state_hash: String,
+    // the state is only accepted on the callback of the provider it was issued for
+    pub provider: String,
+    // set when a logged in user links the provider account instead of logging in
+    pub link_to: Option<String>,
+    pub nonce: String,
+    // PKCE, only the challenge was sent to the provider
+    pub code_verifier: String,
+    pub final_url: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "refresh_tokens"]
+pub struct RefreshToken {
+    pub id: Uuid,
+    // all tokens obtained by rotating the same login share a family, the id of that session
+    pub family_id: Uuid,
+    pub email: String,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "sessions"]
+pub struct Session {
+    pub id: Uuid,
+    pub email: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    // the oauth client the session was authorized for, None for logins at this server
+    pub client_id: Option<String>,
+    // RFC 8176 authentication method references, e.g. pwd, otp, hwk
+    pub auth_methods: Vec<String>,
+    // the scope granted to the oauth client
+    pub scope: Option<String>,
+}
+
+impl Session {
+    pub fn get_revoked_since(
+        since: NaiveDateTime,
+        pool: &DbExecutor,
+    ) -> Result<Vec<(Uuid, NaiveDateTime)>, Error> {
+        use crate::schema::sessions::dsl::{id, revoked_at, sessions};
+        let conn = pool.0.get()?;
+        let revoked: Vec<(Uuid, Option<NaiveDateTime>)> = sessions
+            .filter(revoked_at.gt(since))
+            .select((id, revoked_at))
+            .load(&conn)?;
+        Ok(revoked
+            .into_iter()
+            .filter_map(|(session, revoked)| revoked.map(|revoked| (session, revoked)))
+            .collect())
+    }
+
+    /// Active sessions authorized for an oauth client
+    pub fn get_client_sessions(pool: &DbExecutor) -> Result<Vec<Uuid>, Error> {
+        use crate::schema::sessions::dsl::{client_id, id, revoked_at, sessions};
+        let conn = pool.0.get()?;
+        sessions
+            .filter(client_id.is_not_null())
+            .filter(revoked_at.is_null())
+            .select(id)
+            .load(&conn)
+            .map_err(Into::into)
+    }
+
+    pub fn update_last_seen(
+        activity: &HashMap<Uuid, NaiveDateTime>,
+        pool: &DbExecutor,
+    ) -> Result<(), Error> {
+        use crate::schema::sessions::dsl::{id, last_seen_at, sessions};
+        let conn = pool.0.get()?;
+        for (session_id, seen_at) in activity {
+            diesel::update(sessions.filter(id.eq(session_id)))
+                .set(last_seen_at.eq(seen_at))
+                .execute(&conn)?;
+        }
+        Ok(())
+    }
+}
+
+// a token users create for scripts, accepted as `Authorization: Bearer`
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "personal_access_tokens"]
+pub struct PersonalAccessToken {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub token_hash: String,
+    // the roles of the user the token may use
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl PersonalAccessToken {
+    /// Tokens that are neither revoked nor expired, with the current roles of their users
+    pub fn get_active(pool: &DbExecutor) -> Result<Vec<(Self, Vec<String>)>, Error> {
+        use crate::schema::{personal_access_tokens::dsl as tokens, user_roles::dsl as roles};
+        let conn = pool.0.get()?;
+        let active: Vec<Self> = tokens::personal_access_tokens
+            .filter(tokens::revoked_at.is_null())
+            .filter(tokens::expires_at.gt(Local::now().naive_local()))
+            .load(&conn)?;
+        let emails: Vec<&str> = active.iter().map(|token| token.email.as_str()).collect();
+        let user_roles: Vec<(String, String)> = roles::user_roles
+            .filter(roles::email.eq_any(emails))
+            .select((roles::email, roles::role))
+            .load(&conn)?;
+        Ok(active
+            .into_iter()
+            .map(|token| {
+                let roles = user_roles
+                    .iter()
+                    .filter(|(email, _)| *email == token.email)
+                    .map(|(_, role)| role.clone())
+                    .collect();
+                (token, roles)
+            })
+            .collect())
+    }
+
+    pub fn update_last_used(
+        activity: &HashMap<Uuid, NaiveDateTime>,
+        pool: &DbExecutor,
+    ) -> Result<(), Error> {
+        use crate::schema::personal_access_tokens::dsl::{
+            id, last_used_at, personal_access_tokens,
+        };
+        let conn = pool.0.get()?;
+        for (token_id, used_at) in activity {
+            diesel::update(personal_access_tokens.filter(id.eq(token_id)))
+                .set(last_used_at.eq(used_at))
+                .execute(&conn)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "roles"]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "user_roles"]
+pub struct UserRole {
+    pub email: String,
+    pub role: String,
+    pub created_at: NaiveDateTime,
+}
+
+// an account at an external login provider, logins match on the subject so that changing
+// the email at the provider does not matter
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "user_identities"]
+pub struct UserIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    // the email the provider asserted at the last login
+    pub provider_email: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_login_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "user_mfa"]
+pub struct UserMfa {
+    pub email: String,
+    pub totp_secret: String,
+    pub enabled: bool,
+    pub last_used_step: Option<i64>,
+    pub recovery_codes: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable, Insertable, Clone)]
+#[table_name = "webauthn_credentials"]
+pub struct WebauthnCredential {
+    // base64url encoded credential id
+    pub id: String,
+    pub email: String,
+    pub name: String,
+    // COSE encoded public key
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,3 +434,29 @@ impl From<User> for SlimUser {
         Self { email: user.email }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Local};
+
+    use crate::models::{Invitation, InvitationStatus};
+
+    #[test]
+    fn test_invitation_status() {
+        let now = Local::now().naive_local();
+        let mut invitation = Invitation::from_email("test@example.com".into(), None);
+        assert_eq!(invitation.status(now), InvitationStatus::Pending);
+        assert_eq!(
+            invitation.status(now + Duration::hours(25)),
+            InvitationStatus::Expired
+        );
+        invitation.revoked_at = Some(now);
+        assert_eq!(invitation.status(now), InvitationStatus::Revoked);
+        // using the invitation wins over anything that happened afterwards
+        invitation.accepted_at = Some(now);
+        assert_eq!(
+            invitation.status(now + Duration::hours(25)),
+            InvitationStatus::Accepted
+        );
+    }
+}
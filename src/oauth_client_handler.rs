@@ -0,0 +1,203 @@
+use async_trait::async_trait;
+use chrono::{Local, NaiveDateTime};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use ring::constant_time::verify_slices_are_equal;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use url::Url;
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, HandleRequest, OAuthClient},
+    utils::{get_random_string, get_random_token, hash_token},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthClientInfo {
+    pub client_id: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub redirect_uris: Vec<String>,
+    pub public_client: bool,
+}
+
+impl From<OAuthClient> for OAuthClientInfo {
+    fn from(client: OAuthClient) -> Self {
+        Self {
+            client_id: client.client_id,
+            name: client.name,
+            created_at: client.created_at,
+            disabled_at: client.disabled_at,
+            redirect_uris: client.redirect_uris,
+            public_client: client.public_client,
+        }
+    }
+}
+
+// redirect uris are compared exactly, so they have to be absolute and without a fragment
+fn check_redirect_uri(redirect_uri: &str) -> Result<(), ServiceError> {
+    match Url::parse(redirect_uri) {
+        Ok(url) if url.fragment().is_none() => Ok(()),
+        _ => Err(ServiceError::BadRequest(format!(
+            "Invalid redirect uri {}",
+            redirect_uri
+        ))),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateOAuthClient {
+    pub name: String,
+    // only needed for the authorization code grant
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub public_client: bool,
+}
+
+// the only time the secret is available
+#[derive(Debug, Serialize)]
+pub struct NewOAuthClient {
+    #[serde(flatten)]
+    pub client: OAuthClientInfo,
+    pub client_secret: String,
+}
+
+#[async_trait]
+impl HandleRequest<CreateOAuthClient> for DbExecutor {
+    type Result = Result<NewOAuthClient, ServiceError>;
+
+    async fn handle(&self, msg: CreateOAuthClient) -> Self::Result {
+        use crate::schema::oauth_clients::dsl::oauth_clients;
+
+        for redirect_uri in &msg.redirect_uris {
+            check_redirect_uri(redirect_uri)?;
+        }
+        let client_secret = get_random_token();
+        let client = OAuthClient {
+            client_id: get_random_string(),
+            name: msg.name,
+            secret_hash: hash_token(&client_secret),
+            created_at: Local::now().naive_local(),
+            disabled_at: None,
+            redirect_uris: msg.redirect_uris,
+            public_client: msg.public_client,
+        };
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let client: OAuthClient = diesel::insert_into(oauth_clients)
+                .values(&client)
+                .get_result(&conn)?;
+            Ok(NewOAuthClient {
+                client: client.into(),
+                client_secret,
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct ListOAuthClients;
+
+#[async_trait]
+impl HandleRequest<ListOAuthClients> for DbExecutor {
+    type Result = Result<Vec<OAuthClientInfo>, ServiceError>;
+
+    async fn handle(&self, _: ListOAuthClients) -> Self::Result {
+        use crate::schema::oauth_clients::dsl::{name, oauth_clients};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let clients: Vec<OAuthClient> = oauth_clients.order(name.asc()).load(&conn)?;
+            Ok(clients.into_iter().map(Into::into).collect())
+        })
+        .await?
+    }
+}
+
+/// An enabled client by id, without checking any credentials
+#[derive(Debug)]
+pub struct GetOAuthClient {
+    pub client_id: String,
+}
+
+#[async_trait]
+impl HandleRequest<GetOAuthClient> for DbExecutor {
+    type Result = Result<Option<OAuthClient>, ServiceError>;
+
+    async fn handle(&self, msg: GetOAuthClient) -> Self::Result {
+        use crate::schema::oauth_clients::dsl::{disabled_at, oauth_clients};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            oauth_clients
+                .find(&msg.client_id)
+                .filter(disabled_at.is_null())
+                .first(&conn)
+                .optional()
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteOAuthClient {
+    pub client_id: String,
+}
+
+#[async_trait]
+impl HandleRequest<DeleteOAuthClient> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: DeleteOAuthClient) -> Self::Result {
+        use crate::schema::oauth_clients::dsl::oauth_clients;
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::delete(oauth_clients.find(&msg.client_id))
+                .execute(&conn)
+                .map(|deleted| deleted > 0)
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+/// Check the credentials a client presented, returns the client if they are valid
+#[derive(Debug)]
+pub struct AuthenticateClient {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[async_trait]
+impl HandleRequest<AuthenticateClient> for DbExecutor {
+    type Result = Result<Option<OAuthClient>, ServiceError>;
+
+    async fn handle(&self, msg: AuthenticateClient) -> Self::Result {
+        use crate::schema::oauth_clients::dsl::{disabled_at, oauth_clients};
+
+        let secret_hash = hash_token(&msg.client_secret);
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let client: Option<OAuthClient> = oauth_clients
+                .find(&msg.client_id)
+                .filter(disabled_at.is_null())
+                .first(&conn)
+                .optional()?;
+            Ok(client.filter(|client| {
+                verify_slices_are_equal(client.secret_hash.as_bytes(), secret_hash.as_bytes())
+                    .is_ok()
+            }))
+        })
+        .await?
+    }
+}
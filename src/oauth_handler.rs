@@ -0,0 +1,859 @@
+use async_trait::async_trait;
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use rand::{thread_rng, Rng};
+use ring::constant_time::verify_slices_are_equal;
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::{
+    errors::ServiceError,
+    jwt_keys::id_token_signing_algorithm,
+    models::{
+        DbExecutor, HandleRequest, OAuthCode, OAuthConsent, OAuthDeviceCode, RefreshToken, User,
+    },
+    role_handler::get_user_roles,
+    utils::{get_random_token, hash_token, issuer, pkce_challenge, unix_time, Token},
+};
+
+/// Scopes clients may request, offline_access asks for a refresh token
+pub const SUPPORTED_SCOPES: &[&str] = &["openid", "email", "profile", "offline_access"];
+
+/// The supported scopes, without openid while access tokens are signed with a shared secret
+pub fn supported_scopes() -> Vec<&'static str> {
+    let id_tokens = id_token_signing_algorithm().is_some();
+    SUPPORTED_SCOPES
+        .iter()
+        .copied()
+        .filter(|scope| id_tokens || *scope != "openid")
+        .collect()
+}
+
+// time between the authorization and the token request
+const AUTHORIZATION_CODE_MINUTES: i64 = 10;
+
+/// Normalize a scope parameter to its sorted, distinct scopes, None if any is not supported
+pub fn parse_scope<'a>(scope: &'a str, supported: &[&str]) -> Option<Vec<&'a str>> {
+    let mut scopes: Vec<&str> = scope.split_whitespace().collect();
+    if !scopes.iter().all(|scope| supported.contains(scope)) {
+        return None;
+    }
+    scopes.sort_unstable();
+    scopes.dedup();
+    Some(scopes)
+}
+
+pub fn has_scope(scope: &str, name: &str) -> bool {
+    scope.split_whitespace().any(|s| s == name)
+}
+
+/// Response of the introspection endpoint (RFC 7662), only `active` is set for tokens that
+/// are unknown, expired or revoked
+#[derive(Debug, Default, Serialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    // the email of the user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // the oauth client the token was issued to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+}
+
+/// Either an access token (a JWT) or a refresh token, the hint is not needed to tell them apart
+#[derive(Debug)]
+pub struct IntrospectToken {
+    pub token: String,
+}
+
+#[async_trait]
+impl HandleRequest<IntrospectToken> for DbExecutor {
+    type Result = Result<Introspection, ServiceError>;
+
+    async fn handle(&self, msg: IntrospectToken) -> Self::Result {
+        use crate::schema::{refresh_tokens, service_accounts, sessions, users};
+
+        let claim = Token::decode_token(&msg.token.clone().into()).ok();
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            if let Some((claim, account_id)) = claim
+                .as_ref()
+                .and_then(|claim| Some((claim, claim.get_service_account()?)))
+            {
+                let active = service_accounts::table
+                    .find(account_id)
+                    .filter(service_accounts::disabled_at.is_null())
+                    .select(service_accounts::account_id)
+                    .first::<String>(&conn)
+                    .optional()?
+                    .is_some();
+                if !active {
+                    return Ok(Introspection::default());
+                }
+                return Ok(Introspection {
+                    active,
+                    token_type: Some("access_token".into()),
+                    sub: Some(account_id.into()),
+                    iss: Some(claim.get_issuer().into()),
+                    iat: Some(claim.get_issued_at()),
+                    exp: Some(claim.get_expiry()),
+                    scope: claim.get_scope().map(Into::into),
+                    ..Introspection::default()
+                });
+            }
+            if let Some(claim) = claim {
+                // the session must not be logged out, nor the user disabled since
+                let active = sessions::table
+                    .inner_join(users::table)
+                    .filter(sessions::id.eq(claim.get_session_id()))
+                    .filter(sessions::revoked_at.is_null())
+                    .filter(users::disabled_at.is_null())
+                    .select(sessions::id)
+                    .first::<Uuid>(&conn)
+                    .optional()?
+                    .is_some();
+                if !active {
+                    return Ok(Introspection::default());
+                }
+                // tokens of oauth clients are limited to their scope
+                let roles = match claim.get_client_id() {
+                    Some(_) => None,
+                    None => Some(claim.get_roles().to_vec()),
+                };
+                let introspection = Introspection {
+                    active,
+                    token_type: Some("access_token".into()),
+                    iss: Some(claim.get_issuer().into()),
+                    iat: Some(claim.get_issued_at()),
+                    exp: Some(claim.get_expiry()),
+                    roles,
+                    scope: claim.get_scope().map(Into::into),
+                    client_id: claim.get_client_id().map(Into::into),
+                    aud: claim.get_audience().map(Into::into),
+                    ..Introspection::default()
+                };
+                let email = claim.get_email();
+                return Ok(Introspection {
+                    sub: email.clone(),
+                    email,
+                    ..introspection
+                });
+            }
+
+            let refresh_token: Option<RefreshToken> = refresh_tokens::table
+                .inner_join(users::table)
+                .filter(refresh_tokens::token_hash.eq(hash_token(&msg.token)))
+                .filter(refresh_tokens::used_at.is_null())
+                .filter(refresh_tokens::revoked_at.is_null())
+                .filter(refresh_tokens::expires_at.gt(Local::now().naive_local()))
+                .filter(users::disabled_at.is_null())
+                .select(refresh_tokens::all_columns)
+                .first(&conn)
+                .optional()?;
+            let refresh_token = match refresh_token {
+                Some(refresh_token) => refresh_token,
+                None => return Ok(Introspection::default()),
+            };
+            let (client_id, scope) = sessions::table
+                .find(refresh_token.family_id)
+                .select((sessions::client_id, sessions::scope))
+                .first::<(Option<String>, Option<String>)>(&conn)
+                .optional()?
+                .unwrap_or((None, None));
+            let roles = match client_id {
+                Some(_) => None,
+                None => Some(get_user_roles(&conn, &refresh_token.email)?),
+            };
+            Ok(Introspection {
+                active: true,
+                token_type: Some("refresh_token".into()),
+                iss: Some(issuer()),
+                iat: unix_time(refresh_token.created_at),
+                exp: unix_time(refresh_token.expires_at),
+                roles,
+                email: Some(refresh_token.email.clone()),
+                sub: Some(refresh_token.email),
+                scope,
+                aud: client_id.clone(),
+                client_id,
+            })
+        })
+        .await?
+    }
+}
+
+/// Claims of the userinfo endpoint, the email only if the client was granted the email scope
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+/// The user of an access token, None unless the session is active, the user enabled and the
+/// token was granted the openid scope
+#[derive(Debug)]
+pub struct GetUserInfo {
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<GetUserInfo> for DbExecutor {
+    type Result = Result<Option<UserInfo>, ServiceError>;
+
+    async fn handle(&self, msg: GetUserInfo) -> Self::Result {
+        use crate::schema::{sessions, users};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let found: Option<(User, Option<String>)> = users::table
+                .inner_join(sessions::table)
+                .filter(sessions::id.eq(msg.session_id))
+                .filter(sessions::revoked_at.is_null())
+                .filter(users::disabled_at.is_null())
+                .select((users::all_columns, sessions::scope))
+                .first(&conn)
+                .optional()?;
+            Ok(found.and_then(|(user, session_scope)| {
+                // sessions of logins at this server itself carry no scope
+                let email_scope = match session_scope {
+                    Some(session_scope) if !has_scope(&session_scope, "openid") => return None,
+                    Some(session_scope) => has_scope(&session_scope, "email"),
+                    None => true,
+                };
+                Some(UserInfo {
+                    email: if email_scope {
+                        Some(user.email.clone())
+                    } else {
+                        None
+                    },
+                    email_verified: if email_scope { Some(true) } else { None },
+                    sub: user.email,
+                })
+            }))
+        })
+        .await?
+    }
+}
+
+/// Whether the user already approved all of `scope` for the client
+#[derive(Debug)]
+pub struct HasConsent {
+    pub email: String,
+    pub client_id: String,
+    pub scope: String,
+}
+
+#[async_trait]
+impl HandleRequest<HasConsent> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: HasConsent) -> Self::Result {
+        use crate::schema::oauth_consents::dsl::{oauth_consents, scope};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let consented: Option<String> = oauth_consents
+                .find((&msg.email, &msg.client_id))
+                .select(scope)
+                .first(&conn)
+                .optional()?;
+            Ok(consented.is_some_and(|consented| {
+                msg.scope
+                    .split_whitespace()
+                    .all(|requested| has_scope(&consented, requested))
+            }))
+        })
+        .await?
+    }
+}
+
+/// Remember the approval of `scope` in addition to the scopes approved before
+#[derive(Debug)]
+pub struct GrantConsent {
+    pub email: String,
+    pub client_id: String,
+    pub scope: String,
+}
+
+#[async_trait]
+impl HandleRequest<GrantConsent> for DbExecutor {
+    type Result = Result<(), ServiceError>;
+
+    async fn handle(&self, msg: GrantConsent) -> Self::Result {
+        use crate::schema::oauth_consents::dsl::{oauth_consents, scope};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let existing: Option<OAuthConsent> = oauth_consents
+                    .find((&msg.email, &msg.client_id))
+                    .for_update()
+                    .first(&conn)
+                    .optional()?;
+                match existing {
+                    Some(existing) => {
+                        let mut scopes: Vec<&str> = existing
+                            .scope
+                            .split_whitespace()
+                            .chain(msg.scope.split_whitespace())
+                            .collect();
+                        scopes.sort_unstable();
+                        scopes.dedup();
+                        diesel::update(oauth_consents.find((&msg.email, &msg.client_id)))
+                            .set(scope.eq(scopes.join(" ")))
+                            .execute(&conn)?;
+                    }
+                    None => {
+                        diesel::insert_into(oauth_consents)
+                            .values(&OAuthConsent {
+                                email: msg.email.clone(),
+                                client_id: msg.client_id.clone(),
+                                scope: msg.scope.clone(),
+                                created_at: Local::now().naive_local(),
+                            })
+                            .execute(&conn)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct CreateAuthorizationCode {
+    pub client_id: String,
+    pub email: String,
+    pub redirect_uri: Option<String>,
+    pub scope: String,
+    pub code_challenge: String,
+    pub nonce: Option<String>,
+    pub auth_time: NaiveDateTime,
+    pub auth_methods: Vec<String>,
+}
+
+#[async_trait]
+impl HandleRequest<CreateAuthorizationCode> for DbExecutor {
+    type Result = Result<String, ServiceError>;
+
+    async fn handle(&self, msg: CreateAuthorizationCode) -> Self::Result {
+        use crate::schema::oauth_codes::dsl::oauth_codes;
+
+        let code = get_random_token();
+        let current_time = Local::now().naive_local();
+        let oauth_code = OAuthCode {
+            code_hash: hash_token(&code),
+            client_id: msg.client_id,
+            email: msg.email,
+            redirect_uri: msg.redirect_uri,
+            scope: msg.scope,
+            code_challenge: msg.code_challenge,
+            created_at: current_time,
+            expires_at: current_time + Duration::minutes(AUTHORIZATION_CODE_MINUTES),
+            nonce: msg.nonce,
+            auth_time: msg.auth_time,
+            auth_methods: msg.auth_methods,
+        };
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::insert_into(oauth_codes)
+                .values(&oauth_code)
+                .execute(&conn)
+                .map(|_| code)
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+/// Exchange an authorization code, it is removed whether or not the exchange succeeds
+#[derive(Debug)]
+pub struct RedeemAuthorizationCode {
+    pub code: String,
+    pub client_id: String,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: String,
+}
+
+#[async_trait]
+impl HandleRequest<RedeemAuthorizationCode> for DbExecutor {
+    type Result = Result<Option<OAuthCode>, ServiceError>;
+
+    async fn handle(&self, msg: RedeemAuthorizationCode) -> Self::Result {
+        use crate::schema::oauth_codes::dsl::oauth_codes;
+
+        let code_hash = hash_token(&msg.code);
+        let dbex = self.clone();
+        let oauth_code: Option<OAuthCode> = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::delete(oauth_codes.find(code_hash))
+                .get_results(&conn)
+                .map(|mut codes: Vec<OAuthCode>| codes.pop())
+                .map_err(ServiceError::from)
+        })
+        .await??;
+        let challenge = pkce_challenge(&msg.code_verifier);
+        Ok(oauth_code.filter(|code| {
+            code.expires_at > Local::now().naive_local()
+                && code.client_id == msg.client_id
+                // only required if the authorization request had it (RFC 6749 section 4.1.3)
+                && code
+                    .redirect_uri
+                    .as_ref()
+                    .is_none_or(|redirect_uri| msg.redirect_uri.as_ref() == Some(redirect_uri))
+                && verify_slices_are_equal(code.code_challenge.as_bytes(), challenge.as_bytes())
+                    .is_ok()
+        }))
+    }
+}
+
+// time the user has to enter the user code
+const DEVICE_CODE_MINUTES: i64 = 10;
+
+/// Seconds a device has to wait between polls of the token endpoint, to start with
+pub const DEVICE_POLL_INTERVAL: i32 = 5;
+
+// RFC 8628 section 6.1, without vowels so that codes cannot spell words
+const USER_CODE_CHARS: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+fn get_user_code() -> String {
+    let mut rng = thread_rng();
+    (0..USER_CODE_LENGTH)
+        .map(|_| USER_CODE_CHARS[rng.gen_range(0, USER_CODE_CHARS.len())] as char)
+        .collect()
+}
+
+/// The user code as stored, users may type it in lower case or with the dash it is shown with
+pub fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(char::is_ascii_alphabetic)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Shown to the user as `BCDF-GHJK`
+pub fn format_user_code(user_code: &str) -> String {
+    let (first, second) = user_code.split_at(user_code.len() / 2);
+    format!("{}-{}", first, second)
+}
+
+#[derive(Debug)]
+pub struct CreateDeviceCode {
+    pub client_id: String,
+    pub scope: String,
+}
+
+#[derive(Debug)]
+pub struct NewDeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[async_trait]
+impl HandleRequest<CreateDeviceCode> for DbExecutor {
+    type Result = Result<NewDeviceCode, ServiceError>;
+
+    async fn handle(&self, msg: CreateDeviceCode) -> Self::Result {
+        use crate::schema::oauth_device_codes::dsl::oauth_device_codes;
+
+        let device_code = get_random_token();
+        let current_time = Local::now().naive_local();
+        let device = OAuthDeviceCode {
+            device_code_hash: hash_token(&device_code),
+            user_code: get_user_code(),
+            client_id: msg.client_id,
+            scope: msg.scope,
+            poll_interval: DEVICE_POLL_INTERVAL,
+            last_polled_at: None,
+            email: None,
+            auth_time: None,
+            auth_methods: Vec::new(),
+            denied: false,
+            created_at: current_time,
+            expires_at: current_time + Duration::minutes(DEVICE_CODE_MINUTES),
+        };
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::insert_into(oauth_device_codes)
+                .values(&device)
+                .execute(&conn)?;
+            Ok(NewDeviceCode {
+                device_code,
+                user_code: device.user_code,
+                expires_at: device.expires_at,
+            })
+        })
+        .await?
+    }
+}
+
+/// A device authorization still waiting for the user, by its user code
+#[derive(Debug)]
+pub struct GetDeviceCode {
+    pub user_code: String,
+}
+
+#[async_trait]
+impl HandleRequest<GetDeviceCode> for DbExecutor {
+    type Result = Result<Option<OAuthDeviceCode>, ServiceError>;
+
+    async fn handle(&self, msg: GetDeviceCode) -> Self::Result {
+        use crate::schema::oauth_device_codes::dsl::{
+            denied, email, expires_at, oauth_device_codes, user_code,
+        };
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            oauth_device_codes
+                .filter(user_code.eq(normalize_user_code(&msg.user_code)))
+                .filter(email.is_null())
+                .filter(denied.eq(false))
+                .filter(expires_at.gt(Local::now().naive_local()))
+                .first(&conn)
+                .optional()
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+/// The user's answer to a device authorization, false if the code is unknown or was answered
+#[derive(Debug)]
+pub struct ApproveDeviceCode {
+    pub user_code: String,
+    pub approve: bool,
+    pub email: String,
+    pub auth_time: NaiveDateTime,
+    pub auth_methods: Vec<String>,
+}
+
+#[async_trait]
+impl HandleRequest<ApproveDeviceCode> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: ApproveDeviceCode) -> Self::Result {
+        use crate::schema::oauth_device_codes::dsl::{
+            auth_methods, auth_time, denied, email, expires_at, oauth_device_codes, user_code,
+        };
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let pending = oauth_device_codes
+                .filter(user_code.eq(normalize_user_code(&msg.user_code)))
+                .filter(email.is_null())
+                .filter(denied.eq(false))
+                .filter(expires_at.gt(Local::now().naive_local()));
+            let updated = if msg.approve {
+                diesel::update(pending)
+                    .set((
+                        email.eq(&msg.email),
+                        auth_time.eq(msg.auth_time),
+                        auth_methods.eq(&msg.auth_methods),
+                    ))
+                    .execute(&conn)?
+            } else {
+                diesel::update(pending)
+                    .set(denied.eq(true))
+                    .execute(&conn)?
+            };
+            Ok(updated > 0)
+        })
+        .await?
+    }
+}
+
+/// Answer to a device polling the token endpoint (RFC 8628 section 3.5)
+#[derive(Debug)]
+pub enum DevicePoll {
+    Pending,
+    SlowDown,
+    Denied,
+    Expired,
+    // an approved device code is redeemed like an authorization code, one without redirect
+    // uri and PKCE challenge
+    Approved(Box<OAuthCode>),
+}
+
+/// None for an unknown device code or one issued to another client
+#[derive(Debug)]
+pub struct PollDeviceCode {
+    pub device_code: String,
+    pub client_id: String,
+}
+
+#[async_trait]
+impl HandleRequest<PollDeviceCode> for DbExecutor {
+    type Result = Result<Option<DevicePoll>, ServiceError>;
+
+    async fn handle(&self, msg: PollDeviceCode) -> Self::Result {
+        use crate::schema::oauth_device_codes::dsl::{
+            last_polled_at, oauth_device_codes, poll_interval,
+        };
+
+        let device_code_hash = hash_token(&msg.device_code);
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let current_time = Local::now().naive_local();
+                let device = match oauth_device_codes
+                    .find(&device_code_hash)
+                    .for_update()
+                    .first::<OAuthDeviceCode>(&conn)
+                    .optional()?
+                {
+                    Some(device) if device.client_id == msg.client_id => device,
+                    _ => return Ok(None),
+                };
+                let poll = if device.expires_at < current_time {
+                    DevicePoll::Expired
+                } else if device.denied {
+                    DevicePoll::Denied
+                } else if let (Some(email), Some(auth_time)) = (&device.email, device.auth_time) {
+                    DevicePoll::Approved(Box::new(OAuthCode {
+                        code_hash: device.device_code_hash,
+                        client_id: device.client_id,
+                        email: email.clone(),
+                        redirect_uri: None,
+                        scope: device.scope,
+                        code_challenge: String::new(),
+                        created_at: device.created_at,
+                        expires_at: device.expires_at,
+                        nonce: None,
+                        auth_time,
+                        auth_methods: device.auth_methods,
+                    }))
+                } else {
+                    let too_soon = device.last_polled_at.is_some_and(|last_polled| {
+                        current_time - last_polled < Duration::seconds(device.poll_interval.into())
+                    });
+                    // the device has to wait longer from now on
+                    let interval = if too_soon {
+                        device.poll_interval + DEVICE_POLL_INTERVAL
+                    } else {
+                        device.poll_interval
+                    };
+                    diesel::update(oauth_device_codes.find(&device_code_hash))
+                        .set((last_polled_at.eq(current_time), poll_interval.eq(interval)))
+                        .execute(&conn)?;
+                    return Ok(Some(if too_soon {
+                        DevicePoll::SlowDown
+                    } else {
+                        DevicePoll::Pending
+                    }));
+                };
+                // answered or expired codes are answered only once
+                diesel::delete(oauth_device_codes.find(&device_code_hash)).execute(&conn)?;
+                Ok(Some(poll))
+            })
+        })
+        .await?
+    }
+}
+
+pub fn cleanup_oauth_codes(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    use crate::schema::{oauth_codes, oauth_device_codes};
+
+    let conn = pool.0.get()?;
+    let current_time = Local::now().naive_local();
+    diesel::delete(oauth_codes::table.filter(oauth_codes::expires_at.lt(current_time)))
+        .execute(&conn)?;
+    diesel::delete(
+        oauth_device_codes::table.filter(oauth_device_codes::expires_at.lt(current_time)),
+    )
+    .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use chrono::{Duration, Local};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    use crate::{
+        models::{DbExecutor, HandleRequest, User},
+        oauth_client_handler::CreateOAuthClient,
+        oauth_handler::{
+            format_user_code, get_user_code, has_scope, normalize_user_code, parse_scope,
+            supported_scopes, ApproveDeviceCode, CreateDeviceCode, DevicePoll, NewDeviceCode,
+            PollDeviceCode, DEVICE_POLL_INTERVAL, SUPPORTED_SCOPES,
+        },
+        rust_auth_server::{get_pool, load_config},
+        utils::{get_random_string, hash_token},
+    };
+
+    async fn create_device_code(
+        pool: &DbExecutor,
+        client_id: &str,
+    ) -> Result<NewDeviceCode, Error> {
+        let msg = CreateDeviceCode {
+            client_id: client_id.into(),
+            scope: "openid".into(),
+        };
+        pool.handle(msg).await.map_err(Into::into)
+    }
+
+    async fn poll(
+        pool: &DbExecutor,
+        device: &NewDeviceCode,
+        client_id: &str,
+    ) -> Option<DevicePoll> {
+        let msg = PollDeviceCode {
+            device_code: device.device_code.clone(),
+            client_id: client_id.into(),
+        };
+        pool.handle(msg).await.unwrap()
+    }
+
+    async fn answer(pool: &DbExecutor, device: &NewDeviceCode, email: &str, approve: bool) -> bool {
+        let msg = ApproveDeviceCode {
+            user_code: format_user_code(&device.user_code),
+            approve,
+            email: email.into(),
+            auth_time: Local::now().naive_local(),
+            auth_methods: vec!["pwd".into()],
+        };
+        pool.handle(msg).await.unwrap()
+    }
+
+    #[test]
+    fn test_parse_scope() {
+        assert_eq!(
+            parse_scope("openid email openid offline_access", SUPPORTED_SCOPES),
+            Some(vec!["email", "offline_access", "openid"])
+        );
+        assert_eq!(parse_scope("", SUPPORTED_SCOPES), Some(vec![]));
+        assert_eq!(parse_scope("openid admin", SUPPORTED_SCOPES), None);
+        // the default key is a shared secret, ID tokens could not be verified by clients
+        assert!(!supported_scopes().contains(&"openid"));
+        assert_eq!(parse_scope("openid email", &supported_scopes()), None);
+        assert!(has_scope("email openid", "openid"));
+        assert!(!has_scope("email openid", "open"));
+    }
+
+    #[test]
+    fn test_user_code() {
+        let user_code = get_user_code();
+        assert_eq!(user_code.len(), 8);
+        assert!(user_code
+            .chars()
+            .all(|c| "BCDFGHJKLMNPQRSTVWXZ".contains(c)));
+        assert_eq!(format_user_code("BCDFGHJK"), "BCDF-GHJK");
+        assert_eq!(normalize_user_code(" bcdf-ghjk "), "BCDFGHJK");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_device_code_polling() -> Result<(), Error> {
+        use crate::schema::{oauth_clients, oauth_device_codes, users};
+
+        load_config();
+        let pool = get_pool();
+        let conn = pool.0.get()?;
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        diesel::insert_into(users::table)
+            .values(&User::from_details(email.clone(), String::new()))
+            .execute(&conn)?;
+        let mut client_ids = Vec::new();
+        for _ in 0..2 {
+            let msg = CreateOAuthClient {
+                name: "test device".into(),
+                redirect_uris: Vec::new(),
+                public_client: true,
+            };
+            client_ids.push(pool.handle(msg).await?.client.client_id);
+        }
+        let (client_id, other_client_id) = (&client_ids[0], &client_ids[1]);
+
+        // the device keeps polling until the user answers, too quick polls slow it down
+        let approved = create_device_code(&pool, client_id).await?;
+        let first_poll = poll(&pool, &approved, client_id).await;
+        let quick_poll = poll(&pool, &approved, client_id).await;
+        let interval: i32 = oauth_device_codes::table
+            .find(hash_token(&approved.device_code))
+            .select(oauth_device_codes::poll_interval)
+            .first(&conn)?;
+        let other_client_poll = poll(&pool, &approved, other_client_id).await;
+        let approve = answer(&pool, &approved, &email, true).await;
+        let approved_poll = poll(&pool, &approved, client_id).await;
+        let redeemed_poll = poll(&pool, &approved, client_id).await;
+
+        // an answered code can't be answered again
+        let denied = create_device_code(&pool, client_id).await?;
+        let deny = answer(&pool, &denied, &email, false).await;
+        let approve_denied = answer(&pool, &denied, &email, true).await;
+        let denied_poll = poll(&pool, &denied, client_id).await;
+
+        let expired = create_device_code(&pool, client_id).await?;
+        diesel::update(oauth_device_codes::table.find(hash_token(&expired.device_code)))
+            .set(
+                oauth_device_codes::expires_at
+                    .eq(Local::now().naive_local() - Duration::minutes(1)),
+            )
+            .execute(&conn)?;
+        let approve_expired = answer(&pool, &expired, &email, true).await;
+        let expired_poll = poll(&pool, &expired, client_id).await;
+        let expired_again_poll = poll(&pool, &expired, client_id).await;
+
+        diesel::delete(users::table.filter(users::email.eq(&email))).execute(&conn)?;
+        diesel::delete(oauth_clients::table.filter(oauth_clients::client_id.eq_any(&client_ids)))
+            .execute(&conn)?;
+
+        assert!(matches!(first_poll, Some(DevicePoll::Pending)));
+        assert!(matches!(quick_poll, Some(DevicePoll::SlowDown)));
+        assert_eq!(interval, 2 * DEVICE_POLL_INTERVAL);
+        assert!(other_client_poll.is_none());
+        assert!(approve);
+        match approved_poll {
+            Some(DevicePoll::Approved(oauth_code)) => {
+                assert_eq!(oauth_code.email, email);
+                assert_eq!(&oauth_code.client_id, client_id);
+                assert_eq!(oauth_code.redirect_uri, None);
+            }
+            poll => panic!("expected an approved device code, got {:?}", poll),
+        }
+        assert!(redeemed_poll.is_none());
+
+        assert!(deny);
+        assert!(!approve_denied);
+        assert!(matches!(denied_poll, Some(DevicePoll::Denied)));
+
+        assert!(!approve_expired);
+        assert!(matches!(expired_poll, Some(DevicePoll::Expired)));
+        assert!(expired_again_poll.is_none());
+        Ok(())
+    }
+}
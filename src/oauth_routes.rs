@@ -0,0 +1,1233 @@
+use actix_web::{
+    http::header::{AUTHORIZATION, CACHE_CONTROL, LOCATION, USER_AGENT, WWW_AUTHENTICATE},
+    web::{Data, Form, Json, Path, Query},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use base64::decode;
+use chrono::{Local, NaiveDateTime};
+use jsonwebtoken::Algorithm;
+use maplit::hashmap;
+use serde::{Deserialize, Serialize};
+use std::env::var;
+use url::{form_urlencoded, Url};
+
+use crate::{
+    errors::ServiceError,
+    forward_auth_routes::login_url,
+    jwt_keys::id_token_signing_algorithm,
+    logged_user::{bearer_token, LoggedUser, SessionUser},
+    models::{DbExecutor, HandleRequest, OAuthClient, OAuthCode, Session, SlimUser},
+    oauth_client_handler::{AuthenticateClient, GetOAuthClient},
+    oauth_handler::{
+        format_user_code, has_scope, parse_scope, supported_scopes, ApproveDeviceCode,
+        CreateAuthorizationCode, CreateDeviceCode, DevicePoll, GetDeviceCode, GetUserInfo,
+        GrantConsent, HasConsent, IntrospectToken, PollDeviceCode, RedeemAuthorizationCode,
+        DEVICE_POLL_INTERVAL,
+    },
+    openid_providers::ProviderRegistry,
+    refresh_token_handler::{
+        GetRefreshTokenSession, IssueRefreshToken, RevokeRefreshToken, RotateRefreshToken,
+    },
+    service_account_handler::{grant_scope, AuthenticateServiceAccount},
+    session_handler::{CreateSession, GetSession, RevokeSession},
+    utils::{access_token_lifetime, issuer, unix_time, IdClaim, Token},
+};
+
+// the client id and secret are form encoded before they are joined for basic auth
+fn form_decode(value: &str) -> String {
+    form_urlencoded::parse(value.as_bytes())
+        .next()
+        .map(|(value, _)| value.into_owned())
+        .unwrap_or_default()
+}
+
+/// Client credentials from an `Authorization: Basic` header (client_secret_basic)
+fn basic_credentials(request: &HttpRequest) -> Option<(String, String)> {
+    let header = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(decode(encoded.trim()).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((form_decode(client_id), form_decode(client_secret)))
+}
+
+// the parameters of introspection and revocation requests, the credentials are only used
+// without basic auth (client_secret_post), a token_type_hint is not needed
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub token: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+pub async fn authenticate_client(
+    request: &HttpRequest,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    db: &DbExecutor,
+) -> Result<Option<OAuthClient>, ServiceError> {
+    let credentials = match (basic_credentials(request), client_id, client_secret) {
+        (Some(credentials), _, _) => credentials,
+        (None, Some(client_id), Some(client_secret)) => (client_id, client_secret),
+        _ => return Ok(None),
+    };
+    let msg = AuthenticateClient {
+        client_id: credentials.0,
+        client_secret: credentials.1,
+    };
+    db.handle(msg).await
+}
+
+pub fn invalid_client() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header(WWW_AUTHENTICATE, r#"Basic realm="oauth""#)
+        .json(hashmap! {"error" => "invalid_client"})
+}
+
+/// Token introspection (RFC 7662) for resource servers holding client credentials
+pub async fn introspect(
+    request: HttpRequest,
+    form: Form<TokenRequest>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let TokenRequest {
+        token,
+        client_id,
+        client_secret,
+    } = form.into_inner();
+    let result: Result<_, ServiceError> = async {
+        if authenticate_client(&request, client_id, client_secret, &db)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        db.handle(IntrospectToken { token }).await.map(Some)
+    }
+    .await;
+    match result {
+        Ok(Some(introspection)) => Ok(HttpResponse::Ok().json(introspection)),
+        Ok(None) => Ok(invalid_client()),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+/// Token revocation (RFC 7009), an access token logs out its session, a refresh token its
+/// whole family. Unknown tokens and tokens issued to other clients are not an error, they are
+/// left alone. Service account tokens cannot be revoked, they expire with their lifetime.
+pub async fn revoke(
+    request: HttpRequest,
+    form: Form<TokenRequest>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let TokenRequest {
+        token,
+        client_id,
+        client_secret,
+    } = form.into_inner();
+    let result: Result<_, ServiceError> = async {
+        let client = match authenticate_client(&request, client_id, client_secret, &db).await? {
+            Some(client) => client,
+            None => return Ok(invalid_client()),
+        };
+        // only the client a token was issued to may revoke it (RFC 7009 section 2.1)
+        let is_client_session =
+            |session: &Session| session.client_id.as_deref() == Some(client.client_id.as_str());
+        match Token::decode_token(&token.clone().into()) {
+            Ok(claim) if claim.get_service_account().is_some() => {
+                return Ok(token_error("unsupported_token_type"));
+            }
+            Ok(claim) => {
+                let msg = GetSession {
+                    session_id: claim.get_session_id(),
+                };
+                if let Some(session) = db.handle(msg).await?.filter(is_client_session) {
+                    let msg = RevokeSession {
+                        session_id: session.id,
+                        email: session.email,
+                    };
+                    db.handle(msg).await?;
+                }
+            }
+            Err(_) => {
+                let msg = GetRefreshTokenSession {
+                    refresh_token: token.clone(),
+                };
+                if db.handle(msg).await?.filter(is_client_session).is_some() {
+                    let msg = RevokeRefreshToken {
+                        refresh_token: token,
+                    };
+                    db.handle(msg).await?;
+                }
+            }
+        }
+        Ok(HttpResponse::Ok().finish())
+    }
+    .await;
+    match result {
+        Ok(response) => Ok(response),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizeQuery {
+    pub response_type: String,
+    pub client_id: String,
+    // may be left out if the client registered a single redirect uri
+    pub redirect_uri: Option<String>,
+    #[serde(default)]
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    // OpenID Connect, repeated in the id token
+    pub nonce: Option<String>,
+}
+
+// an authorization request that passed validation
+struct Authorization {
+    client: OAuthClient,
+    redirect_uri: Url,
+    // the redirect_uri parameter as sent, None if the registered one was used
+    requested_redirect_uri: Option<String>,
+    scope: String,
+    code_challenge: String,
+    state: Option<String>,
+    nonce: Option<String>,
+}
+
+enum AuthorizeError {
+    // unknown client or redirect uri, the user must not be sent anywhere
+    Invalid(ServiceError),
+    // anything else is reported to the client on its redirect uri
+    Redirect(Url),
+}
+
+impl From<ServiceError> for AuthorizeError {
+    fn from(error: ServiceError) -> Self {
+        Self::Invalid(error)
+    }
+}
+
+impl AuthorizeError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::Invalid(service_error) => service_error.error_response(),
+            Self::Redirect(url) => HttpResponse::Found()
+                .header(LOCATION, url.as_str())
+                .finish(),
+        }
+    }
+}
+
+// the client's redirect uri with the authorization response appended
+fn authorization_response(redirect_uri: &Url, params: &[(&str, &str)], state: Option<&str>) -> Url {
+    let mut url = redirect_uri.clone();
+    {
+        let mut query = url.query_pairs_mut();
+        query.extend_pairs(params);
+        if let Some(state) = state {
+            query.append_pair("state", state);
+        }
+    }
+    url
+}
+
+async fn check_authorization(
+    query: AuthorizeQuery,
+    db: &DbExecutor,
+) -> Result<Authorization, AuthorizeError> {
+    let client = db
+        .handle(GetOAuthClient {
+            client_id: query.client_id,
+        })
+        .await?
+        .ok_or_else(|| ServiceError::BadRequest("Unknown client".into()))?;
+    // redirect uris are compared exactly, no prefix or wildcard matching
+    let requested_redirect_uri = query.redirect_uri;
+    let redirect_uri = match (&requested_redirect_uri, client.redirect_uris.as_slice()) {
+        (Some(redirect_uri), registered) if registered.contains(redirect_uri) => Some(redirect_uri),
+        (None, [registered]) => Some(registered),
+        _ => None,
+    }
+    .and_then(|redirect_uri| redirect_uri.parse::<Url>().ok())
+    .ok_or_else(|| ServiceError::BadRequest("Invalid redirect_uri".into()))?;
+
+    let state = query.state;
+    let error = |error: &str| {
+        AuthorizeError::Redirect(authorization_response(
+            &redirect_uri,
+            &[("error", error)],
+            state.as_deref(),
+        ))
+    };
+    if query.response_type != "code" {
+        return Err(error("unsupported_response_type"));
+    }
+    // PKCE is required from every client, confidential ones included
+    let code_challenge = match (query.code_challenge, query.code_challenge_method.as_deref()) {
+        (Some(code_challenge), Some("S256")) if code_challenge.len() == 43 => code_challenge,
+        _ => return Err(error("invalid_request")),
+    };
+    let scope = match parse_scope(&query.scope, &supported_scopes()) {
+        Some(scopes) => scopes.join(" "),
+        None => return Err(error("invalid_scope")),
+    };
+    Ok(Authorization {
+        client,
+        redirect_uri,
+        requested_redirect_uri,
+        scope,
+        code_challenge,
+        state,
+        nonce: query.nonce,
+    })
+}
+
+// when and how the user logged in, for the claims of the ID token
+async fn session_auth(
+    user: &SessionUser,
+    db: &DbExecutor,
+) -> Result<(NaiveDateTime, Vec<String>), ServiceError> {
+    let msg = GetSession {
+        session_id: user.session,
+    };
+    match db.handle(msg).await? {
+        Some(session) => Ok((session.created_at, session.auth_methods)),
+        None => Err(ServiceError::Unauthorized),
+    }
+}
+
+// issue an authorization code and send the user back to the client with it
+async fn grant_code(
+    authorization: Authorization,
+    user: &SessionUser,
+    db: &DbExecutor,
+) -> Result<Url, ServiceError> {
+    let (auth_time, auth_methods) = session_auth(user, db).await?;
+    let code = db
+        .handle(CreateAuthorizationCode {
+            client_id: authorization.client.client_id,
+            email: user.email.clone(),
+            redirect_uri: authorization.requested_redirect_uri,
+            scope: authorization.scope,
+            code_challenge: authorization.code_challenge,
+            nonce: authorization.nonce,
+            auth_time,
+            auth_methods,
+        })
+        .await?;
+    Ok(authorization_response(
+        &authorization.redirect_uri,
+        &[("code", &code)],
+        authorization.state.as_deref(),
+    ))
+}
+
+/// The authorization endpoint (RFC 6749 section 4.1) for the authorization code flow with
+/// PKCE. Anonymous users log in on the login page first, a personal access token doesn't
+/// count as a login. Clients the user has not approved yet for the requested scopes go
+/// through the consent page.
+pub async fn authorize(
+    user: Option<SessionUser>,
+    query: Query<AuthorizeQuery>,
+    request: HttpRequest,
+    db: Data<DbExecutor>,
+    providers: Data<ProviderRegistry>,
+) -> Result<HttpResponse, Error> {
+    let result: Result<_, AuthorizeError> = async {
+        let authorization = check_authorization(query.into_inner(), &db).await?;
+        let user = match user {
+            Some(user) => user,
+            None => {
+                let domain = var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+                let original_url = format!("https://{}{}", domain, request.uri()).parse().ok();
+                return Ok(login_url(original_url, &providers));
+            }
+        };
+        let msg = HasConsent {
+            email: user.email.clone(),
+            client_id: authorization.client.client_id.clone(),
+            scope: authorization.scope.clone(),
+        };
+        if db.handle(msg).await? {
+            let url = grant_code(authorization, &user, &db).await?;
+            Ok(url.into())
+        } else {
+            Ok(format!("/auth/consent.html?{}", request.query_string()))
+        }
+    }
+    .await;
+    match result {
+        Ok(location) => Ok(HttpResponse::Found().header(LOCATION, location).finish()),
+        Err(authorize_error) => Ok(authorize_error.error_response()),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConsentData {
+    #[serde(flatten)]
+    pub request: AuthorizeQuery,
+    pub approve: bool,
+}
+
+/// Called by the consent page with the original authorization request, responds with the
+/// url to send the user to
+pub async fn consent(
+    user: SessionUser,
+    data: Json<ConsentData>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let ConsentData { request, approve } = data.into_inner();
+    let result: Result<Url, AuthorizeError> = async {
+        let authorization = check_authorization(request, &db).await?;
+        if !approve {
+            return Ok(authorization_response(
+                &authorization.redirect_uri,
+                &[("error", "access_denied")],
+                authorization.state.as_deref(),
+            ));
+        }
+        let msg = GrantConsent {
+            email: user.email.clone(),
+            client_id: authorization.client.client_id.clone(),
+            scope: authorization.scope.clone(),
+        };
+        db.handle(msg).await?;
+        grant_code(authorization, &user, &db)
+            .await
+            .map_err(Into::into)
+    }
+    .await;
+    match result {
+        Ok(url) | Err(AuthorizeError::Redirect(url)) => {
+            Ok(HttpResponse::Ok().json(hashmap! {"redirect_uri" => String::from(url)}))
+        }
+        Err(AuthorizeError::Invalid(service_error)) => Ok(service_error.error_response()),
+    }
+}
+
+/// The name of a client for the consent page
+pub async fn get_client(
+    _: LoggedUser,
+    client_id: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = GetOAuthClient {
+        client_id: client_id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(Some(client)) => Ok(HttpResponse::Ok().json(hashmap! {
+            "client_id" => client.client_id,
+            "name" => client.name,
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenGrant {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub device_code: Option<String>,
+    // requested scopes of the client credentials grant
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+impl TokenResponse {
+    fn new(token: Token, refresh_token: Option<String>) -> Self {
+        Self {
+            access_token: token.into(),
+            token_type: "Bearer",
+            expires_in: access_token_lifetime().num_seconds(),
+            refresh_token,
+            scope: None,
+            id_token: None,
+        }
+    }
+}
+
+fn token_error(error: &str) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .header(CACHE_CONTROL, "no-store")
+        .json(hashmap! {"error" => error})
+}
+
+// confidential clients authenticate with their secret, public clients only send their id
+async fn token_client(
+    request: &HttpRequest,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    db: &DbExecutor,
+) -> Result<Option<OAuthClient>, ServiceError> {
+    if basic_credentials(request).is_some() || client_secret.is_some() {
+        return authenticate_client(request, client_id, client_secret, db).await;
+    }
+    let client_id = match client_id {
+        Some(client_id) => client_id,
+        None => return Ok(None),
+    };
+    let client = db.handle(GetOAuthClient { client_id }).await?;
+    Ok(client.filter(|client| client.public_client))
+}
+
+// exchange an authorization code for a session of the client
+async fn authorization_code_grant(
+    client: OAuthClient,
+    grant: TokenGrant,
+    request: &HttpRequest,
+    db: &DbExecutor,
+) -> Result<Option<TokenResponse>, ServiceError> {
+    let (code, code_verifier) = match (grant.code, grant.code_verifier) {
+        (Some(code), Some(code_verifier)) => (code, code_verifier),
+        _ => return Ok(None),
+    };
+    let msg = RedeemAuthorizationCode {
+        code,
+        client_id: client.client_id.clone(),
+        redirect_uri: grant.redirect_uri,
+        code_verifier,
+    };
+    match db.handle(msg).await? {
+        Some(oauth_code) => issue_tokens(&client, oauth_code, request, db)
+            .await
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+// start a session of the client for a redeemed authorization or device code
+async fn issue_tokens(
+    client: &OAuthClient,
+    oauth_code: OAuthCode,
+    request: &HttpRequest,
+    db: &DbExecutor,
+) -> Result<TokenResponse, ServiceError> {
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(ToString::to_string);
+    let session = db
+        .handle(CreateSession {
+            email: oauth_code.email.clone(),
+            user_agent,
+            ip_address: None,
+            client_id: Some(client.client_id.clone()),
+            auth_methods: oauth_code.auth_methods.clone(),
+            scope: Some(oauth_code.scope.clone()),
+        })
+        .await?;
+    let user = SlimUser {
+        email: oauth_code.email.clone(),
+    };
+    // the client gets the granted scope, not the roles of the user
+    let token = Token::create_client_token(
+        &user,
+        session.id,
+        &client.client_id,
+        oauth_code.scope.clone(),
+    )?;
+    let refresh_token = if has_scope(&oauth_code.scope, "offline_access") {
+        let msg = IssueRefreshToken {
+            email: oauth_code.email.clone(),
+            session_id: session.id,
+        };
+        Some(db.handle(msg).await?)
+    } else {
+        None
+    };
+    let id_token = if has_scope(&oauth_code.scope, "openid") {
+        Some(IdClaim::from_code(&oauth_code).encode()?)
+    } else {
+        None
+    };
+    Ok(TokenResponse {
+        scope: Some(oauth_code.scope),
+        id_token,
+        ..TokenResponse::new(token, refresh_token)
+    })
+}
+
+// a device polling for the user's answer to its device authorization, the error code tells
+// the device whether to keep polling
+async fn device_code_grant(
+    client: OAuthClient,
+    grant: TokenGrant,
+    request: &HttpRequest,
+    db: &DbExecutor,
+) -> Result<Result<TokenResponse, &'static str>, ServiceError> {
+    let device_code = match grant.device_code {
+        Some(device_code) => device_code,
+        None => return Ok(Err("invalid_request")),
+    };
+    let msg = PollDeviceCode {
+        device_code,
+        client_id: client.client_id.clone(),
+    };
+    let error = match db.handle(msg).await? {
+        Some(DevicePoll::Approved(oauth_code)) => {
+            return Ok(Ok(issue_tokens(&client, *oauth_code, request, db).await?));
+        }
+        Some(DevicePoll::Pending) => "authorization_pending",
+        Some(DevicePoll::SlowDown) => "slow_down",
+        Some(DevicePoll::Denied) => "access_denied",
+        Some(DevicePoll::Expired) => "expired_token",
+        None => "invalid_grant",
+    };
+    Ok(Err(error))
+}
+
+// a token for a service account, the client id and secret are the account's credentials
+async fn client_credentials_grant(
+    request: &HttpRequest,
+    grant: TokenGrant,
+    db: &DbExecutor,
+) -> Result<HttpResponse, ServiceError> {
+    let credentials = match (
+        basic_credentials(request),
+        grant.client_id,
+        grant.client_secret,
+    ) {
+        (Some(credentials), _, _) => credentials,
+        (None, Some(client_id), Some(client_secret)) => (client_id, client_secret),
+        _ => return Ok(invalid_client()),
+    };
+    let msg = AuthenticateServiceAccount {
+        account_id: credentials.0,
+        client_secret: credentials.1,
+    };
+    let account = match db.handle(msg).await? {
+        Some(account) => account,
+        None => return Ok(invalid_client()),
+    };
+    let scope = match grant_scope(grant.scope.as_deref(), &account.scopes) {
+        Some(scope) => scope,
+        None => return Ok(token_error("invalid_scope")),
+    };
+    let token = Token::create_service_token(&account.account_id, scope.clone())?;
+    let response = TokenResponse {
+        scope: Some(scope),
+        ..TokenResponse::new(token, None)
+    };
+    Ok(HttpResponse::Ok()
+        .header(CACHE_CONTROL, "no-store")
+        .json(response))
+}
+
+/// The token endpoint for the authorization_code, refresh_token and device_code grants of
+/// oauth clients and the client_credentials grant of service accounts
+pub async fn token(
+    request: HttpRequest,
+    form: Form<TokenGrant>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let mut grant = form.into_inner();
+    if grant.grant_type == "client_credentials" {
+        return match client_credentials_grant(&request, grant, &db).await {
+            Ok(response) => Ok(response),
+            Err(service_error) => Ok(service_error.error_response()),
+        };
+    }
+    let client = match token_client(
+        &request,
+        grant.client_id.take(),
+        grant.client_secret.take(),
+        &db,
+    )
+    .await
+    {
+        Ok(Some(client)) => client,
+        Ok(None) => return Ok(invalid_client()),
+        Err(service_error) => return Ok(service_error.error_response()),
+    };
+    let result = match grant.grant_type.as_str() {
+        "authorization_code" => authorization_code_grant(client, grant, &request, &db)
+            .await
+            .map(|response| response.ok_or("invalid_grant")),
+        DEVICE_CODE_GRANT => device_code_grant(client, grant, &request, &db).await,
+        "refresh_token" => match grant.refresh_token {
+            Some(refresh_token) => {
+                let msg = RotateRefreshToken {
+                    refresh_token,
+                    client_id: Some(client.client_id),
+                };
+                match db.handle(msg).await {
+                    Ok((_, token, refresh_token)) => {
+                        Ok(Ok(TokenResponse::new(token, Some(refresh_token))))
+                    }
+                    Err(ServiceError::BadRequest(_)) => Ok(Err("invalid_grant")),
+                    Err(service_error) => Err(service_error),
+                }
+            }
+            None => Ok(Err("invalid_request")),
+        },
+        _ => return Ok(token_error("unsupported_grant_type")),
+    };
+    match result {
+        Ok(Ok(response)) => Ok(HttpResponse::Ok()
+            .header(CACHE_CONTROL, "no-store")
+            .json(response)),
+        Ok(Err(error)) => Ok(token_error(error)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceAuthorizationRequest {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: i32,
+}
+
+/// The device authorization endpoint (RFC 8628 section 3.1) for devices without a browser,
+/// the user enters the user code on `/auth/device.html` while the device polls the token
+/// endpoint with the device code
+pub async fn device_authorization(
+    request: HttpRequest,
+    form: Form<DeviceAuthorizationRequest>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let form = form.into_inner();
+    let result: Result<_, ServiceError> = async {
+        let client = match token_client(&request, form.client_id, form.client_secret, &db).await? {
+            Some(client) => client,
+            None => return Ok(invalid_client()),
+        };
+        let scope = match parse_scope(form.scope.as_deref().unwrap_or(""), &supported_scopes()) {
+            Some(scopes) => scopes.join(" "),
+            None => return Ok(token_error("invalid_scope")),
+        };
+        let msg = CreateDeviceCode {
+            client_id: client.client_id,
+            scope,
+        };
+        let device = db.handle(msg).await?;
+        let user_code = format_user_code(&device.user_code);
+        let verification_uri = format!("{}/auth/device.html", issuer());
+        let expires_in = unix_time(device.expires_at).unwrap_or(0) - Local::now().timestamp();
+        Ok(HttpResponse::Ok()
+            .header(CACHE_CONTROL, "no-store")
+            .json(DeviceAuthorizationResponse {
+                device_code: device.device_code,
+                verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+                user_code,
+                verification_uri,
+                expires_in,
+                interval: DEVICE_POLL_INTERVAL,
+            }))
+    }
+    .await;
+    match result {
+        Ok(response) => Ok(response),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceQuery {
+    pub user_code: String,
+}
+
+/// The client and scope of a device authorization for the device page
+pub async fn device_info(
+    _: LoggedUser,
+    query: Query<DeviceQuery>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let result: Result<_, ServiceError> = async {
+        let msg = GetDeviceCode {
+            user_code: query.into_inner().user_code,
+        };
+        let device = match db.handle(msg).await? {
+            Some(device) => device,
+            None => return Ok(None),
+        };
+        let scope = device.scope;
+        let msg = GetOAuthClient {
+            client_id: device.client_id,
+        };
+        Ok(db.handle(msg).await?.map(|client| {
+            hashmap! {
+                "client_id" => client.client_id,
+                "name" => client.name,
+                "scope" => scope,
+            }
+        }))
+    }
+    .await;
+    match result {
+        Ok(Some(info)) => Ok(HttpResponse::Ok().json(info)),
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceApproval {
+    pub user_code: String,
+    pub approve: bool,
+}
+
+/// Called by the device page with the user's answer to a device authorization
+pub async fn device_approve(
+    user: SessionUser,
+    data: Json<DeviceApproval>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let DeviceApproval { user_code, approve } = data.into_inner();
+    let result: Result<_, ServiceError> = async {
+        let (auth_time, auth_methods) = session_auth(&user, &db).await?;
+        let msg = ApproveDeviceCode {
+            user_code,
+            approve,
+            email: user.email.clone(),
+            auth_time,
+            auth_methods,
+        };
+        db.handle(msg).await
+    }
+    .await;
+    match result {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            Ok(HttpResponse::Ok().json(hashmap! { "status" => status }))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+/// The OpenID Connect userinfo endpoint, for access tokens granted the openid scope
+pub async fn userinfo(request: HttpRequest, db: Data<DbExecutor>) -> Result<HttpResponse, Error> {
+    let claim =
+        match bearer_token(&request).map(|token| Token::decode_token(&token.to_string().into())) {
+            Some(Ok(claim)) => claim,
+            _ => return Ok(invalid_token()),
+        };
+    let msg = GetUserInfo {
+        session_id: claim.get_session_id(),
+    };
+    match db.handle(msg).await {
+        Ok(Some(user_info)) => Ok(HttpResponse::Ok().json(user_info)),
+        Ok(None) => Ok(invalid_token()),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+fn invalid_token() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header(WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)
+        .finish()
+}
+
+// OpenID Connect Discovery 1.0 provider metadata
+#[derive(Debug, Serialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    jwks_uri: String,
+    introspection_endpoint: String,
+    revocation_endpoint: String,
+    device_authorization_endpoint: String,
+    scopes_supported: Vec<&'static str>,
+    response_types_supported: [&'static str; 1],
+    grant_types_supported: [&'static str; 4],
+    subject_types_supported: [&'static str; 1],
+    id_token_signing_alg_values_supported: Vec<Algorithm>,
+    token_endpoint_auth_methods_supported: [&'static str; 3],
+    code_challenge_methods_supported: [&'static str; 1],
+    claims_supported: [&'static str; 10],
+}
+
+/// `/.well-known/openid-configuration`, the issuer is `https://{DOMAIN}`
+pub async fn openid_configuration() -> HttpResponse {
+    let issuer = issuer();
+    HttpResponse::Ok().json(OpenIdConfiguration {
+        authorization_endpoint: format!("{}/oauth/authorize", issuer),
+        token_endpoint: format!("{}/oauth/token", issuer),
+        userinfo_endpoint: format!("{}/oauth/userinfo", issuer),
+        jwks_uri: format!("{}/.well-known/jwks.json", issuer),
+        introspection_endpoint: format!("{}/oauth/introspect", issuer),
+        revocation_endpoint: format!("{}/oauth/revoke", issuer),
+        device_authorization_endpoint: format!("{}/oauth/device_authorization", issuer),
+        issuer,
+        scopes_supported: supported_scopes(),
+        response_types_supported: ["code"],
+        grant_types_supported: [
+            "authorization_code",
+            "refresh_token",
+            "client_credentials",
+            DEVICE_CODE_GRANT,
+        ],
+        subject_types_supported: ["public"],
+        id_token_signing_alg_values_supported: id_token_signing_algorithm().into_iter().collect(),
+        token_endpoint_auth_methods_supported: [
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ],
+        code_challenge_methods_supported: ["S256"],
+        claims_supported: [
+            "iss",
+            "sub",
+            "aud",
+            "iat",
+            "exp",
+            "auth_time",
+            "nonce",
+            "amr",
+            "email",
+            "email_verified",
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test::{self, TestRequest},
+        web, App,
+    };
+    use anyhow::Error;
+    use base64::encode;
+    use chrono::{Duration, Local};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use maplit::hashmap;
+    use openid::Config;
+
+    use serde_json::Value;
+    use url::Url;
+    use uuid::Uuid;
+
+    use crate::{
+        logged_user::{LoggedUser, AUTHORIZED_USERS, PERSONAL_TOKENS, PERSONAL_TOKEN_PREFIX},
+        models::{DbExecutor, HandleRequest, PersonalAccessToken, SlimUser, User},
+        oauth_client_handler::{CreateOAuthClient, NewOAuthClient},
+        oauth_routes::{
+            authorization_response, basic_credentials, consent, openid_configuration, revoke, token,
+        },
+        refresh_token_handler::IssueRefreshToken,
+        rust_auth_server::{get_pool, load_config},
+        session_handler::{CreateSession, GetSession},
+        utils::{get_random_string, hash_token, pkce_challenge, Token},
+    };
+
+    fn create_user(pool: &DbExecutor) -> Result<String, Error> {
+        use crate::schema::users::dsl::users;
+
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        diesel::insert_into(users)
+            .values(&User::from_details(email.clone(), String::new()))
+            .execute(&pool.0.get()?)?;
+        Ok(email)
+    }
+
+    async fn create_client(pool: &DbExecutor, redirect_uri: &str) -> Result<NewOAuthClient, Error> {
+        let msg = CreateOAuthClient {
+            name: "test client".into(),
+            redirect_uris: vec![redirect_uri.into()],
+            public_client: false,
+        };
+        pool.handle(msg).await.map_err(Into::into)
+    }
+
+    fn client_auth(client: &NewOAuthClient) -> String {
+        let credentials = format!("{}:{}", client.client.client_id, client.client_secret);
+        format!("Basic {}", encode(credentials))
+    }
+
+    fn delete_test_data(pool: &DbExecutor, email: &str, clients: &[&str]) -> Result<(), Error> {
+        use crate::schema::{oauth_clients, users};
+
+        let conn = pool.0.get()?;
+        diesel::delete(users::table.filter(users::email.eq(email))).execute(&conn)?;
+        diesel::delete(oauth_clients::table.filter(oauth_clients::client_id.eq_any(clients)))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        let request = TestRequest::default()
+            .header(
+                AUTHORIZATION,
+                format!("Basic {}", encode("client%3A1:se+cr%25et")),
+            )
+            .to_http_request();
+        assert_eq!(
+            basic_credentials(&request),
+            Some(("client:1".to_string(), "se cr%et".to_string()))
+        );
+
+        let request = TestRequest::default()
+            .header(AUTHORIZATION, "Bearer token")
+            .to_http_request();
+        assert_eq!(basic_credentials(&request), None);
+        let request = TestRequest::default()
+            .header(AUTHORIZATION, format!("Basic {}", encode("no-secret")))
+            .to_http_request();
+        assert_eq!(basic_credentials(&request), None);
+    }
+
+    #[test]
+    fn test_authorization_response() {
+        let redirect_uri = "https://app.example.com/callback?tenant=a".parse().unwrap();
+        let url = authorization_response(&redirect_uri, &[("code", "abc")], Some("x y&z"));
+        assert_eq!(
+            url.as_str(),
+            "https://app.example.com/callback?tenant=a&code=abc&state=x+y%26z"
+        );
+        let url = authorization_response(&redirect_uri, &[("error", "access_denied")], None);
+        assert_eq!(
+            url.as_str(),
+            "https://app.example.com/callback?tenant=a&error=access_denied"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_openid_configuration() {
+        let mut app = test::init_service(App::new().route(
+            "/.well-known/openid-configuration",
+            web::get().to(openid_configuration),
+        ))
+        .await;
+        let request = TestRequest::get()
+            .uri("/.well-known/openid-configuration")
+            .to_request();
+        // the document must be usable by the same client library we use with Google
+        let config: Config = test::read_response_json(&mut app, request).await;
+        assert_eq!(config.token_endpoint.path(), "/oauth/token");
+        assert_eq!(
+            config.authorization_endpoint.as_str(),
+            format!(
+                "{}/oauth/authorize",
+                config.issuer.as_str().trim_end_matches('/')
+            )
+        );
+        assert_eq!(config.response_types_supported, vec!["code"]);
+        // the default signing key is a shared secret, clients couldn't verify ID tokens
+        assert!(config.id_token_signing_alg_values_supported.is_empty());
+        assert!(!config
+            .scopes_supported
+            .unwrap_or_default()
+            .contains(&"openid".to_string()));
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_revoke_checks_client() -> Result<(), Error> {
+        load_config();
+        let pool = get_pool();
+        let email = create_user(&pool)?;
+        let owner = create_client(&pool, "https://app.example.com/callback").await?;
+        let other = create_client(&pool, "https://app.example.com/callback").await?;
+        let session = pool
+            .handle(CreateSession {
+                email: email.clone(),
+                user_agent: None,
+                ip_address: None,
+                client_id: Some(owner.client.client_id.clone()),
+                auth_methods: Vec::new(),
+                scope: Some("openid".into()),
+            })
+            .await?;
+        let refresh_token = pool
+            .handle(IssueRefreshToken {
+                email: email.clone(),
+                session_id: session.id,
+            })
+            .await?;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(pool.clone())
+                .route("/oauth/revoke", web::post().to(revoke)),
+        )
+        .await;
+        let revoke_request = |client: &NewOAuthClient, token: &str| {
+            TestRequest::post()
+                .uri("/oauth/revoke")
+                .header(AUTHORIZATION, client_auth(client))
+                .set_form(&hashmap! {"token" => token})
+                .to_request()
+        };
+
+        // another client gets the same answer, but the session stays logged in
+        let response = test::call_service(&mut app, revoke_request(&other, &refresh_token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let active = pool
+            .handle(GetSession {
+                session_id: session.id,
+            })
+            .await?;
+
+        let service_token: String =
+            Token::create_service_token("reports", "reports:read".into())?.into();
+        let response = test::call_service(&mut app, revoke_request(&owner, &service_token)).await;
+        let unsupported = response.status();
+
+        let response = test::call_service(&mut app, revoke_request(&owner, &refresh_token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let revoked = pool
+            .handle(GetSession {
+                session_id: session.id,
+            })
+            .await?;
+
+        delete_test_data(
+            &pool,
+            &email,
+            &[&owner.client.client_id, &other.client.client_id],
+        )?;
+        assert!(active.is_some());
+        assert_eq!(unsupported, StatusCode::BAD_REQUEST);
+        assert!(revoked.is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_authorization_code_redirect_uri() -> Result<(), Error> {
+        load_config();
+        let pool = get_pool();
+        let email = create_user(&pool)?;
+        // parsed as a Url this would gain a trailing slash
+        let redirect_uri = "https://app.example.com";
+        let client = create_client(&pool, redirect_uri).await?;
+        AUTHORIZED_USERS.store_auth(LoggedUser::from_email(email.clone()), true)?;
+        let user = SlimUser {
+            email: email.clone(),
+        };
+        let session = pool
+            .handle(CreateSession {
+                email: email.clone(),
+                user_agent: None,
+                ip_address: None,
+                client_id: None,
+                auth_methods: vec!["pwd".into()],
+                scope: None,
+            })
+            .await?;
+        let user_token: String = Token::create_token(&user, session.id, Vec::new())?.into();
+        // a personal access token is no login to approve clients with
+        let personal_token = format!("{}{}", PERSONAL_TOKEN_PREFIX, get_random_string());
+        let current_time = Local::now().naive_local();
+        let personal_access_token = PersonalAccessToken {
+            id: Uuid::new_v4(),
+            email: email.clone(),
+            name: "script".into(),
+            token_hash: hash_token(&personal_token),
+            scopes: Vec::new(),
+            created_at: current_time,
+            expires_at: current_time + Duration::days(1),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        PERSONAL_TOKENS.insert(&personal_access_token, &[]);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(pool.clone())
+                .route("/api/oauth/consent", web::post().to(consent))
+                .route("/oauth/token", web::post().to(token)),
+        )
+        .await;
+        let consent_request = |redirect_uri: Option<&str>, token: &str| {
+            let mut data = serde_json::json!({
+                "response_type": "code",
+                "client_id": client.client.client_id,
+                "scope": "email",
+                "code_challenge": pkce_challenge("verifier"),
+                "code_challenge_method": "S256",
+                "approve": true,
+            });
+            if let Some(redirect_uri) = redirect_uri {
+                data["redirect_uri"] = redirect_uri.into();
+            }
+            TestRequest::post()
+                .uri("/api/oauth/consent")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .set_json(&data)
+                .to_request()
+        };
+        let code_of = |response: Value| {
+            let url: Url = response["redirect_uri"].as_str().unwrap().parse().unwrap();
+            url.query_pairs()
+                .find(|(key, _)| key == "code")
+                .map(|(_, code)| code.into_owned())
+                .unwrap()
+        };
+        let request = consent_request(Some(redirect_uri), &user_token);
+        let code_sent = code_of(test::read_response_json(&mut app, request).await);
+        let request = consent_request(None, &user_token);
+        let code_omitted = code_of(test::read_response_json(&mut app, request).await);
+        let request = consent_request(Some(redirect_uri), &user_token);
+        let code_mismatch = code_of(test::read_response_json(&mut app, request).await);
+        let request = consent_request(Some(redirect_uri), &personal_token);
+        let personal_consent = test::call_service(&mut app, request).await.status();
+        PERSONAL_TOKENS.remove(personal_access_token.id);
+
+        let token_request = |code: &str, redirect_uri: Option<&str>| {
+            let mut form = hashmap! {
+                "grant_type" => "authorization_code",
+                "code" => code,
+                "code_verifier" => "verifier",
+            };
+            if let Some(redirect_uri) = redirect_uri {
+                form.insert("redirect_uri", redirect_uri);
+            }
+            TestRequest::post()
+                .uri("/oauth/token")
+                .header(AUTHORIZATION, client_auth(&client))
+                .set_form(&form)
+                .to_request()
+        };
+        let request = token_request(&code_sent, Some(redirect_uri));
+        let sent = test::call_service(&mut app, request).await.status();
+        let request = token_request(&code_omitted, None);
+        let omitted = test::call_service(&mut app, request).await.status();
+        let request = token_request(&code_mismatch, Some("https://app.example.com/"));
+        let mismatch = test::call_service(&mut app, request).await.status();
+
+        delete_test_data(&pool, &email, &[&client.client.client_id])?;
+        assert_eq!(sent, StatusCode::OK);
+        assert_eq!(omitted, StatusCode::OK);
+        assert_eq!(mismatch, StatusCode::BAD_REQUEST);
+        assert_eq!(personal_consent, StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
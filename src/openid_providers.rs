@@ -0,0 +1,554 @@
+use anyhow::{format_err, Error};
+use log::error;
+use openid::{Client, CompactJson, CustomClaims, Discovered, StandardClaims};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env::var, fs, path::Path, sync::Arc};
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::{errors::ServiceError, github_oauth::GithubClient, invitation_handler::email_domain};
+
+fn default_require_verified_email() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderType {
+    // any issuer supporting discovery (Google, Microsoft Entra, GitLab, Keycloak, ...)
+    #[default]
+    Oidc,
+    // plain OAuth2 without an id token
+    Github,
+}
+
+/// One login provider of the registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub provider_type: ProviderType,
+    // required for oidc, for github the web url of a GitHub Enterprise server
+    #[serde(default)]
+    pub issuer: Option<Url>,
+    pub client_id: String,
+    pub client_secret: String,
+    // empty for the defaults of the provider type, openid is always requested in addition
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // defaults to /api/callback/{name}, the only other path routed is /api/callback which
+    // serves the default provider
+    #[serde(default)]
+    pub callback_path: Option<String>,
+    // github only: the API url if it is not derived from the issuer
+    #[serde(default)]
+    pub api_url: Option<Url>,
+    // github only: restrict logins to members of these organizations
+    #[serde(default)]
+    pub organizations: Vec<String>,
+    // the first login of a user without an identity of this provider links it to the account
+    // with the asserted email, only for providers trusted to verify emails
+    #[serde(default)]
+    pub link_by_email: bool,
+    // reject logins with an email the provider has not verified
+    #[serde(default = "default_require_verified_email")]
+    pub require_verified_email: bool,
+    // oidc only: restrict logins to these Google Workspace domains (the hd claim)
+    #[serde(default)]
+    pub hosted_domains: Vec<String>,
+    // unknown users with a verified email of these domains get an account without a password
+    // on their first login instead of requiring an invitation
+    #[serde(default)]
+    pub provision_domains: Vec<String>,
+}
+
+impl ProviderConfig {
+    pub fn scopes(&self) -> Vec<String> {
+        if !self.scopes.is_empty() {
+            return self.scopes.clone();
+        }
+        match self.provider_type {
+            ProviderType::Oidc => vec!["email".into()],
+            ProviderType::Github if self.organizations.is_empty() => vec!["user:email".into()],
+            ProviderType::Github => vec!["user:email".into(), "read:org".into()],
+        }
+    }
+
+    pub fn callback_path(&self) -> String {
+        self.callback_path
+            .clone()
+            .unwrap_or_else(|| format!("/api/callback/{}", self.name))
+    }
+
+    // any other path would not reach the callback of this provider
+    fn check_callback_path(&self, default: Option<&str>) -> Result<(), Error> {
+        let path = self.callback_path();
+        if path == format!("/api/callback/{}", self.name)
+            || (path == "/api/callback" && default == Some(self.name.as_str()))
+        {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "Invalid callback path {} of provider {}, only /api/callback/{} or /api/callback \
+                 for the default provider are routed",
+                path,
+                self.name,
+                self.name
+            ))
+        }
+    }
+
+    pub fn redirect_url(&self, domain: &str) -> String {
+        format!("https://{}{}", domain, self.callback_path())
+    }
+
+    /// Apply the email and hosted domain policy of the provider to a login
+    pub fn check_identity(&self, identity: &ExternalIdentity) -> Result<(), ServiceError> {
+        if self.require_verified_email && !identity.email_verified {
+            return Err(ServiceError::BadRequest("Email is not verified".into()));
+        }
+        if !self.hosted_domains.is_empty() {
+            let allowed = identity.hosted_domain.as_ref().is_some_and(|hd| {
+                self.hosted_domains
+                    .iter()
+                    .any(|domain| domain.eq_ignore_ascii_case(hd))
+            });
+            if !allowed {
+                return Err(ServiceError::BadRequest("Hosted domain not allowed".into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an unknown user logging in with this identity gets an account
+    pub fn can_provision(&self, identity: &ExternalIdentity) -> bool {
+        if !identity.email_verified {
+            return false;
+        }
+        match identity.email.as_deref().and_then(email_domain) {
+            Some(domain) => self
+                .provision_domains
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(&domain)),
+            None => false,
+        }
+    }
+}
+
+/// Contents of the file at `OPENID_PROVIDERS`, e.g.
+/// ```json
+/// {
+///   "default": "google",
+///   "providers": [
+///     {"name": "google", "issuer": "https://accounts.google.com",
+///      "client_id": "...", "client_secret": "...",
+///      "hosted_domains": ["example.com"], "provision_domains": ["example.com"]},
+///     {"name": "keycloak", "issuer": "https://sso.example.com/realms/main",
+///      "client_id": "...", "client_secret": "...", "scopes": ["email", "profile"]},
+///     {"name": "github", "type": "github", "client_id": "...", "client_secret": "...",
+///      "organizations": ["example-org"]}
+///   ]
+/// }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProvidersConfig {
+    // used by /api/auth_url without a provider and by the /api/callback route
+    #[serde(default)]
+    pub default: Option<String>,
+    pub providers: Vec<ProviderConfig>,
+}
+
+impl ProvidersConfig {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let config: Self = serde_json::from_slice(&fs::read(path)?)?;
+        if let Some(default) = &config.default {
+            if !config.providers.iter().any(|p| &p.name == default) {
+                return Err(format_err!("Unknown default provider {}", default));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The provider file if `OPENID_PROVIDERS` is set, otherwise Google configured through
+    /// `GOOGLE_CLIENT_ID` and `GOOGLE_CLIENT_SECRET` using the original /api/callback path
+    pub fn from_env() -> Result<Self, Error> {
+        if let Ok(path) = var("OPENID_PROVIDERS") {
+            return Self::read(Path::new(&path));
+        }
+        match (var("GOOGLE_CLIENT_ID"), var("GOOGLE_CLIENT_SECRET")) {
+            (Ok(client_id), Ok(client_secret)) => Ok(Self {
+                default: Some("google".into()),
+                providers: vec![ProviderConfig {
+                    name: "google".into(),
+                    provider_type: ProviderType::Oidc,
+                    issuer: Some(Url::parse("https://accounts.google.com")?),
+                    client_id,
+                    client_secret,
+                    scopes: Vec::new(),
+                    callback_path: Some("/api/callback".into()),
+                    api_url: None,
+                    organizations: Vec::new(),
+                    // accounts predating linked identities log in by email once
+                    link_by_email: true,
+                    require_verified_email: true,
+                    hosted_domains: Vec::new(),
+                    provision_domains: Vec::new(),
+                }],
+            }),
+            _ => Ok(Self::default()),
+        }
+    }
+}
+
+/// The account a provider authenticated
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalIdentity {
+    // stable identifier of the account at the provider
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    // the Google Workspace domain of the account, if any
+    pub hosted_domain: Option<String>,
+}
+
+/// Id token claims, the standard ones and the hosted domain asserted by Google
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenIdClaims {
+    #[serde(default)]
+    pub hd: Option<String>,
+    #[serde(flatten)]
+    pub standard_claims: StandardClaims,
+}
+
+impl CustomClaims for OpenIdClaims {
+    fn standard_claims(&self) -> &StandardClaims {
+        &self.standard_claims
+    }
+}
+
+impl CompactJson for OpenIdClaims {}
+
+pub type OpenIdClient = Client<Discovered, OpenIdClaims>;
+
+enum ProviderClient {
+    // discovered on first use, and again after a failed login in case the provider rotated
+    // its keys
+    OpenId {
+        issuer: Url,
+        client: RwLock<Option<Arc<OpenIdClient>>>,
+    },
+    Github(Box<GithubClient>),
+}
+
+pub struct LoginProvider {
+    pub config: ProviderConfig,
+    redirect_url: String,
+    client: ProviderClient,
+}
+
+impl LoginProvider {
+    pub fn new(config: ProviderConfig, domain: &str) -> Result<Self, Error> {
+        let redirect_url = config.redirect_url(domain);
+        let client = match config.provider_type {
+            ProviderType::Oidc => ProviderClient::OpenId {
+                issuer: config
+                    .issuer
+                    .clone()
+                    .ok_or_else(|| format_err!("Provider {} has no issuer", config.name))?,
+                client: RwLock::new(None),
+            },
+            ProviderType::Github => {
+                ProviderClient::Github(Box::new(GithubClient::new(&config, redirect_url.clone())?))
+            }
+        };
+        Ok(Self {
+            config,
+            redirect_url,
+            client,
+        })
+    }
+
+    pub fn github(&self) -> Option<&GithubClient> {
+        match &self.client {
+            ProviderClient::Github(client) => Some(client),
+            ProviderClient::OpenId { .. } => None,
+        }
+    }
+
+    pub async fn get_client(&self) -> Result<Arc<OpenIdClient>, ServiceError> {
+        if let ProviderClient::OpenId { client, .. } = &self.client {
+            if let Some(client) = client.read().await.as_ref() {
+                return Ok(client.clone());
+            }
+        }
+        self.rediscover().await
+    }
+
+    pub async fn rediscover(&self) -> Result<Arc<OpenIdClient>, ServiceError> {
+        let (issuer, cached) = match &self.client {
+            ProviderClient::OpenId { issuer, client } => (issuer, client),
+            ProviderClient::Github(_) => {
+                return Err(ServiceError::BadRequest(format!(
+                    "{} is not an OpenID provider",
+                    self.config.name
+                )))
+            }
+        };
+        let client = OpenIdClient::discover(
+            self.config.client_id.clone(),
+            self.config.client_secret.clone(),
+            Some(self.redirect_url.clone()),
+            issuer.clone(),
+        )
+        .await
+        .map_err(|e| {
+            error!("Discovery of {} failed {:?}", self.config.name, e);
+            e
+        })?;
+        let client = Arc::new(client);
+        *cached.write().await = Some(client.clone());
+        Ok(client)
+    }
+}
+
+/// Whether `url` has the origin of one of the `allowed` urls and a path at or below its path
+pub fn is_allowed_redirect(url: &Url, allowed: &[Url]) -> bool {
+    allowed.iter().any(|base| {
+        let prefix = base.path().trim_end_matches('/');
+        base.origin() == url.origin()
+            && (url.path() == prefix || url.path().starts_with(&format!("{}/", prefix)))
+    })
+}
+
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: HashMap<String, LoginProvider>,
+    default: Option<String>,
+    // where a login may send the browser afterwards
+    allowed_redirects: Vec<Url>,
+}
+
+impl ProviderRegistry {
+    /// Without `allowed_redirects` only urls on `https://{domain}/` are accepted
+    pub fn new(
+        config: ProvidersConfig,
+        domain: &str,
+        allowed_redirects: Vec<Url>,
+    ) -> Result<Self, Error> {
+        let ProvidersConfig { default, providers } = config;
+        let default = default.or_else(|| providers.first().map(|p| p.name.clone()));
+        let providers = providers
+            .into_iter()
+            .map(|p| {
+                p.check_callback_path(default.as_deref())?;
+                Ok((p.name.clone(), LoginProvider::new(p, domain)?))
+            })
+            .collect::<Result<_, Error>>()?;
+        let allowed_redirects = if allowed_redirects.is_empty() {
+            vec![Url::parse(&format!("https://{}/", domain))?]
+        } else {
+            allowed_redirects
+        };
+        Ok(Self {
+            providers,
+            default,
+            allowed_redirects,
+        })
+    }
+
+    /// `ALLOWED_REDIRECTS` is a comma separated list of urls, e.g.
+    /// `https://www.example.com/,https://app.example.com/auth/`
+    pub fn from_env() -> Result<Self, Error> {
+        let domain = var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+        let allowed_redirects = var("ALLOWED_REDIRECTS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(Url::parse)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .unwrap_or_else(|_| Ok(Vec::new()))?;
+        Self::new(ProvidersConfig::from_env()?, &domain, allowed_redirects)
+    }
+
+    /// Parse the url to send the browser to after a login, if it is allowed
+    pub fn redirect_url(&self, final_url: &str) -> Result<Url, ServiceError> {
+        let final_url: Url = final_url
+            .parse()
+            .map_err(|err| ServiceError::BadRequest(format!("Failed to parse url {:?}", err)))?;
+        if is_allowed_redirect(&final_url, &self.allowed_redirects) {
+            Ok(final_url)
+        } else {
+            Err(ServiceError::BadRequest("Redirect url not allowed".into()))
+        }
+    }
+
+    /// Look up a provider by name, or the default provider for `None`
+    pub fn get(&self, name: Option<&str>) -> Result<&LoginProvider, ServiceError> {
+        name.or(self.default.as_deref())
+            .and_then(|name| self.providers.get(name))
+            .ok_or_else(|| ServiceError::BadRequest("Unknown provider".into()))
+    }
+
+    /// Names of the configured providers, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.providers.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::ServiceError,
+        openid_providers::{
+            is_allowed_redirect, ExternalIdentity, ProviderRegistry, ProviderType, ProvidersConfig,
+        },
+    };
+    use url::Url;
+
+    #[test]
+    fn test_providers_config() {
+        let config: ProvidersConfig = serde_json::from_str(
+            r#"{"providers": [
+                {"name": "gitlab", "issuer": "https://gitlab.com", "client_id": "id",
+                 "client_secret": "secret", "callback_path": "/api/callback"},
+                {"name": "entra",
+                 "issuer": "https://login.microsoftonline.com/tenant/v2.0",
+                 "client_id": "id", "client_secret": "secret",
+                 "scopes": ["email", "profile"]},
+                {"name": "github", "type": "github", "client_id": "id",
+                 "client_secret": "secret", "organizations": ["example-org"]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.providers[0].provider_type, ProviderType::Oidc);
+        assert_eq!(config.providers[0].scopes(), vec!["email".to_string()]);
+        assert_eq!(
+            config.providers[0].redirect_url("example.com"),
+            "https://example.com/api/callback"
+        );
+        assert_eq!(config.providers[1].callback_path(), "/api/callback/entra");
+        assert_eq!(
+            config.providers[2].scopes(),
+            vec!["user:email".to_string(), "read:org".to_string()]
+        );
+
+        let registry = ProviderRegistry::new(config, "example.com", Vec::new()).unwrap();
+        assert_eq!(registry.names(), vec!["entra", "github", "gitlab"]);
+        // the first provider is the default
+        assert_eq!(registry.get(None).unwrap().config.name, "gitlab");
+        assert_eq!(registry.get(Some("entra")).unwrap().config.name, "entra");
+        assert!(registry.get(Some("github")).unwrap().github().is_some());
+        assert!(registry.get(Some("gitlab")).unwrap().github().is_none());
+        assert!(matches!(
+            registry.get(Some("bitbucket")),
+            Err(ServiceError::BadRequest(_))
+        ));
+
+        // an oidc provider needs an issuer
+        let config: ProvidersConfig = serde_json::from_str(
+            r#"{"providers": [{"name": "oidc", "client_id": "id", "client_secret": "secret"}]}"#,
+        )
+        .unwrap();
+        assert!(ProviderRegistry::new(config, "example.com", Vec::new()).is_err());
+
+        // only the routed callback paths are accepted, /api/callback for the default provider
+        for providers in [
+            r#"[{"name": "gitlab", "issuer": "https://gitlab.com", "client_id": "id",
+                 "client_secret": "secret", "callback_path": "/api/callback/ms"}]"#,
+            r#"[{"name": "gitlab", "issuer": "https://gitlab.com", "client_id": "id",
+                 "client_secret": "secret"},
+                {"name": "entra", "issuer": "https://login.microsoftonline.com/tenant/v2.0",
+                 "client_id": "id", "client_secret": "secret", "callback_path": "/api/callback"}]"#,
+        ] {
+            let config = ProvidersConfig {
+                default: None,
+                providers: serde_json::from_str(providers).unwrap(),
+            };
+            assert!(ProviderRegistry::new(config, "example.com", Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_allowed_redirects() {
+        let allowed = vec![
+            Url::parse("https://example.com/").unwrap(),
+            Url::parse("https://app.example.com/auth").unwrap(),
+        ];
+        for url in &[
+            "https://example.com/",
+            "https://example.com/auth/login.html?x=1",
+            "https://app.example.com/auth",
+            "https://app.example.com/auth/done#token",
+        ] {
+            assert!(
+                is_allowed_redirect(&Url::parse(url).unwrap(), &allowed),
+                "{}",
+                url
+            );
+        }
+        for url in &[
+            "http://example.com/",
+            "https://example.com:8443/",
+            "https://evil.com/https://example.com/",
+            "https://example.com.evil.com/",
+            "https://app.example.com/",
+            "https://app.example.com/authx",
+            "javascript:alert(1)",
+        ] {
+            assert!(
+                !is_allowed_redirect(&Url::parse(url).unwrap(), &allowed),
+                "{}",
+                url
+            );
+        }
+
+        let config = ProvidersConfig::default();
+        let registry = ProviderRegistry::new(config, "example.com", Vec::new()).unwrap();
+        assert!(registry.redirect_url("https://example.com/auth/").is_ok());
+        assert!(registry.redirect_url("https://other.com/").is_err());
+        assert!(registry.redirect_url("not a url").is_err());
+    }
+
+    #[test]
+    fn test_identity_policy() {
+        let config: ProvidersConfig = serde_json::from_str(
+            r#"{"providers": [
+                {"name": "google", "issuer": "https://accounts.google.com", "client_id": "id",
+                 "client_secret": "secret", "hosted_domains": ["example.com"],
+                 "provision_domains": ["Example.com"]},
+                {"name": "gitlab", "issuer": "https://gitlab.com", "client_id": "id",
+                 "client_secret": "secret", "require_verified_email": false}
+            ]}"#,
+        )
+        .unwrap();
+        let (google, gitlab) = (&config.providers[0], &config.providers[1]);
+        let mut identity = ExternalIdentity {
+            subject: "123".into(),
+            email: Some("user@example.com".into()),
+            email_verified: true,
+            hosted_domain: Some("example.com".into()),
+        };
+        assert!(google.check_identity(&identity).is_ok());
+        assert!(google.can_provision(&identity));
+        assert!(!gitlab.can_provision(&identity));
+
+        identity.hosted_domain = Some("other.com".into());
+        assert!(google.check_identity(&identity).is_err());
+        identity.hosted_domain = None;
+        assert!(google.check_identity(&identity).is_err());
+        assert!(gitlab.check_identity(&identity).is_ok());
+
+        identity.email_verified = false;
+        assert!(gitlab.check_identity(&identity).is_ok());
+        assert!(!google.can_provision(&identity));
+        identity.hosted_domain = Some("example.com".into());
+        assert!(matches!(
+            google.check_identity(&identity),
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+}
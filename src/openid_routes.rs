@@ -0,0 +1,460 @@
+use crate::{
+    auth_routes::start_session,
+    errors::ServiceError,
+    identity_handler::{IdentityLogin, LinkIdentity},
+    models::{DbExecutor, HandleRequest, PendingAuth, SlimUser, User},
+    openid_providers::{
+        ExternalIdentity, LoginProvider, OpenIdClaims, OpenIdClient, ProviderRegistry,
+    },
+    pending_auth_handler::{CreatePendingAuth, PendingAuthStore},
+    utils::{get_random_string, get_random_token, pkce_challenge},
+};
+use actix::Addr;
+use actix_identity::Identity;
+use actix_web::{
+    http::header::{ACCEPT, LOCATION},
+    web,
+    web::{Data, Json, Path, Query},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use bcrypt::verify;
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::debug;
+use openid::{Bearer, Claims, Options, Token as OpenIdToken, Userinfo};
+use serde::{Deserialize, Serialize};
+use std::env::var;
+use tokio::task::spawn_blocking;
+use url::Url;
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAuthUrlData {
+    pub final_url: String,
+}
+
+pub async fn auth_providers(providers: Data<ProviderRegistry>) -> HttpResponse {
+    HttpResponse::Ok().json(providers.names())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProviderQuery {
+    pub provider: Option<String>,
+}
+
+fn get_auth_url(
+    provider: &LoginProvider,
+    client: &OpenIdClient,
+    code_challenge: &str,
+) -> (Url, Options) {
+    let options = Options {
+        scope: Some(provider.config.scopes().join(" ")),
+        state: Some(get_random_string()),
+        nonce: Some(get_random_string()),
+        ..Options::default()
+    };
+    let mut url = client.auth_url(&options);
+    url.query_pairs_mut()
+        .append_pair("code_challenge", code_challenge)
+        .append_pair("code_challenge_method", "S256");
+    (url, options)
+}
+
+/// Authorization url of the provider, the callback redirects to `final_url` afterwards, which
+/// has to be checked with `ProviderRegistry::redirect_url`
+pub async fn start_login(
+    provider: &LoginProvider,
+    db: &DbExecutor,
+    final_url: Url,
+    link_to: Option<String>,
+) -> Result<Url, ServiceError> {
+    debug!("{:?}", final_url);
+    let code_verifier = get_random_token();
+    let code_challenge = pkce_challenge(&code_verifier);
+    let (authorize_url, csrf_state, nonce) = if let Some(github) = provider.github() {
+        // without an id token there is nothing to bind a nonce to
+        let csrf_state = get_random_string();
+        (
+            github.auth_url(&csrf_state, &code_challenge),
+            csrf_state,
+            String::new(),
+        )
+    } else {
+        let client = provider.get_client().await?;
+        let (authorize_url, options) = get_auth_url(provider, &client, &code_challenge);
+        let csrf_state = options.state.expect("No CSRF state");
+        let nonce = options.nonce.expect("No nonce");
+        (authorize_url, csrf_state, nonce)
+    };
+
+    let msg = CreatePendingAuth {
+        state: csrf_state,
+        provider: provider.config.name.clone(),
+        link_to,
+        nonce,
+        code_verifier,
+        final_url,
+    };
+    db.create_pending_auth(msg).await?;
+    Ok(authorize_url)
+}
+
+pub async fn auth_url(
+    payload: Json<GetAuthUrlData>,
+    query: Query<ProviderQuery>,
+    db: Data<DbExecutor>,
+    providers: Data<ProviderRegistry>,
+) -> Result<HttpResponse, ServiceError> {
+    let provider = providers.get(query.provider.as_deref())?;
+    let final_url = providers.redirect_url(&payload.final_url)?;
+    let authorize_url = start_login(provider, &db, final_url, None).await?;
+    Ok(HttpResponse::Ok().body(String::from(authorize_url)))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CallbackQuery {
+    code: String,
+    state: String,
+}
+
+// Client::authenticate cannot send a code verifier, so the token request is made here and the
+// id token validated the same way
+async fn request_token(
+    client: &OpenIdClient,
+    code: &str,
+    nonce: &str,
+    code_verifier: &str,
+) -> Result<OpenIdToken<OpenIdClaims>, ServiceError> {
+    let response = client
+        .http_client
+        .post(client.config().token_endpoint.clone())
+        .basic_auth(&client.client_id, Some(&client.client_secret))
+        .header(ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", client.redirect_url()),
+            ("code_verifier", code_verifier),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(ServiceError::BadRequest(format!(
+            "Token request failed {}",
+            response.status()
+        )));
+    }
+    let bearer: Bearer = response.json().await?;
+    let mut token: OpenIdToken<OpenIdClaims> = bearer.into();
+    if let Some(id_token) = token.id_token.as_mut() {
+        client.decode_token(id_token)?;
+        client.validate_token(id_token, Some(nonce), None)?;
+    }
+    Ok(token)
+}
+
+async fn request_identity(
+    client: &OpenIdClient,
+    code: &str,
+    nonce: &str,
+    code_verifier: &str,
+) -> Result<ExternalIdentity, ServiceError> {
+    let token = request_token(client, code, nonce, code_verifier).await?;
+    let userinfo = client.request_userinfo(&token).await?;
+    // the subject of the validated id token, which the userinfo response has to match
+    let (subject, hosted_domain) = match &token.id_token {
+        Some(id_token) => {
+            let claims = id_token
+                .payload()
+                .map_err(|e| ServiceError::BadRequest(format!("Invalid id token {:?}", e)))?;
+            (Some(claims.sub().to_string()), claims.hd.clone())
+        }
+        None => (userinfo.sub, None),
+    };
+    Ok(ExternalIdentity {
+        subject: subject.ok_or_else(|| ServiceError::BadRequest("No subject".into()))?,
+        email: userinfo.email,
+        email_verified: userinfo.email_verified,
+        hosted_domain,
+    })
+}
+
+/// Callback of the default provider, the redirect url originally registered with Google
+pub async fn callback(
+    query: Query<CallbackQuery>,
+    db: Data<DbExecutor>,
+    providers: Data<ProviderRegistry>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, ServiceError> {
+    let provider = providers.get(None)?;
+    provider_callback(provider, query.into_inner(), &db, &request, &id).await
+}
+
+pub async fn callback_provider(
+    provider: Path<String>,
+    query: Query<CallbackQuery>,
+    db: Data<DbExecutor>,
+    providers: Data<ProviderRegistry>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, ServiceError> {
+    let provider = providers.get(Some(provider.as_str()))?;
+    provider_callback(provider, query.into_inner(), &db, &request, &id).await
+}
+
+async fn provider_callback(
+    provider: &LoginProvider,
+    query: CallbackQuery,
+    db: &Data<DbExecutor>,
+    request: &HttpRequest,
+    id: &Identity,
+) -> Result<HttpResponse, ServiceError> {
+    let code = query.code.clone();
+
+    if let Some(PendingAuth {
+        provider: state_provider,
+        link_to,
+        nonce,
+        code_verifier,
+        final_url,
+        ..
+    }) = db.take_pending_auth(query.state.clone()).await?
+    {
+        if state_provider != provider.config.name {
+            return Ok(HttpResponse::Ok().body("Csrf Token invalid"));
+        }
+        debug!("Nonce {:?}", nonce);
+
+        let identity = if let Some(github) = provider.github() {
+            github.get_identity(&code, &code_verifier).await?
+        } else {
+            let client = provider.get_client().await?;
+            match request_identity(&client, &code, &nonce, &code_verifier).await {
+                Ok(identity) => identity,
+                Err(e) => {
+                    provider.rediscover().await?;
+                    return Err(e);
+                }
+            }
+        };
+        provider.config.check_identity(&identity)?;
+
+        if let Some(email) = link_to {
+            let msg = LinkIdentity {
+                email,
+                provider: provider.config.name.clone(),
+                identity,
+            };
+            db.handle(msg).await?;
+            return Ok(HttpResponse::SeeOther()
+                .header(LOCATION, final_url.as_str())
+                .finish());
+        }
+
+        let msg = IdentityLogin {
+            provider: provider.config.name.clone(),
+            provision: provider.config.can_provision(&identity),
+            identity,
+            link_by_email: provider.config.link_by_email,
+        };
+        if let Some(user) = db.handle(msg).await? {
+            let refresh_cookie = start_session(&user, &["fed"], request, id, db).await?;
+            return Ok(HttpResponse::SeeOther()
+                .header(LOCATION, final_url.as_str())
+                .cookie(refresh_cookie)
+                .finish());
+        }
+        Err(ServiceError::BadRequest("Oauth failed".into()))
+    } else {
+        Ok(HttpResponse::Ok().body("Csrf Token invalid"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test,
+        web::{self, Form},
+        App, HttpRequest, HttpResponse,
+    };
+    use anyhow::Error;
+    use base64::{encode_config, URL_SAFE_NO_PAD};
+    use chrono::{Duration, Utc};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::{collections::HashMap, path::Path};
+    use url::Url;
+
+    use crate::{
+        openid_providers::{ProviderConfig, ProviderRegistry, ProviderType, ProvidersConfig},
+        openid_routes::{get_auth_url, request_identity},
+        utils::pkce_challenge,
+    };
+
+    const MOCK_SECRET: &[u8] = b"mock issuer signing secret";
+
+    fn mock_issuer_url(request: &HttpRequest) -> String {
+        format!("http://{}", request.connection_info().host())
+    }
+
+    async fn mock_discovery(request: HttpRequest) -> HttpResponse {
+        let issuer = mock_issuer_url(&request);
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "userinfo_endpoint": format!("{}/userinfo", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["HS256"],
+        }))
+    }
+
+    async fn mock_jwks() -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "keys": [{"kty": "oct", "k": encode_config(MOCK_SECRET, URL_SAFE_NO_PAD)}]
+        }))
+    }
+
+    // the mock treats the authorization code as the nonce of the login and the nonce as the
+    // code verifier
+    async fn mock_token(request: HttpRequest, form: Form<HashMap<String, String>>) -> HttpResponse {
+        if form.get("code_verifier") != form.get("code") {
+            return HttpResponse::BadRequest().json(json!({"error": "invalid_grant"}));
+        }
+        let now = Utc::now();
+        let claims = json!({
+            "iss": mock_issuer_url(&request),
+            "sub": "mock-user",
+            "aud": "mock-client",
+            "iat": now.timestamp(),
+            "exp": (now + Duration::minutes(5)).timestamp(),
+            "nonce": form.get("code"),
+            "hd": "example.com",
+        });
+        let id_token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(MOCK_SECRET),
+        )
+        .unwrap();
+        HttpResponse::Ok().json(json!({
+            "access_token": "mock-access-token",
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token,
+        }))
+    }
+
+    async fn mock_userinfo() -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "sub": "mock-user",
+            "email": "user@example.com",
+            "email_verified": true,
+        }))
+    }
+
+    fn mock_issuer() -> test::TestServer {
+        test::start(|| {
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(mock_discovery),
+                )
+                .route("/jwks", web::get().to(mock_jwks))
+                .route("/token", web::post().to(mock_token))
+                .route("/userinfo", web::get().to(mock_userinfo))
+        })
+    }
+
+    #[actix_rt::test]
+    async fn test_mock_issuer() -> Result<(), Error> {
+        let issuer = mock_issuer();
+        let config = ProvidersConfig {
+            default: None,
+            providers: vec![ProviderConfig {
+                name: "mock".into(),
+                provider_type: ProviderType::Oidc,
+                issuer: Some(Url::parse(&issuer.url("/"))?),
+                client_id: "mock-client".into(),
+                client_secret: "mock-secret".into(),
+                scopes: vec!["email".into(), "profile".into()],
+                callback_path: None,
+                api_url: None,
+                organizations: Vec::new(),
+                link_by_email: false,
+                require_verified_email: true,
+                hosted_domains: vec!["example.com".into()],
+                provision_domains: Vec::new(),
+            }],
+        };
+        let providers = ProviderRegistry::new(config, "auth.example.com", Vec::new())?;
+        let provider = providers.get(Some("mock"))?;
+        let client = provider.get_client().await?;
+
+        let (url, options) = get_auth_url(provider, &client, &pkce_challenge("verifier"));
+        assert!(url.as_str().starts_with(&issuer.url("/authorize")));
+        assert!(url.as_str().contains("client_id=mock-client"));
+        assert!(url
+            .as_str()
+            .contains("redirect_uri=https%3A%2F%2Fauth.example.com%2Fapi%2Fcallback%2Fmock"));
+        assert!(url.as_str().contains("scope=openid+email+profile"));
+        assert!(url.as_str().contains(&format!(
+            "code_challenge={}&code_challenge_method=S256",
+            pkce_challenge("verifier")
+        )));
+
+        // the example of RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+
+        let nonce = options.nonce.expect("No nonce");
+        let identity = request_identity(&client, &nonce, &nonce, &nonce).await?;
+        assert_eq!(identity.subject, "mock-user");
+        assert_eq!(identity.email.as_deref(), Some("user@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.hosted_domain.as_deref(), Some("example.com"));
+        assert!(provider.config.check_identity(&identity).is_ok());
+
+        // an id token for another login is rejected
+        assert!(
+            request_identity(&client, "other-code", &nonce, "other-code")
+                .await
+                .is_err()
+        );
+        // so is a token request without the code verifier of the login
+        assert!(request_identity(&client, &nonce, &nonce, "other-verifier")
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_google_openid() -> Result<(), Error> {
+        let config_dir = dirs::config_dir().expect("No CONFIG directory");
+        let env_file = config_dir.join("rust_auth_server").join("config.env");
+
+        if env_file.exists() {
+            dotenv::from_path(&env_file).ok();
+        } else if Path::new("config.env").exists() {
+            dotenv::from_filename("config.env").ok();
+        } else {
+            dotenv::dotenv().ok();
+        }
+
+        let providers = ProviderRegistry::from_env()?;
+        let provider = providers.get(Some("google"))?;
+        let client = provider.get_client().await?;
+        let (url, _) = get_auth_url(provider, &client, &pkce_challenge("verifier"));
+        assert_eq!(url.domain(), Some("accounts.google.com"));
+        assert!(url
+            .as_str()
+            .contains("redirect_uri=https%3A%2F%2Fwww.ddboline.net%2Fapi%2Fcallback"));
+        assert!(url.as_str().contains("scope=openid+email"));
+        assert!(url.as_str().contains("response_type=code"));
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use chrono::{Duration, Local};
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::Deserialize;
+use std::env::var;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::{
+    email_service::send_password_reset,
+    errors::ServiceError,
+    logged_user::{PERSONAL_TOKENS, SESSIONS},
+    models::{DbExecutor, HandleRequest, PasswordReset, User},
+    personal_token_handler::revoke_user_personal_tokens,
+    session_handler::revoke_user_sessions,
+    utils::hash_password,
+};
+
+#[derive(Deserialize)]
+pub struct RequestPasswordReset {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<RequestPasswordReset> for DbExecutor {
+    type Result = Result<(), ServiceError>;
+
+    async fn handle(&self, msg: RequestPasswordReset) -> Self::Result {
+        use crate::schema::{password_resets::dsl::password_resets, users::dsl::users};
+
+        let dbex = self.clone();
+        let password_reset = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let user = users.find(&msg.email).first::<User>(&conn).optional()?;
+            if let Some(user) = user {
+                // reset links are only valid for one hour
+                let new_reset = PasswordReset {
+                    id: Uuid::new_v4(),
+                    email: user.email,
+                    expires_at: Local::now().naive_local() + Duration::hours(1),
+                    used_at: None,
+                };
+                diesel::insert_into(password_resets)
+                    .values(&new_reset)
+                    .get_result::<PasswordReset>(&conn)
+                    .map(Some)
+                    .map_err(ServiceError::DbError)
+            } else {
+                Ok(None)
+            }
+        })
+        .await??;
+
+        // the email is sent in the background and its errors are only logged, so the
+        // response doesn't reveal whether the email belongs to an account
+        if let Some(password_reset) = password_reset {
+            let callback_url = var("PASSWORD_RESET_URL")
+                .unwrap_or_else(|_| "http://localhost:3000/auth/reset_password.html".to_string());
+            actix_rt::spawn(async move {
+                if let Err(e) = send_password_reset(&password_reset, &callback_url).await {
+                    error!("Failed to send password reset {:?}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+// UserData is used to extract data from a post request by the client
+#[derive(Debug, Deserialize)]
+pub struct UserData {
+    pub password: String,
+}
+
+// to be used to send data via the Actix actor system
+#[derive(Debug)]
+pub struct ResetPassword {
+    pub reset_id: String,
+    pub password: String,
+}
+
+#[async_trait]
+impl HandleRequest<ResetPassword> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: ResetPassword) -> Self::Result {
+        use crate::schema::{password_resets, users};
+
+        let reset_id = Uuid::parse_str(&msg.reset_id)?;
+
+        let dbex = self.clone();
+        let (changed, revoked, revoked_tokens) = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| -> Result<(bool, Vec<Uuid>, Vec<Uuid>), ServiceError> {
+                let current_time = Local::now().naive_local();
+                let password_reset = password_resets::table
+                    .find(reset_id)
+                    .filter(password_resets::used_at.is_null())
+                    .filter(password_resets::expires_at.gt(current_time))
+                    .for_update()
+                    .first::<PasswordReset>(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest("Invalid Password Reset".into()))?;
+
+                let password_: String = hash_password(&msg.password)?;
+                let changed = diesel::update(users::table.find(&password_reset.email))
+                    .set(users::password.eq(password_))
+                    .execute(&conn)?;
+
+                // consume every outstanding reset for this user, not only this one
+                diesel::update(
+                    password_resets::table
+                        .filter(password_resets::email.eq(&password_reset.email))
+                        .filter(password_resets::used_at.is_null()),
+                )
+                .set(password_resets::used_at.eq(current_time))
+                .execute(&conn)?;
+
+                // whoever knew the old password is logged out everywhere, and loses the
+                // personal access tokens created with it
+                let revoked = revoke_user_sessions(&conn, &password_reset.email, None)?;
+                let revoked_tokens = revoke_user_personal_tokens(&conn, &password_reset.email)?;
+                Ok((changed > 0, revoked, revoked_tokens))
+            })
+        })
+        .await??;
+        SESSIONS.revoke(&revoked);
+        for token_id in revoked_tokens {
+            PERSONAL_TOKENS.remove(token_id);
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use chrono::{Duration, Local};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use uuid::Uuid;
+
+    use crate::{
+        models::{HandleRequest, PasswordReset, User},
+        password_reset_handler::{RequestPasswordReset, ResetPassword},
+        rust_auth_server::{get_pool, load_config},
+        session_handler::{CreateSession, GetSession},
+        utils::get_random_string,
+    };
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_reset_password() -> Result<(), Error> {
+        use crate::schema::{password_resets, users};
+
+        load_config();
+        let pool = get_pool();
+        let conn = pool.0.get()?;
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        diesel::insert_into(users::table)
+            .values(&User::from_details(email.clone(), String::new()))
+            .execute(&conn)?;
+        let session = pool
+            .handle(CreateSession {
+                email: email.clone(),
+                user_agent: None,
+                ip_address: None,
+                client_id: None,
+                auth_methods: vec!["pwd".into()],
+                scope: None,
+            })
+            .await?;
+
+        let expired = PasswordReset {
+            id: Uuid::new_v4(),
+            email: email.clone(),
+            expires_at: Local::now().naive_local() - Duration::minutes(1),
+            used_at: None,
+        };
+        diesel::insert_into(password_resets::table)
+            .values(&expired)
+            .execute(&conn)?;
+        let reset = |reset_id: Uuid| ResetPassword {
+            reset_id: reset_id.to_string(),
+            password: "new password".into(),
+        };
+        let expired_reset = pool.handle(reset(expired.id)).await;
+
+        let msg = RequestPasswordReset {
+            email: email.clone(),
+        };
+        pool.handle(msg).await?;
+        let reset_id: Uuid = password_resets::table
+            .filter(password_resets::email.eq(&email))
+            .filter(password_resets::expires_at.gt(Local::now().naive_local()))
+            .select(password_resets::id)
+            .first(&conn)?;
+        let first_use = pool.handle(reset(reset_id)).await;
+        let second_use = pool.handle(reset(reset_id)).await;
+        let session_id = session.id;
+        let session_after = pool.handle(GetSession { session_id }).await?;
+
+        diesel::delete(users::table.filter(users::email.eq(&email))).execute(&conn)?;
+        assert!(expired_reset.is_err());
+        assert!(first_use?);
+        // the link works once, and whoever knew the old password is logged out
+        assert!(second_use.is_err());
+        assert!(session_after.is_none());
+        Ok(())
+    }
+}
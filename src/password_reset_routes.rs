@@ -0,0 +1,97 @@
+use actix_web::{
+    web::{Data, Json, Path},
+    Error, HttpResponse, ResponseError,
+};
+use maplit::hashmap;
+
+use crate::{
+    models::{DbExecutor, HandleRequest},
+    password_reset_handler::{RequestPasswordReset, ResetPassword, UserData},
+};
+
+pub async fn request_password_reset(
+    reset_request: Json<RequestPasswordReset>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(reset_request.into_inner()).await {
+        Ok(_) => {
+            let result = hashmap! { "status" => "success" };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn reset_password(
+    reset_id: Path<String>,
+    user_data: Json<UserData>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ResetPassword {
+        // into_inner() returns the inner string value from Path
+        reset_id: reset_id.into_inner(),
+        password: user_data.into_inner().password,
+    };
+
+    match db.handle(msg).await {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            let result = hashmap! { "status" => status };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{self, TestRequest},
+        web, App,
+    };
+    use anyhow::Error;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    use crate::{
+        models::User,
+        password_reset_routes::request_password_reset,
+        rust_auth_server::{get_pool, load_config},
+        utils::get_random_string,
+    };
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_request_password_reset_response() -> Result<(), Error> {
+        use crate::schema::users;
+
+        load_config();
+        let pool = get_pool();
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        diesel::insert_into(users::table)
+            .values(&User::from_details(email.clone(), String::new()))
+            .execute(&pool.0.get()?)?;
+        let mut app = test::init_service(App::new().data(pool.clone()).route(
+            "/api/password_reset",
+            web::post().to(request_password_reset),
+        ))
+        .await;
+
+        // the response must not tell whether the email belongs to an account
+        let mut responses = Vec::new();
+        let unknown = format!("{}@example.com", get_random_string().to_lowercase());
+        for email in &[&email, &unknown] {
+            let request = TestRequest::post()
+                .uri("/api/password_reset")
+                .set_json(&serde_json::json!({ "email": email }))
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            let status = response.status();
+            responses.push((status, test::read_body(response).await));
+        }
+
+        diesel::delete(users::table.filter(users::email.eq(&email))).execute(&pool.0.get()?)?;
+        assert!(responses[0].0.is_success());
+        assert_eq!(responses[0], responses[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use async_trait::async_trait;
+use chrono::{Duration, Local};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use tokio::task::spawn_blocking;
+use url::Url;
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, PendingAuth},
+    utils::hash_token,
+};
+
+// time a user has to complete the login at the provider
+const PENDING_AUTH_MINUTES: i64 = 60;
+
+/// Storage of the logins started at an identity provider. It has to be shared by every
+/// instance of the server, the provider's callback may reach another one than the login
+#[async_trait]
+pub trait PendingAuthStore {
+    async fn create_pending_auth(&self, msg: CreatePendingAuth) -> Result<(), ServiceError>;
+
+    /// Look up the login of a callback, the state is removed so that it can only be used once
+    async fn take_pending_auth(&self, state: String) -> Result<Option<PendingAuth>, ServiceError>;
+
+    /// Remove the logins that were never completed
+    async fn cleanup_pending_auths(&self) -> Result<(), ServiceError>;
+}
+
+#[derive(Debug)]
+pub struct CreatePendingAuth {
+    pub state: String,
+    pub provider: String,
+    pub link_to: Option<String>,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub final_url: Url,
+}
+
+// the postgres implementation, a row of the pending_auths table per login
+#[async_trait]
+impl PendingAuthStore for DbExecutor {
+    async fn create_pending_auth(&self, msg: CreatePendingAuth) -> Result<(), ServiceError> {
+        use crate::schema::pending_auths::dsl::pending_auths;
+
+        let current_time = Local::now().naive_local();
+        let pending_auth = PendingAuth {
+            state_hash: hash_token(&msg.state),
+            provider: msg.provider,
+            link_to: msg.link_to,
+            nonce: msg.nonce,
+            code_verifier: msg.code_verifier,
+            final_url: msg.final_url.into(),
+            created_at: current_time,
+            expires_at: current_time + Duration::minutes(PENDING_AUTH_MINUTES),
+        };
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::insert_into(pending_auths)
+                .values(&pending_auth)
+                .execute(&conn)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn take_pending_auth(&self, state: String) -> Result<Option<PendingAuth>, ServiceError> {
+        use crate::schema::pending_auths::dsl::pending_auths;
+
+        let state_hash = hash_token(&state);
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let pending_auth: Option<PendingAuth> = diesel::delete(pending_auths.find(state_hash))
+                .get_results(&conn)?
+                .pop();
+            Ok(pending_auth
+                .filter(|pending_auth| pending_auth.expires_at > Local::now().naive_local()))
+        })
+        .await?
+    }
+
+    async fn cleanup_pending_auths(&self) -> Result<(), ServiceError> {
+        use crate::schema::pending_auths::dsl::{expires_at, pending_auths};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::delete(pending_auths.filter(expires_at.lt(Local::now().naive_local())))
+                .execute(&conn)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+
+    use crate::{
+        pending_auth_handler::{CreatePendingAuth, PendingAuthStore},
+        rust_auth_server::{get_pool, load_config},
+        utils::get_random_token,
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_pending_auth_store() -> Result<(), Error> {
+        load_config();
+        let pool = get_pool();
+        let state = get_random_token();
+        let msg = CreatePendingAuth {
+            state: state.clone(),
+            provider: "google".into(),
+            link_to: None,
+            nonce: "nonce".into(),
+            code_verifier: "verifier".into(),
+            final_url: "https://example.com/".parse()?,
+        };
+        pool.create_pending_auth(msg).await?;
+
+        let pending_auth = pool.take_pending_auth(state.clone()).await?;
+        assert_eq!(pending_auth.map(|p| p.nonce), Some("nonce".to_string()));
+        assert!(pool.take_pending_auth(state).await?.is_none());
+        pool.cleanup_pending_auths().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use async_trait::async_trait;
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, QueryResult, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::{
+    errors::ServiceError,
+    logged_user::{PERSONAL_TOKENS, PERSONAL_TOKEN_PREFIX},
+    models::{DbExecutor, HandleRequest, PersonalAccessToken},
+    utils::{get_random_token, hash_token},
+};
+
+const MAX_EXPIRES_IN_DAYS: i64 = 365;
+
+#[derive(Debug, Serialize)]
+pub struct PersonalTokenInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    // written back periodically, so it can be a minute behind
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+impl From<PersonalAccessToken> for PersonalTokenInfo {
+    fn from(token: PersonalAccessToken) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            scopes: token.scopes,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+        }
+    }
+}
+
+fn default_expires_in_days() -> i64 {
+    90
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PersonalTokenData {
+    pub name: String,
+    // roles of the user the token may use, none by default
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default = "default_expires_in_days")]
+    pub expires_in_days: i64,
+}
+
+#[derive(Debug)]
+pub struct CreatePersonalToken {
+    pub email: String,
+    // the roles of the creating user, the most a token can be granted
+    pub roles: Vec<String>,
+    pub data: PersonalTokenData,
+}
+
+// the only time the token is available
+#[derive(Debug, Serialize)]
+pub struct NewPersonalToken {
+    #[serde(flatten)]
+    pub info: PersonalTokenInfo,
+    pub token: String,
+}
+
+#[async_trait]
+impl HandleRequest<CreatePersonalToken> for DbExecutor {
+    type Result = Result<NewPersonalToken, ServiceError>;
+
+    async fn handle(&self, msg: CreatePersonalToken) -> Self::Result {
+        use crate::schema::personal_access_tokens::dsl::personal_access_tokens;
+
+        if let Some(scope) = msg.data.scopes.iter().find(|s| !msg.roles.contains(s)) {
+            return Err(ServiceError::BadRequest(format!(
+                "Cannot grant role {} to a token",
+                scope
+            )));
+        }
+        if msg.data.name.is_empty() || msg.data.name.len() > 100 {
+            return Err(ServiceError::BadRequest("Invalid token name".into()));
+        }
+        let token = format!("{}{}", PERSONAL_TOKEN_PREFIX, get_random_token());
+        let current_time = Local::now().naive_local();
+        let expires_in_days = msg.data.expires_in_days.clamp(1, MAX_EXPIRES_IN_DAYS);
+        let mut scopes = msg.data.scopes;
+        scopes.sort();
+        scopes.dedup();
+        let personal_token = PersonalAccessToken {
+            id: Uuid::new_v4(),
+            email: msg.email,
+            name: msg.data.name,
+            token_hash: hash_token(&token),
+            scopes,
+            created_at: current_time,
+            expires_at: current_time + Duration::days(expires_in_days),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        let dbex = self.clone();
+        let personal_token = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::insert_into(personal_access_tokens)
+                .values(&personal_token)
+                .execute(&conn)
+                .map(|_| personal_token)
+                .map_err(ServiceError::from)
+        })
+        .await??;
+        // usable right away on this instance, other instances load it with their next update
+        PERSONAL_TOKENS.insert(&personal_token, &msg.roles);
+        Ok(NewPersonalToken {
+            info: personal_token.into(),
+            token,
+        })
+    }
+}
+
+/// The tokens of a user that are neither revoked nor expired
+#[derive(Debug)]
+pub struct ListPersonalTokens {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<ListPersonalTokens> for DbExecutor {
+    type Result = Result<Vec<PersonalTokenInfo>, ServiceError>;
+
+    async fn handle(&self, msg: ListPersonalTokens) -> Self::Result {
+        use crate::schema::personal_access_tokens::dsl::{
+            created_at, email, expires_at, personal_access_tokens, revoked_at,
+        };
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let tokens: Vec<PersonalAccessToken> = personal_access_tokens
+                .filter(email.eq(&msg.email))
+                .filter(revoked_at.is_null())
+                .filter(expires_at.gt(Local::now().naive_local()))
+                .order(created_at.desc())
+                .load(&conn)?;
+            Ok(tokens.into_iter().map(Into::into).collect())
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct RevokePersonalToken {
+    pub email: String,
+    pub id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<RevokePersonalToken> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: RevokePersonalToken) -> Self::Result {
+        use crate::schema::personal_access_tokens::dsl::{
+            email, id, personal_access_tokens, revoked_at,
+        };
+
+        let token_id = msg.id;
+        let dbex = self.clone();
+        let revoked = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::update(
+                personal_access_tokens
+                    .filter(id.eq(token_id))
+                    .filter(email.eq(&msg.email))
+                    .filter(revoked_at.is_null()),
+            )
+            .set(revoked_at.eq(Local::now().naive_local()))
+            .execute(&conn)
+            .map(|updated| updated > 0)
+            .map_err(ServiceError::from)
+        })
+        .await??;
+        if revoked {
+            PERSONAL_TOKENS.remove(token_id);
+        }
+        Ok(revoked)
+    }
+}
+
+/// Revoke all active personal access tokens of a user, to be run inside the transaction that
+/// resets the credentials. The caller must remove the result from `PERSONAL_TOKENS` once the
+/// transaction committed.
+pub fn revoke_user_personal_tokens(conn: &PgConnection, email_: &str) -> QueryResult<Vec<Uuid>> {
+    use crate::schema::personal_access_tokens::dsl::{
+        email, id, personal_access_tokens, revoked_at,
+    };
+
+    diesel::update(
+        personal_access_tokens
+            .filter(email.eq(email_))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Local::now().naive_local()))
+    .returning(id)
+    .get_results(conn)
+}
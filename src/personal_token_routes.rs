@@ -0,0 +1,123 @@
+use actix_web::{
+    web::{Data, Json, Path},
+    Error, HttpResponse, ResponseError,
+};
+use maplit::hashmap;
+use uuid::Uuid;
+
+use crate::{
+    logged_user::{LoggedUser, SessionUser},
+    models::{DbExecutor, HandleRequest},
+    personal_token_handler::{
+        CreatePersonalToken, ListPersonalTokens, PersonalTokenData, RevokePersonalToken,
+    },
+};
+
+pub async fn list_tokens(
+    logged_user: LoggedUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ListPersonalTokens {
+        email: logged_user.email,
+    };
+    match db.handle(msg).await {
+        Ok(tokens) => Ok(HttpResponse::Ok().json(tokens)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn create_token(
+    session_user: SessionUser,
+    token_data: Json<PersonalTokenData>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    // a personal access token must not mint new ones, it could outlive its own expiry
+    let msg = CreatePersonalToken {
+        email: session_user.email,
+        roles: session_user.roles,
+        data: token_data.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(token) => Ok(HttpResponse::Ok().json(token)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn revoke_token(
+    logged_user: LoggedUser,
+    token_id: Path<Uuid>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = RevokePersonalToken {
+        email: logged_user.email,
+        id: token_id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            let result = hashmap! { "status" => status };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test::{self, TestRequest},
+        web, App,
+    };
+    use chrono::{Duration, Local};
+    use uuid::Uuid;
+
+    use crate::{
+        logged_user::{LoggedUser, AUTHORIZED_USERS, PERSONAL_TOKENS, PERSONAL_TOKEN_PREFIX},
+        models::PersonalAccessToken,
+        personal_token_routes::create_token,
+        rust_auth_server::{get_pool, load_config},
+        utils::{get_random_string, hash_token},
+    };
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_personal_token_cannot_create_tokens() {
+        load_config();
+        let pool = get_pool();
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .route("/api/tokens", web::post().to(create_token)),
+        )
+        .await;
+
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        AUTHORIZED_USERS
+            .store_auth(LoggedUser::from_email(email.clone()), true)
+            .unwrap();
+        let token = format!("{}{}", PERSONAL_TOKEN_PREFIX, get_random_string());
+        let current_time = Local::now().naive_local();
+        let personal_token = PersonalAccessToken {
+            id: Uuid::new_v4(),
+            email,
+            name: "ci".into(),
+            token_hash: hash_token(&token),
+            scopes: Vec::new(),
+            created_at: current_time,
+            expires_at: current_time + Duration::days(1),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        PERSONAL_TOKENS.insert(&personal_token, &[]);
+
+        let request = TestRequest::post()
+            .uri("/api/tokens")
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .set_json(&serde_json::json!({"name": "forever", "expires_in_days": 365}))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        PERSONAL_TOKENS.remove(personal_token.id);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
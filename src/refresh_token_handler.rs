@@ -0,0 +1,405 @@
+use actix_web::{cookie::SameSite, http::Cookie};
+use async_trait::async_trait;
+use chrono::Local;
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use log::warn;
+use std::env;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::{
+    errors::ServiceError,
+    logged_user::SESSIONS,
+    models::{DbExecutor, HandleRequest, RefreshToken, Session, SlimUser, User},
+    role_handler::get_user_roles,
+    session_handler::revoke_sessions,
+    utils::{get_random_token, hash_token, refresh_token_lifetime, Token},
+};
+
+pub const REFRESH_COOKIE: &str = "refresh";
+
+/// The refresh token cookie is only sent to the api, and never visible to javascript
+pub fn refresh_cookie(refresh_token: String) -> Cookie<'static> {
+    let domain = env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    Cookie::build(REFRESH_COOKIE, refresh_token)
+        .path("/api")
+        .domain(domain)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(time::Duration::seconds(
+            refresh_token_lifetime().num_seconds(),
+        ))
+        .finish()
+}
+
+fn new_refresh_token(email: String, family_id: Uuid) -> (String, RefreshToken) {
+    let token = get_random_token();
+    let current_time = Local::now().naive_local();
+    let refresh_token = RefreshToken {
+        id: Uuid::new_v4(),
+        family_id,
+        email,
+        token_hash: hash_token(&token),
+        created_at: current_time,
+        expires_at: current_time + refresh_token_lifetime(),
+        used_at: None,
+        revoked_at: None,
+    };
+    (token, refresh_token)
+}
+
+// start a new token family for a freshly created session
+#[derive(Debug)]
+pub struct IssueRefreshToken {
+    pub email: String,
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<IssueRefreshToken> for DbExecutor {
+    type Result = Result<String, ServiceError>;
+
+    async fn handle(&self, msg: IssueRefreshToken) -> Self::Result {
+        use crate::schema::refresh_tokens::dsl::refresh_tokens;
+
+        let (token, refresh_token) = new_refresh_token(msg.email, msg.session_id);
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::insert_into(refresh_tokens)
+                .values(&refresh_token)
+                .execute(&conn)
+                .map(|_| token)
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct RotateRefreshToken {
+    pub refresh_token: String,
+    // tokens of a session authorized for an oauth client are only accepted from that client
+    pub client_id: Option<String>,
+}
+
+enum Rotation {
+    // the user, the session, its roles or its scope for an oauth client, the refresh token
+    Rotated(SlimUser, Uuid, Vec<String>, Option<String>, String),
+    Invalid,
+    Reused(Uuid),
+}
+
+#[async_trait]
+impl HandleRequest<RotateRefreshToken> for DbExecutor {
+    type Result = Result<(SlimUser, Token, String), ServiceError>;
+
+    async fn handle(&self, msg: RotateRefreshToken) -> Self::Result {
+        use crate::schema::{refresh_tokens, sessions, users};
+
+        let token_hash_ = hash_token(&msg.refresh_token);
+        let client_id = msg.client_id.clone();
+        let dbex = self.clone();
+        let rotation = spawn_blocking(move || -> Result<Rotation, ServiceError> {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let current_time = Local::now().naive_local();
+                let existing = match refresh_tokens::table
+                    .filter(refresh_tokens::token_hash.eq(&token_hash_))
+                    .for_update()
+                    .first::<RefreshToken>(&conn)
+                    .optional()?
+                {
+                    Some(existing) => existing,
+                    None => return Ok(Rotation::Invalid),
+                };
+                if existing.revoked_at.is_some() || existing.expires_at < current_time {
+                    return Ok(Rotation::Invalid);
+                }
+                if existing.used_at.is_some() {
+                    // a rotated token was presented again, so either the client or an
+                    // attacker holds a stolen copy: revoke every token of the family
+                    diesel::update(
+                        refresh_tokens::table
+                            .filter(refresh_tokens::family_id.eq(existing.family_id))
+                            .filter(refresh_tokens::revoked_at.is_null()),
+                    )
+                    .set(refresh_tokens::revoked_at.eq(current_time))
+                    .execute(&conn)?;
+                    return Ok(Rotation::Reused(existing.family_id));
+                }
+                let (session_client, scope) = match sessions::table
+                    .find(existing.family_id)
+                    .select((sessions::client_id, sessions::scope))
+                    .first::<(Option<String>, Option<String>)>(&conn)
+                    .optional()?
+                {
+                    Some(session) => session,
+                    None => return Ok(Rotation::Invalid),
+                };
+                if session_client != msg.client_id {
+                    return Ok(Rotation::Invalid);
+                }
+                // the family id is the session, which might have been logged out meanwhile
+                let session_active = diesel::update(
+                    sessions::table
+                        .find(existing.family_id)
+                        .filter(sessions::revoked_at.is_null()),
+                )
+                .set(sessions::last_seen_at.eq(current_time))
+                .execute(&conn)?
+                    > 0;
+                if !session_active {
+                    return Ok(Rotation::Invalid);
+                }
+                let user = match users::table
+                    .find(&existing.email)
+                    .filter(users::disabled_at.is_null())
+                    .first::<User>(&conn)
+                    .optional()?
+                {
+                    Some(user) => user,
+                    None => return Ok(Rotation::Invalid),
+                };
+
+                diesel::update(refresh_tokens::table.find(existing.id))
+                    .set(refresh_tokens::used_at.eq(current_time))
+                    .execute(&conn)?;
+                let (token, refresh_token) =
+                    new_refresh_token(user.email.clone(), existing.family_id);
+                diesel::insert_into(refresh_tokens::table)
+                    .values(&refresh_token)
+                    .execute(&conn)?;
+                // roles are read again so changes apply from the next refresh on
+                let roles = get_user_roles(&conn, &user.email)?;
+                Ok(Rotation::Rotated(
+                    user.into(),
+                    existing.family_id,
+                    roles,
+                    scope,
+                    token,
+                ))
+            })
+        })
+        .await??;
+
+        match rotation {
+            Rotation::Rotated(user, session_id, roles, scope, refresh_token) => {
+                let token = match client_id {
+                    Some(client_id) => Token::create_client_token(
+                        &user,
+                        session_id,
+                        &client_id,
+                        scope.unwrap_or_default(),
+                    )?,
+                    None => Token::create_token(&user, session_id, roles)?,
+                };
+                Ok((user, token, refresh_token))
+            }
+            Rotation::Reused(family_id) => {
+                warn!("Refresh token reuse detected, revoked family {}", family_id);
+                Err(ServiceError::BadRequest("Invalid refresh token".into()))
+            }
+            Rotation::Invalid => Err(ServiceError::BadRequest("Invalid refresh token".into())),
+        }
+    }
+}
+
+/// The active session of a refresh token, to check which client it was issued to
+#[derive(Debug)]
+pub struct GetRefreshTokenSession {
+    pub refresh_token: String,
+}
+
+#[async_trait]
+impl HandleRequest<GetRefreshTokenSession> for DbExecutor {
+    type Result = Result<Option<Session>, ServiceError>;
+
+    async fn handle(&self, msg: GetRefreshTokenSession) -> Self::Result {
+        use crate::schema::{refresh_tokens, sessions};
+
+        let token_hash_ = hash_token(&msg.refresh_token);
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let family_id: Option<Uuid> = refresh_tokens::table
+                .filter(refresh_tokens::token_hash.eq(token_hash_))
+                .select(refresh_tokens::family_id)
+                .first(&conn)
+                .optional()?;
+            match family_id {
+                Some(family_id) => sessions::table
+                    .find(family_id)
+                    .filter(sessions::revoked_at.is_null())
+                    .first(&conn)
+                    .optional()
+                    .map_err(Into::into),
+                None => Ok(None),
+            }
+        })
+        .await?
+    }
+}
+
+// revoke the session of a refresh token together with its whole family, used on logout
+#[derive(Debug)]
+pub struct RevokeRefreshToken {
+    pub refresh_token: String,
+}
+
+#[async_trait]
+impl HandleRequest<RevokeRefreshToken> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: RevokeRefreshToken) -> Self::Result {
+        use crate::schema::refresh_tokens::dsl::{family_id, refresh_tokens, token_hash};
+
+        let token_hash_ = hash_token(&msg.refresh_token);
+        let dbex = self.clone();
+        let revoked = spawn_blocking(move || -> Result<Vec<Uuid>, ServiceError> {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let family: Vec<Uuid> = refresh_tokens
+                    .filter(token_hash.eq(token_hash_))
+                    .select(family_id)
+                    .load(&conn)?;
+                revoke_sessions(&conn, &family).map_err(Into::into)
+            })
+        })
+        .await??;
+        SESSIONS.revoke(&revoked);
+        Ok(!revoked.is_empty())
+    }
+}
+
+pub fn cleanup_refresh_tokens(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    use crate::schema::refresh_tokens::dsl::{expires_at, refresh_tokens};
+
+    let conn = pool.0.get()?;
+    diesel::delete(refresh_tokens.filter(expires_at.lt(Local::now().naive_local())))
+        .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use chrono::Local;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use uuid::Uuid;
+
+    use crate::{
+        models::{DbExecutor, HandleRequest, User},
+        oauth_client_handler::CreateOAuthClient,
+        refresh_token_handler::{IssueRefreshToken, RotateRefreshToken},
+        rust_auth_server::{get_pool, load_config},
+        session_handler::{CreateSession, RevokeSession},
+        utils::get_random_string,
+    };
+
+    // a new session and the first refresh token of its family
+    async fn start_session(
+        pool: &DbExecutor,
+        email: &str,
+        client_id: Option<&str>,
+    ) -> Result<(Uuid, String), Error> {
+        let session = pool
+            .handle(CreateSession {
+                email: email.into(),
+                user_agent: None,
+                ip_address: None,
+                client_id: client_id.map(Into::into),
+                auth_methods: vec!["pwd".into()],
+                scope: client_id.map(|_| "openid offline_access".into()),
+            })
+            .await?;
+        let msg = IssueRefreshToken {
+            email: email.into(),
+            session_id: session.id,
+        };
+        Ok((session.id, pool.handle(msg).await?))
+    }
+
+    // the next refresh token, None if the token was refused
+    async fn rotate(
+        pool: &DbExecutor,
+        refresh_token: &str,
+        client_id: Option<&str>,
+    ) -> Option<String> {
+        let msg = RotateRefreshToken {
+            refresh_token: refresh_token.into(),
+            client_id: client_id.map(Into::into),
+        };
+        pool.handle(msg)
+            .await
+            .ok()
+            .map(|(_, _, refresh_token)| refresh_token)
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_rotate_refresh_token() -> Result<(), Error> {
+        use crate::schema::{oauth_clients, users};
+
+        load_config();
+        let pool = get_pool();
+        let conn = pool.0.get()?;
+        let email = format!("{}@example.com", get_random_string().to_lowercase());
+        diesel::insert_into(users::table)
+            .values(&User::from_details(email.clone(), String::new()))
+            .execute(&conn)?;
+        let msg = CreateOAuthClient {
+            name: "test refresh".into(),
+            redirect_uris: Vec::new(),
+            public_client: true,
+        };
+        let client_id = pool.handle(msg).await?.client.client_id;
+
+        // each token is good for one rotation, presenting it again revokes the whole family
+        let (_, first) = start_session(&pool, &email, None).await?;
+        let second = rotate(&pool, &first, None).await;
+        let reused = rotate(&pool, &first, None).await;
+        let after_reuse = match &second {
+            Some(second) => rotate(&pool, second, None).await,
+            None => None,
+        };
+
+        let (session_id, logged_out) = start_session(&pool, &email, None).await?;
+        let msg = RevokeSession {
+            email: email.clone(),
+            session_id,
+        };
+        pool.handle(msg).await?;
+        let revoked_session = rotate(&pool, &logged_out, None).await;
+
+        // tokens of a client session are only accepted from that client
+        let (_, client_token) = start_session(&pool, &email, Some(&client_id)).await?;
+        let without_client = rotate(&pool, &client_token, None).await;
+        let other_client = rotate(&pool, &client_token, Some("other-client")).await;
+        let same_client = rotate(&pool, &client_token, Some(&client_id)).await;
+        let (_, first_party) = start_session(&pool, &email, None).await?;
+        let first_party_as_client = rotate(&pool, &first_party, Some(&client_id)).await;
+
+        let (_, disabled) = start_session(&pool, &email, None).await?;
+        diesel::update(users::table.find(&email))
+            .set(users::disabled_at.eq(Local::now().naive_local()))
+            .execute(&conn)?;
+        let disabled_user = rotate(&pool, &disabled, None).await;
+
+        diesel::delete(users::table.filter(users::email.eq(&email))).execute(&conn)?;
+        diesel::delete(oauth_clients::table.filter(oauth_clients::client_id.eq(&client_id)))
+            .execute(&conn)?;
+
+        assert!(second.is_some());
+        assert_ne!(second.as_ref(), Some(&first));
+        assert!(reused.is_none());
+        assert!(after_reuse.is_none());
+        assert!(revoked_session.is_none());
+        assert!(without_client.is_none());
+        assert!(other_client.is_none());
+        assert!(same_client.is_some());
+        assert!(first_party_as_client.is_none());
+        assert!(disabled_user.is_none());
+        Ok(())
+    }
+}
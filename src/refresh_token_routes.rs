@@ -0,0 +1,64 @@
+use actix_identity::Identity;
+use actix_web::{
+    web::{Data, Json},
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, HandleRequest},
+    refresh_token_handler::{refresh_cookie, RotateRefreshToken, REFRESH_COOKIE},
+    utils::access_token_lifetime,
+};
+
+// api clients send the refresh token in the body, browsers in the refresh cookie
+#[derive(Debug, Deserialize)]
+pub struct RefreshData {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RefreshResponse {
+    email: String,
+    expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+pub async fn refresh(
+    request: HttpRequest,
+    refresh_data: Option<Json<RefreshData>>,
+    id: Identity,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let body_token = refresh_data.and_then(|data| data.into_inner().refresh_token);
+    let from_body = body_token.is_some();
+    let refresh_token = body_token
+        .or_else(|| {
+            request
+                .cookie(REFRESH_COOKIE)
+                .map(|c| c.value().to_string())
+        })
+        .ok_or_else(|| ServiceError::BadRequest("Missing refresh token".into()))?;
+
+    let (user, token, refresh_token) = db
+        .handle(RotateRefreshToken {
+            refresh_token,
+            client_id: None,
+        })
+        .await?;
+    id.remember(token.into());
+    let response = RefreshResponse {
+        email: user.email,
+        expires_in: access_token_lifetime().num_seconds(),
+        refresh_token: if from_body {
+            Some(refresh_token.clone())
+        } else {
+            None
+        },
+    };
+    Ok(HttpResponse::Ok()
+        .cookie(refresh_cookie(refresh_token))
+        .json(response))
+}
@@ -1,6 +1,8 @@
 use async_trait::async_trait;
 use chrono::Local;
-use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+};
 use serde::{Deserialize, Serialize};
 use tokio::task::spawn_blocking;
 use uuid::Uuid;
@@ -8,7 +10,7 @@ use uuid::Uuid;
 use crate::{
     errors::ServiceError,
     logged_user::TRIGGER_DB_UPDATE,
-    models::{DbExecutor, HandleRequest, Invitation, SlimUser, User},
+    models::{DbExecutor, HandleRequest, Invitation, InvitationStatus, SlimUser, User},
     utils::hash_password,
 };
 
@@ -30,7 +32,7 @@ impl HandleRequest<RegisterUser> for DbExecutor {
     type Result = Result<SlimUser, ServiceError>;
     async fn handle(&self, msg: RegisterUser) -> Self::Result {
         use crate::schema::{
-            invitations::dsl::{id, invitations},
+            invitations::dsl::{accepted_at, invitations},
             users::dsl::users,
         };
 
@@ -41,27 +43,39 @@ impl HandleRequest<RegisterUser> for DbExecutor {
         let dbex = self.clone();
         spawn_blocking(move || {
             let conn = dbex.0.get()?;
+            // try hashing the password, else return the error that will be converted to
+            // ServiceError
+            let password: String = hash_password(&msg.password)?;
 
-            invitations
-                .filter(id.eq(invitation_id))
-                .load::<Invitation>(&conn)
-                .map_err(|_db_error| ServiceError::BadRequest("Invalid Invitation".into()))
-                .and_then(|mut result| {
-                    if let Some(invitation) = result.pop() {
-                        // if invitation is not expired
-                        if invitation.expires_at > Local::now().naive_local() {
-                            // try hashing the password, else return the error that will be
-                            // converted to ServiceError
-                            let password: String = hash_password(&msg.password)?;
-                            let user = User::from_details(invitation.email, password);
-                            let inserted_user: User =
-                                diesel::insert_into(users).values(&user).get_result(&conn)?;
-                            TRIGGER_DB_UPDATE.set();
-                            return Ok(inserted_user.into());
-                        }
+            let inserted_user = conn.transaction(|| -> Result<User, ServiceError> {
+                // lock the invitation so that concurrent requests cannot both use it
+                let invitation: Invitation = invitations
+                    .find(invitation_id)
+                    .for_update()
+                    .first(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest("Invalid Invitation".into()))?;
+                let current_time = Local::now().naive_local();
+                match invitation.status(current_time) {
+                    InvitationStatus::Pending => {}
+                    InvitationStatus::Accepted => {
+                        return Err(ServiceError::BadRequest("Invitation already used".into()))
+                    }
+                    InvitationStatus::Revoked | InvitationStatus::Expired => {
+                        return Err(ServiceError::BadRequest("Invalid Invitation".into()))
                     }
-                    Err(ServiceError::BadRequest("Invalid Invitation".into()))
-                })
+                }
+                diesel::update(invitations.find(invitation_id))
+                    .set(accepted_at.eq(current_time))
+                    .execute(&conn)?;
+                let user = User::from_details(invitation.email, password);
+                diesel::insert_into(users)
+                    .values(&user)
+                    .get_result(&conn)
+                    .map_err(Into::into)
+            })?;
+            TRIGGER_DB_UPDATE.set();
+            Ok(inserted_user.into())
         })
         .await?
     }
@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+use chrono::Local;
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, QueryResult, RunQueryDsl};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, HandleRequest, Role, UserRole},
+};
+
+/// Roles of a user, as carried in the access token
+pub fn get_user_roles(conn: &PgConnection, email_: &str) -> QueryResult<Vec<String>> {
+    use crate::schema::user_roles::dsl::{email, role, user_roles};
+
+    user_roles
+        .filter(email.eq(email_))
+        .select(role)
+        .order(role.asc())
+        .load(conn)
+}
+
+/// Grant a role to a user, creating the role if it does not exist yet.
+/// Returns false if the user already had it.
+pub fn grant_role(conn: &PgConnection, email: &str, role: &str) -> QueryResult<bool> {
+    use crate::schema::{roles, user_roles};
+
+    let current_time = Local::now().naive_local();
+    diesel::insert_into(roles::table)
+        .values(&Role {
+            name: role.into(),
+            description: String::new(),
+            created_at: current_time,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    diesel::insert_into(user_roles::table)
+        .values(&UserRole {
+            email: email.into(),
+            role: role.into(),
+            created_at: current_time,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .map(|inserted| inserted > 0)
+}
+
+pub fn revoke_role(conn: &PgConnection, email_: &str, role_: &str) -> QueryResult<bool> {
+    use crate::schema::user_roles::dsl::{email, role, user_roles};
+
+    diesel::delete(user_roles.filter(email.eq(email_)).filter(role.eq(role_)))
+        .execute(conn)
+        .map(|deleted| deleted > 0)
+}
+
+#[derive(Debug)]
+pub struct GetUserRoles {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<GetUserRoles> for DbExecutor {
+    type Result = Result<Vec<String>, ServiceError>;
+
+    async fn handle(&self, msg: GetUserRoles) -> Self::Result {
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            get_user_roles(&conn, &msg.email).map_err(Into::into)
+        })
+        .await?
+    }
+}
@@ -1,33 +1,47 @@
 use actix::{Addr, SyncArbiter};
 use actix_identity::{CookieIdentityPolicy, IdentityService};
 use actix_web::{middleware::Logger, web, App, HttpServer};
-use anyhow::Error;
+use anyhow::{format_err, Error};
 use diesel::{r2d2::ConnectionManager, PgConnection};
 use dotenv::dotenv;
-use std::{env, path::Path, sync::Arc, time::Duration};
-use tokio::{sync::RwLock, time::interval};
+use std::{env, path::Path, time::Duration};
+use tokio::time::interval;
 
 use crate::{
-    auth_routes, change_password_routes,
-    google_openid::{self, cleanup_token_map, get_google_client, GoogleClient},
+    admin_routes, auth_routes, change_password_routes,
+    forward_auth::ForwardAuthConfig,
+    forward_auth_routes, identity_routes,
+    invitation_handler::cleanup_invitations,
     invitation_routes,
-    logged_user::{fill_auth_from_db, TRIGGER_DB_UPDATE},
+    jwt_keys::{reload_jwt_keys, JWT_KEYS},
+    logged_user::{
+        fill_auth_from_db, fill_personal_tokens_from_db, fill_sessions_from_db, TRIGGER_DB_UPDATE,
+    },
+    mfa_handler::cleanup_mfa_pending,
+    mfa_routes,
     models::DbExecutor,
-    register_routes,
-    static_files::{change_password, index_html, login_html, main_css, main_js, register_html},
+    oauth_handler::cleanup_oauth_codes,
+    oauth_routes,
+    openid_providers::ProviderRegistry,
+    openid_routes, password_reset_routes,
+    pending_auth_handler::PendingAuthStore,
+    personal_token_routes,
+    refresh_token_handler::cleanup_refresh_tokens,
+    refresh_token_routes, register_routes,
+    role_handler::{get_user_roles, grant_role, revoke_role},
+    service_account_handler::cleanup_service_account_secrets,
+    session_handler::cleanup_sessions,
+    session_routes,
+    static_files::{
+        change_password, consent_html, device_html, index_html, login_html, main_css, main_js,
+        passkeys_html, register_html, reset_password,
+    },
+    utils::access_token_lifetime,
+    webauthn_handler::cleanup_webauthn_challenges,
+    webauthn_routes,
 };
 
-pub async fn run_auth_server(port: u32) -> Result<(), Error> {
-    async fn _update_db(pool: DbExecutor) {
-        let mut i = interval(Duration::from_secs(60));
-        loop {
-            fill_auth_from_db(&pool).unwrap_or(());
-            cleanup_token_map().await;
-            i.tick().await;
-        }
-    }
-    TRIGGER_DB_UPDATE.set();
-
+pub fn load_config() {
     let config_dir = dirs::config_dir().expect("No CONFIG directory");
     let env_file = config_dir.join("rust_auth_server").join("config.env");
 
@@ -38,19 +52,80 @@ pub async fn run_auth_server(port: u32) -> Result<(), Error> {
     } else {
         dotenv::dotenv().ok();
     }
+}
 
-    env_logger::init();
-
+pub(crate) fn get_pool() -> DbExecutor {
     let database_url = std::env::var("AUTHDB").expect("DATABASE_URL must be set");
 
     // create db connection pool
     let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool = DbExecutor(
+    DbExecutor(
         r2d2::Pool::builder()
             .build(manager)
             .expect("Failed to create pool."),
-    );
-    let openid = Arc::new(get_google_client().await?);
+    )
+}
+
+const ROLES_USAGE: &str = "usage: roles list <email>
+       roles grant <email> <role>
+       roles revoke <email> <role>";
+
+/// Admin command to grant roles, mainly to bootstrap the first admin
+pub fn run_roles_command(args: &[String]) -> Result<(), Error> {
+    let conn = get_pool().0.get()?;
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["list", email] => println!("{}", get_user_roles(&conn, email)?.join(" ")),
+        ["grant", email, role] => {
+            if !grant_role(&conn, email, role)? {
+                println!("{} already has role {}", email, role);
+            }
+        }
+        ["revoke", email, role] => {
+            if !revoke_role(&conn, email, role)? {
+                println!("{} does not have role {}", email, role);
+            }
+        }
+        _ => return Err(format_err!("{}", ROLES_USAGE)),
+    }
+    Ok(())
+}
+
+pub async fn run_auth_server(port: u32) -> Result<(), Error> {
+    async fn _update_db(pool: DbExecutor) {
+        let mut i = interval(Duration::from_secs(60));
+        loop {
+            fill_auth_from_db(&pool).unwrap_or(());
+            reload_jwt_keys();
+            fill_sessions_from_db(&pool).unwrap_or(());
+            fill_personal_tokens_from_db(&pool).unwrap_or(());
+            cleanup_refresh_tokens(&pool).unwrap_or(());
+            cleanup_sessions(&pool).unwrap_or(());
+            cleanup_invitations(&pool).unwrap_or(());
+            pool.cleanup_pending_auths().await.unwrap_or(());
+            cleanup_oauth_codes(&pool).unwrap_or(());
+            cleanup_service_account_secrets(&pool).unwrap_or(());
+            cleanup_mfa_pending();
+            cleanup_webauthn_challenges();
+            i.tick().await;
+        }
+    }
+    TRIGGER_DB_UPDATE.set();
+
+    load_config();
+
+    env_logger::init();
+
+    // fail at startup rather than on the first login if the signing key is unusable
+    lazy_static::initialize(&JWT_KEYS);
+
+    let pool = get_pool();
+    let providers = web::Data::new(ProviderRegistry::from_env()?);
+    let forward_auth_rules = web::Data::new(ForwardAuthConfig::from_env()?);
 
     actix_rt::spawn(_update_db(pool.clone()));
 
@@ -61,14 +136,15 @@ pub async fn run_auth_server(port: u32) -> Result<(), Error> {
 
         App::new()
             .data(pool.clone())
-            .data(RwLock::new(openid.clone()))
+            .app_data(providers.clone())
+            .app_data(forward_auth_rules.clone())
             .wrap(Logger::default())
             .wrap(IdentityService::new(
                 CookieIdentityPolicy::new(secret.as_bytes())
                     .name("auth")
                     .path("/")
                     .domain(domain.as_str())
-                    .max_age(24*3600)
+                    .max_age(access_token_lifetime().num_seconds())
                     .secure(false), // this can only be true if you have https
             ))
             // everything under '/api/' route
@@ -81,6 +157,126 @@ pub async fn run_auth_server(port: u32) -> Result<(), Error> {
                             .route(web::delete().to(auth_routes::logout))
                             .route(web::get().to(auth_routes::get_me)),
                     )
+                    .service(web::resource("/auth/mfa").route(web::post().to(mfa_routes::login)))
+                    .service(
+                        web::resource("/token/refresh")
+                            .route(web::post().to(refresh_token_routes::refresh)),
+                    )
+                    .service(
+                        web::resource("/sessions")
+                            .route(web::get().to(session_routes::list_sessions))
+                            .route(web::delete().to(session_routes::revoke_other_sessions)),
+                    )
+                    .service(
+                        web::resource("/sessions/{session_id}")
+                            .route(web::delete().to(session_routes::revoke_session)),
+                    )
+                    .service(
+                        web::resource("/tokens")
+                            .route(web::get().to(personal_token_routes::list_tokens))
+                            .route(web::post().to(personal_token_routes::create_token)),
+                    )
+                    .service(
+                        web::resource("/tokens/{token_id}")
+                            .route(web::delete().to(personal_token_routes::revoke_token)),
+                    )
+                    .service(
+                        web::resource("/mfa/enroll").route(web::post().to(mfa_routes::enroll)),
+                    )
+                    .service(
+                        web::resource("/mfa/confirm").route(web::post().to(mfa_routes::confirm)),
+                    )
+                    .service(
+                        web::resource("/mfa/disable").route(web::post().to(mfa_routes::disable)),
+                    )
+                    .service(
+                        web::resource("/webauthn/register/start")
+                            .route(web::post().to(webauthn_routes::register_start)),
+                    )
+                    .service(
+                        web::resource("/webauthn/register/finish")
+                            .route(web::post().to(webauthn_routes::register_finish)),
+                    )
+                    .service(
+                        web::resource("/webauthn/login/start")
+                            .route(web::post().to(webauthn_routes::login_start)),
+                    )
+                    .service(
+                        web::resource("/webauthn/login/finish")
+                            .route(web::post().to(webauthn_routes::login_finish)),
+                    )
+                    .service(
+                        web::resource("/webauthn/credentials")
+                            .route(web::get().to(webauthn_routes::list_credentials)),
+                    )
+                    .service(
+                        web::resource("/webauthn/credentials/{credential_id}")
+                            .route(web::delete().to(webauthn_routes::delete_credential)),
+                    )
+                    // admin only user management
+                    .service(
+                        web::resource("/admin/users")
+                            .route(web::get().to(admin_routes::list_users)),
+                    )
+                    .service(
+                        web::resource("/admin/users/{email}")
+                            .route(web::get().to(admin_routes::get_user))
+                            .route(web::delete().to(admin_routes::delete_user)),
+                    )
+                    .service(
+                        web::resource("/admin/users/{email}/disable")
+                            .route(web::post().to(admin_routes::disable_user)),
+                    )
+                    .service(
+                        web::resource("/admin/users/{email}/enable")
+                            .route(web::post().to(admin_routes::enable_user)),
+                    )
+                    .service(
+                        web::resource("/admin/users/{email}/password_reset")
+                            .route(web::post().to(admin_routes::force_password_reset)),
+                    )
+                    .service(
+                        web::resource("/admin/users/{email}/roles")
+                            .route(web::put().to(admin_routes::set_roles)),
+                    )
+                    .service(
+                        web::resource("/admin/invitations")
+                            .route(web::get().to(admin_routes::list_invitations)),
+                    )
+                    .service(
+                        web::resource("/admin/invitations/{id}")
+                            .route(web::delete().to(admin_routes::revoke_invitation)),
+                    )
+                    .service(
+                        web::resource("/admin/invitations/{id}/resend")
+                            .route(web::post().to(admin_routes::resend_invitation)),
+                    )
+                    .service(
+                        web::resource("/admin/oauth_clients")
+                            .route(web::get().to(admin_routes::list_oauth_clients))
+                            .route(web::post().to(admin_routes::create_oauth_client)),
+                    )
+                    .service(
+                        web::resource("/admin/oauth_clients/{client_id}")
+                            .route(web::delete().to(admin_routes::delete_oauth_client)),
+                    )
+                    .service(
+                        web::resource("/admin/service_accounts")
+                            .route(web::get().to(admin_routes::list_service_accounts))
+                            .route(web::post().to(admin_routes::create_service_account)),
+                    )
+                    .service(
+                        web::resource("/admin/service_accounts/{account_id}/disable")
+                            .route(web::post().to(admin_routes::disable_service_account)),
+                    )
+                    .service(
+                        web::resource("/admin/service_accounts/{account_id}/enable")
+                            .route(web::post().to(admin_routes::enable_service_account)),
+                    )
+                    .service(
+                        web::resource("/admin/service_accounts/{account_id}/secrets")
+                            .route(web::post().to(admin_routes::rotate_service_account_secret)),
+                    )
                     // routes to invitation
                     .service(
                         web::resource("/invitation")
@@ -96,12 +292,88 @@ pub async fn run_auth_server(port: u32) -> Result<(), Error> {
                             .route(web::post().to(change_password_routes::change_password_user)),
                     )
                     .service(
-                        web::resource("/auth_url").route(web::post().to(google_openid::auth_url)),
+                        web::resource("/password_reset")
+                            .route(web::post().to(password_reset_routes::request_password_reset)),
+                    )
+                    .service(
+                        web::resource("/password_reset/{reset_id}")
+                            .route(web::post().to(password_reset_routes::reset_password)),
+                    )
+                    .service(
+                        web::resource("/forward_auth")
+                            .route(web::get().to(forward_auth_routes::forward_auth)),
+                    )
+                    .service(
+                        web::resource("/forward_auth/return")
+                            .route(web::get().to(forward_auth_routes::forward_auth_return)),
+                    )
+                    .service(
+                        web::resource("/oauth/consent").route(web::post().to(oauth_routes::consent)),
                     )
                     .service(
-                        web::resource("/callback").route(web::get().to(google_openid::callback)),
+                        web::resource("/oauth/clients/{client_id}")
+                            .route(web::get().to(oauth_routes::get_client)),
+                    )
+                    .service(
+                        web::resource("/oauth/device")
+                            .route(web::get().to(oauth_routes::device_info))
+                            .route(web::post().to(oauth_routes::device_approve)),
+                    )
+                    .service(
+                        web::resource("/identities")
+                            .route(web::get().to(identity_routes::list_identities)),
+                    )
+                    .service(
+                        web::resource("/identities/link")
+                            .route(web::post().to(identity_routes::link_identity)),
+                    )
+                    .service(
+                        web::resource("/identities/{provider}")
+                            .route(web::delete().to(identity_routes::unlink_identity)),
+                    )
+                    .service(
+                        web::resource("/auth_providers")
+                            .route(web::get().to(openid_routes::auth_providers)),
+                    )
+                    .service(
+                        web::resource("/auth_url").route(web::post().to(openid_routes::auth_url)),
+                    )
+                    .service(
+                        web::resource("/callback").route(web::get().to(openid_routes::callback)),
+                    )
+                    .service(
+                        web::resource("/callback/{provider}")
+                            .route(web::get().to(openid_routes::callback_provider)),
                     ),
             )
+            .service(
+                web::resource("/.well-known/jwks.json").route(web::get().to(auth_routes::jwks)),
+            )
+            .service(
+                web::resource("/.well-known/openid-configuration")
+                    .route(web::get().to(oauth_routes::openid_configuration)),
+            )
+            // endpoints for other services, authenticated with client credentials
+            .service(
+                web::scope("/oauth")
+                    .service(
+                        web::resource("/authorize").route(web::get().to(oauth_routes::authorize)),
+                    )
+                    .service(web::resource("/token").route(web::post().to(oauth_routes::token)))
+                    .service(
+                        web::resource("/device_authorization")
+                            .route(web::post().to(oauth_routes::device_authorization)),
+                    )
+                    .service(
+                        web::resource("/userinfo")
+                            .route(web::get().to(oauth_routes::userinfo))
+                            .route(web::post().to(oauth_routes::userinfo)),
+                    )
+                    .service(
+                        web::resource("/introspect").route(web::post().to(oauth_routes::introspect)),
+                    )
+                    .service(web::resource("/revoke").route(web::post().to(oauth_routes::revoke))),
+            )
             // serve static files
             .service(
                 web::scope("/auth")
@@ -113,10 +385,16 @@ pub async fn run_auth_server(port: u32) -> Result<(), Error> {
                     .service(
                         web::resource("/change_password.html")
                             .route(web::get().to(change_password)),
-                    ),
+                    )
+                    .service(
+                        web::resource("/reset_password.html").route(web::get().to(reset_password)),
+                    )
+                    .service(web::resource("/passkeys.html").route(web::get().to(passkeys_html)))
+                    .service(web::resource("/consent.html").route(web::get().to(consent_html)))
+                    .service(web::resource("/device.html").route(web::get().to(device_html))),
             )
     })
-    .bind(&format!("127.0.0.1:{}", port))?
+    .bind(format!("127.0.0.1:{}", port))?
     .run()
     .await?;
     Ok(())
@@ -3,6 +3,185 @@ table! {
         id -> Uuid,
         email -> Varchar,
         expires_at -> Timestamp,
+        created_at -> Timestamp,
+        accepted_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        invited_by -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    oauth_clients (client_id) {
+        client_id -> Varchar,
+        name -> Varchar,
+        secret_hash -> Varchar,
+        created_at -> Timestamp,
+        disabled_at -> Nullable<Timestamp>,
+        redirect_uris -> Array<Text>,
+        public_client -> Bool,
+    }
+}
+
+table! {
+    oauth_codes (code_hash) {
+        code_hash -> Varchar,
+        client_id -> Varchar,
+        email -> Varchar,
+        redirect_uri -> Nullable<Text>,
+        scope -> Text,
+        code_challenge -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        nonce -> Nullable<Text>,
+        auth_time -> Timestamp,
+        auth_methods -> Array<Text>,
+    }
+}
+
+table! {
+    oauth_consents (email, client_id) {
+        email -> Varchar,
+        client_id -> Varchar,
+        scope -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    oauth_device_codes (device_code_hash) {
+        device_code_hash -> Varchar,
+        user_code -> Varchar,
+        client_id -> Varchar,
+        scope -> Text,
+        poll_interval -> Int4,
+        last_polled_at -> Nullable<Timestamp>,
+        email -> Nullable<Varchar>,
+        auth_time -> Nullable<Timestamp>,
+        auth_methods -> Array<Text>,
+        denied -> Bool,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Uuid,
+        email -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    pending_auths (state_hash) {
+        state_hash -> Varchar,
+        provider -> Varchar,
+        link_to -> Nullable<Varchar>,
+        nonce -> Varchar,
+        code_verifier -> Varchar,
+        final_url -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    personal_access_tokens (id) {
+        id -> Uuid,
+        email -> Varchar,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        family_id -> Uuid,
+        email -> Varchar,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    roles (name) {
+        name -> Varchar,
+        description -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    service_account_secrets (secret_hash) {
+        secret_hash -> Varchar,
+        account_id -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    service_accounts (account_id) {
+        account_id -> Varchar,
+        description -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        disabled_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Uuid,
+        email -> Varchar,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        client_id -> Nullable<Varchar>,
+        auth_methods -> Array<Text>,
+        scope -> Nullable<Text>,
+    }
+}
+
+table! {
+    user_identities (provider, subject) {
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Varchar,
+        provider_email -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_login_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_mfa (email) {
+        email -> Varchar,
+        totp_secret -> Varchar,
+        enabled -> Bool,
+        last_used_step -> Nullable<Int8>,
+        recovery_codes -> Array<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_roles (email, role) {
+        email -> Varchar,
+        role -> Varchar,
+        created_at -> Timestamp,
     }
 }
 
@@ -11,7 +190,59 @@ table! {
         email -> Varchar,
         password -> Varchar,
         created_at -> Timestamp,
+        disabled_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webauthn_credentials (id) {
+        id -> Varchar,
+        email -> Varchar,
+        name -> Varchar,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
     }
 }
 
-allow_tables_to_appear_in_same_query!(invitations, users,);
+joinable!(oauth_codes -> oauth_clients (client_id));
+joinable!(oauth_codes -> users (email));
+joinable!(oauth_consents -> oauth_clients (client_id));
+joinable!(oauth_consents -> users (email));
+joinable!(oauth_device_codes -> oauth_clients (client_id));
+joinable!(oauth_device_codes -> users (email));
+joinable!(password_resets -> users (email));
+joinable!(pending_auths -> users (link_to));
+joinable!(personal_access_tokens -> users (email));
+joinable!(refresh_tokens -> sessions (family_id));
+joinable!(refresh_tokens -> users (email));
+joinable!(service_account_secrets -> service_accounts (account_id));
+joinable!(sessions -> oauth_clients (client_id));
+joinable!(sessions -> users (email));
+joinable!(user_identities -> users (email));
+joinable!(user_mfa -> users (email));
+joinable!(user_roles -> roles (role));
+joinable!(user_roles -> users (email));
+joinable!(webauthn_credentials -> users (email));
+
+allow_tables_to_appear_in_same_query!(
+    invitations,
+    oauth_clients,
+    oauth_codes,
+    oauth_consents,
+    oauth_device_codes,
+    password_resets,
+    pending_auths,
+    personal_access_tokens,
+    refresh_tokens,
+    roles,
+    service_account_secrets,
+    service_accounts,
+    sessions,
+    user_identities,
+    user_mfa,
+    user_roles,
+    users,
+    webauthn_credentials,
+);
@@ -0,0 +1,395 @@
+use async_trait::async_trait;
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, HandleRequest, ServiceAccount, ServiceAccountSecret},
+    utils::{get_random_token, hash_token},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretInfo {
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceAccountInfo {
+    pub account_id: String,
+    pub description: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+    // the secrets that are currently accepted, newest first
+    pub secrets: Vec<SecretInfo>,
+}
+
+impl ServiceAccountInfo {
+    fn new(account: ServiceAccount, secrets: Vec<SecretInfo>) -> Self {
+        Self {
+            account_id: account.account_id,
+            description: account.description,
+            scopes: account.scopes,
+            created_at: account.created_at,
+            disabled_at: account.disabled_at,
+            secrets,
+        }
+    }
+}
+
+// account ids cannot contain an `@`, so tokens of an account are never mistaken for a user's
+fn check_account_id(account_id: &str) -> Result<(), ServiceError> {
+    let valid = !account_id.is_empty()
+        && account_id.len() <= 64
+        && account_id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ServiceError::BadRequest(format!(
+            "Invalid account id {}, use lowercase letters, digits, - and _",
+            account_id
+        )))
+    }
+}
+
+// a scope-token of RFC 6749 section 3.3
+fn is_scope_token(scope: &str) -> bool {
+    !scope.is_empty()
+        && scope
+            .chars()
+            .all(|c| c == '!' || ('#'..='[').contains(&c) || (']'..='~').contains(&c))
+}
+
+/// The scope of a client credentials token: the requested scopes if the account may have all
+/// of them, every scope of the account if none were requested
+pub fn grant_scope(requested: Option<&str>, allowed: &[String]) -> Option<String> {
+    let mut scopes: Vec<&str> = match requested {
+        Some(requested) if !requested.trim().is_empty() => requested.split_whitespace().collect(),
+        _ => allowed.iter().map(String::as_str).collect(),
+    };
+    if !scopes
+        .iter()
+        .all(|scope| allowed.iter().any(|a| a == scope))
+    {
+        return None;
+    }
+    scopes.sort_unstable();
+    scopes.dedup();
+    Some(scopes.join(" "))
+}
+
+fn new_secret(account_id: String) -> (String, ServiceAccountSecret) {
+    let secret = get_random_token();
+    let account_secret = ServiceAccountSecret {
+        secret_hash: hash_token(&secret),
+        account_id,
+        created_at: Local::now().naive_local(),
+        expires_at: None,
+    };
+    (secret, account_secret)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateServiceAccount {
+    pub account_id: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+// the only time the secret is available
+#[derive(Debug, Serialize)]
+pub struct NewServiceAccountSecret {
+    pub account_id: String,
+    pub client_secret: String,
+}
+
+#[async_trait]
+impl HandleRequest<CreateServiceAccount> for DbExecutor {
+    type Result = Result<NewServiceAccountSecret, ServiceError>;
+
+    async fn handle(&self, msg: CreateServiceAccount) -> Self::Result {
+        use crate::schema::{service_account_secrets, service_accounts};
+
+        check_account_id(&msg.account_id)?;
+        if let Some(scope) = msg.scopes.iter().find(|scope| !is_scope_token(scope)) {
+            return Err(ServiceError::BadRequest(format!("Invalid scope {}", scope)));
+        }
+        let account = ServiceAccount {
+            account_id: msg.account_id,
+            description: msg.description,
+            scopes: msg.scopes,
+            created_at: Local::now().naive_local(),
+            disabled_at: None,
+        };
+        let (client_secret, account_secret) = new_secret(account.account_id.clone());
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let exists = service_accounts::table
+                    .find(&account.account_id)
+                    .select(service_accounts::account_id)
+                    .first::<String>(&conn)
+                    .optional()?
+                    .is_some();
+                if exists {
+                    return Err(ServiceError::BadRequest(
+                        "Service account already exists".into(),
+                    ));
+                }
+                diesel::insert_into(service_accounts::table)
+                    .values(&account)
+                    .execute(&conn)?;
+                diesel::insert_into(service_account_secrets::table)
+                    .values(&account_secret)
+                    .execute(&conn)?;
+                Ok(NewServiceAccountSecret {
+                    account_id: account.account_id,
+                    client_secret,
+                })
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct ListServiceAccounts;
+
+#[async_trait]
+impl HandleRequest<ListServiceAccounts> for DbExecutor {
+    type Result = Result<Vec<ServiceAccountInfo>, ServiceError>;
+
+    async fn handle(&self, _: ListServiceAccounts) -> Self::Result {
+        use crate::schema::{service_account_secrets, service_accounts};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let accounts: Vec<ServiceAccount> = service_accounts::table
+                .order(service_accounts::account_id.asc())
+                .load(&conn)?;
+            let secrets: Vec<ServiceAccountSecret> = service_account_secrets::table
+                .filter(
+                    service_account_secrets::expires_at
+                        .is_null()
+                        .or(service_account_secrets::expires_at.gt(Local::now().naive_local())),
+                )
+                .order(service_account_secrets::created_at.desc())
+                .load(&conn)?;
+            Ok(accounts
+                .into_iter()
+                .map(|account| {
+                    let account_secrets = secrets
+                        .iter()
+                        .filter(|secret| secret.account_id == account.account_id)
+                        .map(|secret| SecretInfo {
+                            created_at: secret.created_at,
+                            expires_at: secret.expires_at,
+                        })
+                        .collect();
+                    ServiceAccountInfo::new(account, account_secrets)
+                })
+                .collect())
+        })
+        .await?
+    }
+}
+
+/// Disabling an account stops it from getting new tokens, its issued tokens stop being active
+/// for introspection
+#[derive(Debug)]
+pub struct SetServiceAccountDisabled {
+    pub account_id: String,
+    pub disabled: bool,
+}
+
+#[async_trait]
+impl HandleRequest<SetServiceAccountDisabled> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: SetServiceAccountDisabled) -> Self::Result {
+        use crate::schema::service_accounts::dsl::{disabled_at, service_accounts};
+
+        let value = if msg.disabled {
+            Some(Local::now().naive_local())
+        } else {
+            None
+        };
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::update(service_accounts.find(&msg.account_id))
+                .set(disabled_at.eq(value))
+                .execute(&conn)
+                .map(|updated| updated > 0)
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+/// Add a new secret, the current ones stay valid for `overlap` so deployments can switch over
+#[derive(Debug)]
+pub struct RotateServiceAccountSecret {
+    pub account_id: String,
+    pub overlap: Duration,
+}
+
+#[async_trait]
+impl HandleRequest<RotateServiceAccountSecret> for DbExecutor {
+    type Result = Result<Option<NewServiceAccountSecret>, ServiceError>;
+
+    async fn handle(&self, msg: RotateServiceAccountSecret) -> Self::Result {
+        use crate::schema::{service_account_secrets, service_accounts};
+
+        let (client_secret, account_secret) = new_secret(msg.account_id.clone());
+        let expires_at = account_secret.created_at + msg.overlap;
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let exists = service_accounts::table
+                    .find(&msg.account_id)
+                    .filter(service_accounts::disabled_at.is_null())
+                    .select(service_accounts::account_id)
+                    .for_update()
+                    .first::<String>(&conn)
+                    .optional()?
+                    .is_some();
+                if !exists {
+                    return Ok(None);
+                }
+                // secrets expiring before the end of the overlap keep their earlier expiry
+                diesel::update(
+                    service_account_secrets::table
+                        .filter(service_account_secrets::account_id.eq(&msg.account_id))
+                        .filter(
+                            service_account_secrets::expires_at
+                                .is_null()
+                                .or(service_account_secrets::expires_at.gt(expires_at)),
+                        ),
+                )
+                .set(service_account_secrets::expires_at.eq(expires_at))
+                .execute(&conn)?;
+                diesel::insert_into(service_account_secrets::table)
+                    .values(&account_secret)
+                    .execute(&conn)?;
+                Ok(Some(NewServiceAccountSecret {
+                    account_id: msg.account_id,
+                    client_secret,
+                }))
+            })
+        })
+        .await?
+    }
+}
+
+/// Check the credentials of the client credentials grant, returns the account if they are
+/// valid
+#[derive(Debug)]
+pub struct AuthenticateServiceAccount {
+    pub account_id: String,
+    pub client_secret: String,
+}
+
+#[async_trait]
+impl HandleRequest<AuthenticateServiceAccount> for DbExecutor {
+    type Result = Result<Option<ServiceAccount>, ServiceError>;
+
+    async fn handle(&self, msg: AuthenticateServiceAccount) -> Self::Result {
+        use crate::schema::{service_account_secrets, service_accounts};
+
+        // the secret is only compared by its hash, which is the key of the lookup
+        let secret_hash = hash_token(&msg.client_secret);
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            service_accounts::table
+                .inner_join(service_account_secrets::table)
+                .filter(service_account_secrets::secret_hash.eq(&secret_hash))
+                .filter(service_accounts::account_id.eq(&msg.account_id))
+                .filter(service_accounts::disabled_at.is_null())
+                .filter(
+                    service_account_secrets::expires_at
+                        .is_null()
+                        .or(service_account_secrets::expires_at.gt(Local::now().naive_local())),
+                )
+                .select(service_accounts::all_columns)
+                .first(&conn)
+                .optional()
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+pub fn cleanup_service_account_secrets(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    use crate::schema::service_account_secrets::dsl::{expires_at, service_account_secrets};
+
+    let conn = pool.0.get()?;
+    diesel::delete(service_account_secrets.filter(expires_at.lt(Local::now().naive_local())))
+        .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{
+        models::SlimUser,
+        service_account_handler::{check_account_id, grant_scope, is_scope_token},
+        utils::Token,
+    };
+
+    #[test]
+    fn test_grant_scope() {
+        let allowed = vec!["reports:read".to_string(), "reports:write".to_string()];
+        assert_eq!(
+            grant_scope(None, &allowed),
+            Some("reports:read reports:write".to_string())
+        );
+        assert_eq!(
+            grant_scope(Some("reports:read reports:read"), &allowed),
+            Some("reports:read".to_string())
+        );
+        assert_eq!(grant_scope(Some("admin"), &allowed), None);
+        assert_eq!(grant_scope(None, &[]), Some(String::new()));
+
+        assert!(check_account_id("billing-job_2").is_ok());
+        assert!(check_account_id("job@example.com").is_err());
+        assert!(check_account_id("").is_err());
+        assert!(is_scope_token("reports:read"));
+        assert!(!is_scope_token("a\"b"));
+        assert!(!is_scope_token("a b"));
+    }
+
+    #[test]
+    fn test_service_token() {
+        let token = Token::create_service_token("billing-job", "reports:read".into()).unwrap();
+        let claim = Token::decode_token(&token).unwrap();
+        assert_eq!(claim.get_service_account(), Some("billing-job"));
+        assert_eq!(claim.get_client_id(), Some("billing-job"));
+        assert_eq!(claim.get_scope(), Some("reports:read"));
+        // the account id is never mistaken for the email of a user
+        assert_eq!(claim.get_email(), None);
+
+        let user = SlimUser {
+            email: "billing-job@example.com".into(),
+        };
+        let token = Token::create_token(&user, Uuid::new_v4(), Vec::new()).unwrap();
+        let claim = Token::decode_token(&token).unwrap();
+        assert_eq!(claim.get_service_account(), None);
+        assert_eq!(claim.get_email(), Some(user.email));
+    }
+}
@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use chrono::{Local, NaiveDateTime};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, QueryResult, RunQueryDsl,
+};
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::{
+    errors::ServiceError,
+    logged_user::SESSIONS,
+    models::{DbExecutor, HandleRequest, Session},
+    utils::{access_token_lifetime, refresh_token_lifetime},
+};
+
+// a new session is started on every login
+#[derive(Debug)]
+pub struct CreateSession {
+    pub email: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub client_id: Option<String>,
+    pub auth_methods: Vec<String>,
+    pub scope: Option<String>,
+}
+
+#[async_trait]
+impl HandleRequest<CreateSession> for DbExecutor {
+    type Result = Result<Session, ServiceError>;
+
+    async fn handle(&self, msg: CreateSession) -> Self::Result {
+        use crate::schema::{sessions::dsl::sessions, users};
+
+        let current_time = Local::now().naive_local();
+        let session = Session {
+            id: Uuid::new_v4(),
+            email: msg.email,
+            user_agent: msg.user_agent,
+            ip_address: msg.ip_address,
+            created_at: current_time,
+            last_seen_at: current_time,
+            revoked_at: None,
+            client_id: msg.client_id,
+            auth_methods: msg.auth_methods,
+            scope: msg.scope,
+        };
+        let dbex = self.clone();
+        let session = spawn_blocking(move || -> Result<Session, ServiceError> {
+            let conn = dbex.0.get()?;
+            // every way of logging in ends up here, so this is where disabled users are stopped
+            let enabled = users::table
+                .find(&session.email)
+                .filter(users::disabled_at.is_null())
+                .select(users::email)
+                .first::<String>(&conn)
+                .optional()?
+                .is_some();
+            if !enabled {
+                return Err(ServiceError::BadRequest("Account disabled".into()));
+            }
+            diesel::insert_into(sessions)
+                .values(&session)
+                .execute(&conn)
+                .map(|_| session)
+                .map_err(Into::into)
+        })
+        .await??;
+        if session.client_id.is_some() {
+            SESSIONS.add_client_session(session.id);
+        }
+        Ok(session)
+    }
+}
+
+/// Revoke the given sessions and every refresh token issued for them, returns the sessions
+/// that were still active
+pub fn revoke_sessions(conn: &PgConnection, ids: &[Uuid]) -> QueryResult<Vec<Uuid>> {
+    use crate::schema::{refresh_tokens, sessions};
+
+    let current_time = Local::now().naive_local();
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::family_id.eq_any(ids))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(current_time))
+    .execute(conn)?;
+    diesel::update(
+        sessions::table
+            .filter(sessions::id.eq_any(ids))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(current_time))
+    .returning(sessions::id)
+    .get_results(conn)
+}
+
+/// Revoke all active sessions of a user except `keep`, to be run inside the transaction
+/// that changes the credentials. The caller must pass the result to `SESSIONS.revoke`
+/// once the transaction committed.
+pub fn revoke_user_sessions(
+    conn: &PgConnection,
+    email_: &str,
+    keep: Option<Uuid>,
+) -> QueryResult<Vec<Uuid>> {
+    use crate::schema::sessions::dsl::{email, id, revoked_at, sessions};
+
+    let ids: Vec<Uuid> = sessions
+        .filter(email.eq(email_))
+        .filter(revoked_at.is_null())
+        .select(id)
+        .load::<Uuid>(conn)?
+        .into_iter()
+        .filter(|session| Some(*session) != keep)
+        .collect();
+    revoke_sessions(conn, &ids)
+}
+
+/// An active session by id
+#[derive(Debug)]
+pub struct GetSession {
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<GetSession> for DbExecutor {
+    type Result = Result<Option<Session>, ServiceError>;
+
+    async fn handle(&self, msg: GetSession) -> Self::Result {
+        use crate::schema::sessions::dsl::{revoked_at, sessions};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            sessions
+                .find(msg.session_id)
+                .filter(revoked_at.is_null())
+                .first(&conn)
+                .optional()
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub client_id: Option<String>,
+    // whether this is the session making the request
+    pub current: bool,
+}
+
+#[derive(Debug)]
+pub struct ListSessions {
+    pub email: String,
+    pub current: Option<Uuid>,
+}
+
+#[async_trait]
+impl HandleRequest<ListSessions> for DbExecutor {
+    type Result = Result<Vec<SessionInfo>, ServiceError>;
+
+    async fn handle(&self, msg: ListSessions) -> Self::Result {
+        use crate::schema::sessions::dsl::{created_at, email, revoked_at, sessions};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            let active: Vec<Session> = sessions
+                .filter(email.eq(&msg.email))
+                .filter(revoked_at.is_null())
+                .order(created_at.desc())
+                .load(&conn)?;
+            Ok(active
+                .into_iter()
+                .map(|session| SessionInfo {
+                    current: Some(session.id) == msg.current,
+                    id: session.id,
+                    user_agent: session.user_agent,
+                    ip_address: session.ip_address,
+                    created_at: session.created_at,
+                    last_seen_at: session.last_seen_at,
+                    client_id: session.client_id,
+                })
+                .collect())
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct RevokeSession {
+    pub email: String,
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl HandleRequest<RevokeSession> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: RevokeSession) -> Self::Result {
+        use crate::schema::sessions::dsl::{email, id, sessions};
+
+        let dbex = self.clone();
+        let revoked = spawn_blocking(move || -> Result<Vec<Uuid>, ServiceError> {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let ids: Vec<Uuid> = sessions
+                    .filter(id.eq(msg.session_id))
+                    .filter(email.eq(&msg.email))
+                    .select(id)
+                    .load(&conn)?;
+                revoke_sessions(&conn, &ids).map_err(Into::into)
+            })
+        })
+        .await??;
+        SESSIONS.revoke(&revoked);
+        Ok(!revoked.is_empty())
+    }
+}
+
+// log out everywhere except the current session
+#[derive(Debug)]
+pub struct RevokeOtherSessions {
+    pub email: String,
+    pub current: Option<Uuid>,
+}
+
+#[async_trait]
+impl HandleRequest<RevokeOtherSessions> for DbExecutor {
+    type Result = Result<usize, ServiceError>;
+
+    async fn handle(&self, msg: RevokeOtherSessions) -> Self::Result {
+        let dbex = self.clone();
+        let revoked = spawn_blocking(move || -> Result<Vec<Uuid>, ServiceError> {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                revoke_user_sessions(&conn, &msg.email, msg.current).map_err(Into::into)
+            })
+        })
+        .await??;
+        SESSIONS.revoke(&revoked);
+        Ok(revoked.len())
+    }
+}
+
+pub fn cleanup_sessions(pool: &DbExecutor) -> Result<(), anyhow::Error> {
+    use crate::schema::sessions::dsl::{last_seen_at, revoked_at, sessions};
+
+    let conn = pool.0.get()?;
+    let current_time = Local::now().naive_local();
+    // revoked sessions are kept until their last access token expired, idle ones until their
+    // refresh token did
+    diesel::delete(
+        sessions.filter(
+            revoked_at
+                .lt(current_time - access_token_lifetime())
+                .or(last_seen_at.lt(current_time - refresh_token_lifetime())),
+        ),
+    )
+    .execute(&conn)?;
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use actix_web::{
+    web::{Data, Path},
+    Error, HttpResponse, ResponseError,
+};
+use maplit::hashmap;
+use uuid::Uuid;
+
+use crate::{
+    logged_user::SessionUser,
+    models::{DbExecutor, HandleRequest},
+    session_handler::{ListSessions, RevokeOtherSessions, RevokeSession},
+};
+
+pub async fn list_sessions(
+    session_user: SessionUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ListSessions {
+        email: session_user.email,
+        current: Some(session_user.session),
+    };
+    match db.handle(msg).await {
+        Ok(sessions) => Ok(HttpResponse::Ok().json(sessions)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn revoke_session(
+    session_user: SessionUser,
+    session_id: Path<Uuid>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = RevokeSession {
+        email: session_user.email,
+        session_id: session_id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            let result = hashmap! { "status" => status };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn revoke_other_sessions(
+    session_user: SessionUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = RevokeOtherSessions {
+        email: session_user.email,
+        current: Some(session_user.session),
+    };
+    match db.handle(msg).await {
+        Ok(revoked) => Ok(HttpResponse::Ok().json(hashmap! { "revoked" => revoked })),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
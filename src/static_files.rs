@@ -31,8 +31,32 @@ pub fn login_html() -> HttpResponse {
         .body(include_str!("../static/login.html"))
 }
 
+pub fn consent_html() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../static/consent.html"))
+}
+
+pub fn device_html() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../static/device.html"))
+}
+
 pub fn change_password() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(include_str!("../static/change_password.html"))
 }
+
+pub fn reset_password() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../static/reset_password.html"))
+}
+
+pub fn passkeys_html() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../static/passkeys.html"))
+}
@@ -0,0 +1,159 @@
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use rand::{thread_rng, Rng};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    digest::{digest, SHA256},
+    hmac,
+};
+use url::Url;
+
+const TOTP_PERIOD: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// accept codes from one step before or after the current one to allow for clock drift
+const TOTP_SKEW: i64 = 1;
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Generate a new random TOTP secret, base32 encoded without padding
+pub fn generate_secret() -> String {
+    let secret: Vec<u8> = (0..SECRET_LENGTH)
+        .map(|_| thread_rng().gen::<u8>())
+        .collect();
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// Build the otpauth:// URI used by authenticator apps (usually shown as a QR code)
+pub fn otpauth_uri(secret: &str, email: &str, issuer: &str) -> String {
+    let mut url = Url::parse("otpauth://totp/").expect("Invalid otpauth url");
+    url.set_path(&format!("{}:{}", issuer, email));
+    url.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_PERIOD.to_string());
+    url.into()
+}
+
+/// RFC 4226 HOTP value for a given counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    binary % 10_u32.pow(TOTP_DIGITS)
+}
+
+fn format_code(code: u32) -> String {
+    format!("{:0width$}", code, width = TOTP_DIGITS as usize)
+}
+
+/// Verify a TOTP code at the given unix timestamp.
+///
+/// Returns the time step that matched so the caller can store it and reject
+/// replays of the same (or an older) code, which is why any step not strictly
+/// greater than `last_used_step` is refused.
+pub fn verify_code(
+    secret: &str,
+    code: &str,
+    timestamp: i64,
+    last_used_step: Option<i64>,
+) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let current_step = timestamp / TOTP_PERIOD;
+    (current_step - TOTP_SKEW..=current_step + TOTP_SKEW)
+        .filter(|step| *step >= 0 && last_used_step.is_none_or(|last| *step > last))
+        .find(|step| {
+            let expected = format_code(hotp(&secret, *step as u64));
+            verify_slices_are_equal(expected.as_bytes(), code.as_bytes()).is_ok()
+        })
+}
+
+/// Generate a fresh set of single-use recovery codes, formatted as `xxxx-xxxx`
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let bytes: Vec<u8> = (0..5).map(|_| thread_rng().gen::<u8>()).collect();
+            let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            format!("{}-{}", &code[..4], &code[4..])
+        })
+        .collect()
+}
+
+/// Recovery codes are high entropy, so a plain SHA-256 is enough to avoid storing them in the
+/// clear; dashes, whitespace and case are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase();
+    HEXLOWER.encode(digest(&SHA256, normalized.as_bytes()).as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::BASE32_NOPAD;
+
+    use crate::totp::{
+        generate_recovery_codes, generate_secret, hash_recovery_code, hotp, otpauth_uri,
+        verify_code,
+    };
+
+    // RFC 6238 appendix B test vectors (SHA1), truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_hotp_rfc_vectors() {
+        assert_eq!(hotp(RFC_SECRET, 59 / 30), 287_082);
+        assert_eq!(hotp(RFC_SECRET, 1_111_111_109 / 30), 81_804);
+        assert_eq!(hotp(RFC_SECRET, 1_234_567_890 / 30), 5_924);
+        assert_eq!(hotp(RFC_SECRET, 2_000_000_000 / 30), 279_037);
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        assert_eq!(
+            verify_code(&secret, "081804", 1_111_111_109, None),
+            Some(1_111_111_109 / 30)
+        );
+        // one step of clock drift is tolerated
+        assert!(verify_code(&secret, "081804", 1_111_111_109 + 30, None).is_some());
+        assert!(verify_code(&secret, "081804", 1_111_111_109 + 90, None).is_none());
+        // replaying an already used step is rejected
+        assert!(verify_code(&secret, "081804", 1_111_111_109, Some(1_111_111_109 / 30)).is_none());
+        assert!(verify_code(&secret, "81804", 1_111_111_109, None).is_none());
+        assert!(verify_code(&secret, "abcdef", 1_111_111_109, None).is_none());
+    }
+
+    #[test]
+    fn test_generate_secret() {
+        let secret = generate_secret();
+        assert_eq!(BASE32_NOPAD.decode(secret.as_bytes()).unwrap().len(), 20);
+        let uri = otpauth_uri(&secret, "user@test", "localhost");
+        assert!(uri.starts_with("otpauth://totp/localhost:user@test?secret="));
+        assert!(uri.contains("&issuer=localhost"));
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), 10);
+        assert!(codes.iter().all(|c| c.len() == 9 && &c[4..5] == "-"));
+        let code = &codes[0];
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&code.to_uppercase().replace('-', " "))
+        );
+        assert_ne!(hash_recovery_code(code), hash_recovery_code(&codes[1]));
+    }
+}
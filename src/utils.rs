@@ -1,14 +1,20 @@
+use base64::{encode_config, URL_SAFE_NO_PAD};
 use bcrypt::{hash, DEFAULT_COST};
-use chrono::{Duration, Local};
+use chrono::{Duration, Local, NaiveDateTime, TimeZone, Utc};
+use data_encoding::HEXLOWER;
 use derive_more::{From, Into};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use log::debug;
+use log::{debug, error};
+use rand::{thread_rng, Rng};
+use ring::digest::{digest, SHA256};
 use serde::{Deserialize, Serialize};
 use std::env;
+use uuid::Uuid;
 
-use crate::{errors::ServiceError, models::SlimUser};
-
-const DEFAULT_ALGORITHM: Algorithm = Algorithm::HS256;
+use crate::{
+    errors::ServiceError,
+    jwt_keys::JWT_KEYS,
+    models::{OAuthCode, SlimUser},
+};
 
 pub fn hash_password(plain: &str) -> Result<String, ServiceError> {
     // get the hashing cost from the env variable or use default
@@ -20,6 +26,63 @@ pub fn hash_password(plain: &str) -> Result<String, ServiceError> {
     hash(plain, hashing_cost).map_err(|_| ServiceError::InternalServerError)
 }
 
+pub fn get_random_string() -> String {
+    let random_bytes: Vec<u8> = (0..16).map(|_| thread_rng().gen::<u8>()).collect();
+    encode_config(&random_bytes, URL_SAFE_NO_PAD)
+}
+
+/// Random opaque token that is only ever stored hashed, see `hash_token`
+pub fn get_random_token() -> String {
+    let random_bytes: Vec<u8> = (0..32).map(|_| thread_rng().gen::<u8>()).collect();
+    encode_config(&random_bytes, URL_SAFE_NO_PAD)
+}
+
+/// Opaque tokens are high entropy, so a plain SHA-256 is enough to avoid storing them in the
+/// clear while still allowing lookups by hash
+pub fn hash_token(token: &str) -> String {
+    HEXLOWER.encode(digest(&SHA256, token.as_bytes()).as_ref())
+}
+
+/// The S256 code challenge of a PKCE code verifier (RFC 7636)
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    encode_config(
+        digest(&SHA256, code_verifier.as_bytes()).as_ref(),
+        URL_SAFE_NO_PAD,
+    )
+}
+
+/// The OpenID Connect issuer, `iss` of every token and base of the discovery document
+pub fn issuer() -> String {
+    let domain = env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    format!("https://{}", domain)
+}
+
+// the timestamps in the database are local time
+pub fn unix_time(time: NaiveDateTime) -> Option<i64> {
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.timestamp())
+}
+
+// lifetime of the JWT access token, and of the auth cookie holding it
+pub fn access_token_lifetime() -> Duration {
+    let minutes = env::var("ACCESS_TOKEN_MINUTES")
+        .ok()
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(15);
+    Duration::minutes(minutes)
+}
+
+// lifetime of an opaque refresh token, each rotation starts a new period
+pub fn refresh_token_lifetime() -> Duration {
+    let days = env::var("REFRESH_TOKEN_DAYS")
+        .ok()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(30);
+    Duration::days(days)
+}
+
 // JWT claim
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claim {
@@ -31,62 +94,224 @@ pub struct Claim {
     iat: i64,
     // expiry
     exp: i64,
-    // user email
-    email: String,
+    // session id, see the sessions table
+    jti: Uuid,
+    // user email, tokens of service accounts have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    // roles granted to the user when the token was issued, only in tokens of this server's
+    // own logins
+    #[serde(default)]
+    roles: Vec<String>,
+    // the oauth client a token was issued to, see `for_client`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+    // the oauth client or the service account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    // scopes granted to an oauth client or a service account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
 }
 
 // struct to get converted to token and back
 impl Claim {
-    fn with_email(email: &str) -> Self {
-        let domain = env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    fn with_email(email: &str, session_id: Uuid, roles: Vec<String>) -> Self {
         Self {
-            iss: domain,
+            iss: issuer(),
             sub: "auth".into(),
-            email: email.to_owned(),
+            email: Some(email.to_owned()),
+            jti: session_id,
+            roles,
             iat: Local::now().timestamp(),
-            exp: (Local::now() + Duration::hours(24)).timestamp(),
+            exp: (Local::now() + access_token_lifetime()).timestamp(),
+            aud: None,
+            client_id: None,
+            scope: None,
         }
     }
 
-    pub fn get_email(self) -> String {
+    // a user's token for an oauth client, it carries the granted scope instead of the roles
+    fn for_client(email: &str, session_id: Uuid, client_id: &str, scope: String) -> Self {
+        Self {
+            aud: Some(client_id.into()),
+            client_id: Some(client_id.into()),
+            scope: Some(scope),
+            ..Self::with_email(email, session_id, Vec::new())
+        }
+    }
+
+    // a token without a user, the account is the subject and the client, the jti is random
+    // as there is no session
+    fn for_service_account(account_id: &str, scope: String) -> Self {
+        Self {
+            iss: issuer(),
+            sub: account_id.into(),
+            email: None,
+            jti: Uuid::new_v4(),
+            roles: Vec::new(),
+            iat: Local::now().timestamp(),
+            exp: (Local::now() + access_token_lifetime()).timestamp(),
+            aud: None,
+            client_id: Some(account_id.into()),
+            scope: Some(scope),
+        }
+    }
+
+    /// The account id of a token issued with the client credentials grant
+    pub fn get_service_account(&self) -> Option<&str> {
+        match self.email {
+            Some(_) => None,
+            None => self.client_id.as_deref(),
+        }
+    }
+
+    pub fn get_scope(&self) -> Option<&str> {
+        self.scope.as_deref()
+    }
+
+    /// The oauth client of a token issued by the token endpoint, None for this server's logins
+    pub fn get_client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    pub fn get_audience(&self) -> Option<&str> {
+        self.aud.as_deref()
+    }
+
+    /// Whether the token is one of this server's own logins rather than one for an oauth client
+    /// or a service account
+    pub fn is_first_party(&self) -> bool {
+        self.email.is_some() && self.client_id.is_none() && self.aud.is_none()
+    }
+
+    pub fn get_email(self) -> Option<String> {
         self.email
     }
+
+    pub fn get_session_id(&self) -> Uuid {
+        self.jti
+    }
+
+    pub fn get_roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    pub fn get_issuer(&self) -> &str {
+        &self.iss
+    }
+
+    pub fn get_issued_at(&self) -> i64 {
+        self.iat
+    }
+
+    pub fn get_expiry(&self) -> i64 {
+        self.exp
+    }
+}
+
+// OpenID Connect ID token claims, the subject is the user's email
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdClaim {
+    iss: String,
+    sub: String,
+    // the oauth client the token was issued to
+    aud: String,
+    iat: i64,
+    exp: i64,
+    // when the user logged in at this server
+    auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    amr: Vec<String>,
+    // only with the email scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
 }
 
-impl From<Claim> for SlimUser {
-    fn from(claims: Claim) -> Self {
+impl IdClaim {
+    pub fn from_code(code: &OAuthCode) -> Self {
+        let email = if code.scope.split_whitespace().any(|scope| scope == "email") {
+            Some(code.email.clone())
+        } else {
+            None
+        };
         Self {
-            email: claims.email,
+            iss: issuer(),
+            sub: code.email.clone(),
+            aud: code.client_id.clone(),
+            iat: Local::now().timestamp(),
+            exp: (Local::now() + access_token_lifetime()).timestamp(),
+            auth_time: unix_time(code.auth_time).unwrap_or_else(|| Local::now().timestamp()),
+            nonce: code.nonce.clone(),
+            amr: code.auth_methods.clone(),
+            email_verified: email.as_ref().map(|_| true),
+            email,
         }
     }
+
+    pub fn encode(&self) -> Result<String, ServiceError> {
+        JWT_KEYS.read().encode(self, Utc::now()).map_err(|err| {
+            error!("Failed to sign id token: {}", err);
+            ServiceError::InternalServerError
+        })
+    }
 }
 
 #[derive(From, Into)]
 pub struct Token(String);
 
 impl Token {
-    pub fn create_token(data: &SlimUser) -> Result<Self, ServiceError> {
-        let claims = Claim::with_email(data.email.as_str());
-        encode(
-            &Header::new(DEFAULT_ALGORITHM),
-            &claims,
-            &EncodingKey::from_secret(get_secret().as_ref()),
-        )
-        .map(Into::into)
-        .map_err(|_err| ServiceError::InternalServerError)
+    fn sign(claims: &Claim) -> Result<Self, ServiceError> {
+        JWT_KEYS
+            .read()
+            .encode(claims, Utc::now())
+            .map(Into::into)
+            .map_err(|err| {
+                error!("Failed to sign token: {}", err);
+                ServiceError::InternalServerError
+            })
     }
 
-    pub fn decode_token(token: &Self) -> Result<Claim, ServiceError> {
-        decode::<Claim>(
-            &token.0,
-            &DecodingKey::from_secret(get_secret().as_ref()),
-            &Validation::new(DEFAULT_ALGORITHM),
-        )
-        .map(|data| Ok(data.claims))
-        .map_err(|_err| ServiceError::Unauthorized)?
+    pub fn create_token(
+        data: &SlimUser,
+        session_id: Uuid,
+        roles: Vec<String>,
+    ) -> Result<Self, ServiceError> {
+        Self::sign(&Claim::with_email(data.email.as_str(), session_id, roles))
+    }
+
+    /// An access token of the token endpoint, only valid for the scope granted to the client
+    pub fn create_client_token(
+        data: &SlimUser,
+        session_id: Uuid,
+        client_id: &str,
+        scope: String,
+    ) -> Result<Self, ServiceError> {
+        Self::sign(&Claim::for_client(
+            data.email.as_str(),
+            session_id,
+            client_id,
+            scope,
+        ))
+    }
+
+    pub fn create_service_token(account_id: &str, scope: String) -> Result<Self, ServiceError> {
+        Self::sign(&Claim::for_service_account(account_id, scope))
     }
-}
 
-fn get_secret() -> String {
-    env::var("JWT_SECRET").unwrap_or_else(|_| "my secret".into())
+    pub fn decode_token(token: &Self) -> Result<Claim, ServiceError> {
+        let (claims, retire_at) = JWT_KEYS
+            .read()
+            .decode::<Claim>(&token.0, Utc::now())
+            .map_err(|_err| ServiceError::Unauthorized)?;
+        // a retired key keeps verifying the tokens it signed, but must not have signed new ones
+        if retire_at.is_some_and(|retire_at| claims.iat > retire_at.timestamp()) {
+            return Err(ServiceError::Unauthorized);
+        }
+        Ok(claims)
+    }
 }
@@ -0,0 +1,496 @@
+use data_encoding::BASE64URL_NOPAD;
+use rand::{thread_rng, Rng};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    digest::{digest, SHA256},
+    signature::{
+        RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ED25519,
+        RSA_PKCS1_2048_8192_SHA256,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_cbor::{Deserializer, Value};
+use std::{collections::BTreeMap, convert::TryInto, env};
+
+use crate::errors::ServiceError;
+
+// COSE algorithm identifiers we can verify
+pub const COSE_ALG_ES256: i128 = -7;
+pub const COSE_ALG_EDDSA: i128 = -8;
+pub const COSE_ALG_RS256: i128 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// Relying party identity, the rp id is the domain the credentials are scoped to and the
+/// origin is what the browser reports in the client data
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn from_env() -> Self {
+        let domain = env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+        let id = env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| domain.clone());
+        let origin = env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| format!("https://{}", domain));
+        Self {
+            id,
+            name: domain,
+            origin,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    ceremony_type: String,
+    challenge: String,
+    origin: String,
+}
+
+/// A credential public key as produced by a successful registration ceremony
+#[derive(Debug)]
+pub struct RegisteredCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    // (credential id, COSE encoded public key), only present during registration
+    attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn bad_request(message: &str) -> ServiceError {
+    ServiceError::BadRequest(message.into())
+}
+
+/// Generate a random base64url encoded challenge for a new ceremony
+pub fn generate_challenge() -> String {
+    let challenge: Vec<u8> = (0..32).map(|_| thread_rng().gen::<u8>()).collect();
+    BASE64URL_NOPAD.encode(&challenge)
+}
+
+/// Opaque user handle for the authenticator, so the email itself isn't stored on the device
+pub fn user_handle(email: &str) -> String {
+    BASE64URL_NOPAD.encode(digest(&SHA256, email.as_bytes()).as_ref())
+}
+
+pub fn decode_base64url(input: &str) -> Result<Vec<u8>, ServiceError> {
+    BASE64URL_NOPAD
+        .decode(input.trim_end_matches('=').as_bytes())
+        .map_err(|_| bad_request("Invalid base64url encoding"))
+}
+
+/// Extract the challenge from the client data, used to find the pending ceremony
+pub fn client_data_challenge(client_data_json: &[u8]) -> Result<String, ServiceError> {
+    serde_json::from_slice::<CollectedClientData>(client_data_json)
+        .map(|client_data| client_data.challenge)
+        .map_err(|_| bad_request("Invalid client data"))
+}
+
+fn verify_client_data(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    ceremony_type: &str,
+    challenge: &str,
+) -> Result<(), ServiceError> {
+    let client_data: CollectedClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| bad_request("Invalid client data"))?;
+    if client_data.ceremony_type != ceremony_type {
+        return Err(bad_request("Unexpected ceremony type"));
+    }
+    if verify_slices_are_equal(client_data.challenge.as_bytes(), challenge.as_bytes()).is_err() {
+        return Err(bad_request("Challenge mismatch"));
+    }
+    if client_data.origin != rp.origin {
+        return Err(bad_request("Origin mismatch"));
+    }
+    Ok(())
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, ServiceError> {
+    if data.len() < 37 {
+        return Err(bad_request("Authenticator data too short"));
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes(data[33..37].try_into().expect("Slice of length 4"));
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        None
+    } else {
+        // aaguid (16 bytes), credential id length (2 bytes), credential id, public key
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(bad_request("Attested credential data too short"));
+        }
+        let id_length = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < id_length {
+            return Err(bad_request("Attested credential data too short"));
+        }
+        let (credential_id, rest) = rest.split_at(id_length);
+        // the public key is a CBOR map possibly followed by extensions, so only consume
+        // one item to find out where it ends
+        let mut deserializer = Deserializer::from_slice(rest);
+        serde::Deserialize::deserialize(&mut deserializer)
+            .map(|_: Value| ())
+            .map_err(|_| bad_request("Invalid credential public key"))?;
+        let public_key = rest[..deserializer.byte_offset()].to_vec();
+        Some((credential_id.to_vec(), public_key))
+    };
+    Ok(AuthenticatorData {
+        rp_id_hash: data[..32].to_vec(),
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+fn verify_rp_id_hash(rp: &RelyingParty, auth_data: &AuthenticatorData) -> Result<(), ServiceError> {
+    let expected = digest(&SHA256, rp.id.as_bytes());
+    verify_slices_are_equal(expected.as_ref(), &auth_data.rp_id_hash)
+        .map_err(|_| bad_request("Relying party mismatch"))
+}
+
+fn cose_get(map: &BTreeMap<Value, Value>, key: i128) -> Option<&Value> {
+    map.get(&Value::Integer(key))
+}
+
+fn cose_bytes(map: &BTreeMap<Value, Value>, key: i128) -> Result<&[u8], ServiceError> {
+    match cose_get(map, key) {
+        Some(Value::Bytes(bytes)) => Ok(bytes),
+        _ => Err(bad_request("Invalid credential public key")),
+    }
+}
+
+/// COSE algorithm of an encoded credential public key
+pub fn cose_algorithm(public_key: &[u8]) -> Result<i128, ServiceError> {
+    let map: BTreeMap<Value, Value> =
+        serde_cbor::from_slice(public_key).map_err(|_| bad_request("Invalid public key"))?;
+    match cose_get(&map, 3) {
+        Some(Value::Integer(alg)) => Ok(*alg),
+        _ => Err(bad_request("Invalid credential public key")),
+    }
+}
+
+fn verify_signature(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), ServiceError> {
+    let map: BTreeMap<Value, Value> =
+        serde_cbor::from_slice(public_key).map_err(|_| bad_request("Invalid public key"))?;
+    let result = match cose_get(&map, 3) {
+        Some(Value::Integer(COSE_ALG_ES256)) => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(cose_bytes(&map, -2)?);
+            point.extend_from_slice(cose_bytes(&map, -3)?);
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point).verify(message, signature)
+        }
+        Some(Value::Integer(COSE_ALG_EDDSA)) => {
+            UnparsedPublicKey::new(&ED25519, cose_bytes(&map, -2)?).verify(message, signature)
+        }
+        Some(Value::Integer(COSE_ALG_RS256)) => RsaPublicKeyComponents {
+            n: cose_bytes(&map, -1)?,
+            e: cose_bytes(&map, -2)?,
+        }
+        .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature),
+        _ => return Err(bad_request("Unsupported credential algorithm")),
+    };
+    result.map_err(|_| bad_request("Invalid signature"))
+}
+
+/// Verify the response to a `navigator.credentials.create()` call.
+///
+/// Only the "none" attestation format is accepted, we don't keep a list of trusted
+/// authenticator vendors so any attestation statement would be ignored anyway.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &str,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<RegisteredCredential, ServiceError> {
+    verify_client_data(rp, client_data_json, "webauthn.create", challenge)?;
+
+    let attestation: BTreeMap<String, Value> = serde_cbor::from_slice(attestation_object)
+        .map_err(|_| bad_request("Invalid attestation object"))?;
+    match attestation.get("fmt") {
+        Some(Value::Text(fmt)) if fmt == "none" => {}
+        _ => return Err(bad_request("Unsupported attestation format")),
+    }
+    let auth_data = match attestation.get("authData") {
+        Some(Value::Bytes(auth_data)) => parse_authenticator_data(auth_data)?,
+        _ => return Err(bad_request("Invalid attestation object")),
+    };
+    verify_rp_id_hash(rp, &auth_data)?;
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(bad_request("User not present"));
+    }
+    let (credential_id, public_key) = auth_data
+        .attested_credential
+        .ok_or_else(|| bad_request("Missing attested credential data"))?;
+    // reject keys we won't be able to verify later
+    match cose_algorithm(&public_key)? {
+        COSE_ALG_ES256 | COSE_ALG_EDDSA | COSE_ALG_RS256 => {}
+        _ => return Err(bad_request("Unsupported credential algorithm")),
+    }
+    Ok(RegisteredCredential {
+        credential_id,
+        public_key,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Verify the response to a `navigator.credentials.get()` call, returns the new signature
+/// counter to store.
+///
+/// Authenticators that don't implement a counter always report zero, otherwise the counter
+/// must increase with every assertion or the credential may have been cloned.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &str,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+) -> Result<u32, ServiceError> {
+    verify_client_data(rp, client_data_json, "webauthn.get", challenge)?;
+
+    let auth_data = parse_authenticator_data(authenticator_data)?;
+    verify_rp_id_hash(rp, &auth_data)?;
+    // this is a passwordless login, so the authenticator must have verified the user
+    if auth_data.flags & FLAG_USER_PRESENT == 0 || auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(bad_request("User not verified"));
+    }
+
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(digest(&SHA256, client_data_json).as_ref());
+    verify_signature(public_key, &message, signature)?;
+
+    if (auth_data.sign_count != 0 || stored_sign_count != 0)
+        && auth_data.sign_count <= stored_sign_count
+    {
+        return Err(bad_request("Signature counter did not increase"));
+    }
+    Ok(auth_data.sign_count)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub id: String,
+}
+
+impl CredentialDescriptor {
+    pub fn new(id: String) -> Self {
+        Self {
+            credential_type: "public-key".to_string(),
+            id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        digest::{digest, SHA256},
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use serde_cbor::Value;
+    use std::collections::BTreeMap;
+
+    use crate::webauthn::{
+        generate_challenge, verify_assertion, verify_registration, RelyingParty,
+    };
+
+    /// Minimal software authenticator holding a single ES256 credential
+    struct SoftwareAuthenticator {
+        key_pair: EcdsaKeyPair,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+        rng: SystemRandom,
+    }
+
+    impl SoftwareAuthenticator {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+                .expect("Failed to generate key");
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+                    .expect("Failed to parse key");
+            Self {
+                key_pair,
+                credential_id: b"software-credential".to_vec(),
+                sign_count: 0,
+                rng,
+            }
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let point = self.key_pair.public_key().as_ref();
+            let mut map = BTreeMap::new();
+            map.insert(Value::Integer(1), Value::Integer(2));
+            map.insert(Value::Integer(3), Value::Integer(-7));
+            map.insert(Value::Integer(-1), Value::Integer(1));
+            map.insert(Value::Integer(-2), Value::Bytes(point[1..33].to_vec()));
+            map.insert(Value::Integer(-3), Value::Bytes(point[33..65].to_vec()));
+            serde_cbor::to_vec(&Value::Map(map)).unwrap()
+        }
+
+        fn auth_data(&self, rp_id: &str, flags: u8, attested: bool) -> Vec<u8> {
+            let mut data = digest(&SHA256, rp_id.as_bytes()).as_ref().to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if attested {
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.credential_id);
+                data.extend_from_slice(&self.cose_key());
+            }
+            data
+        }
+
+        fn create(&self, rp_id: &str, origin: &str, challenge: &str) -> (Vec<u8>, Vec<u8>) {
+            let client_data = client_data("webauthn.create", challenge, origin);
+            let mut attestation = BTreeMap::new();
+            attestation.insert(Value::Text("fmt".into()), Value::Text("none".into()));
+            attestation.insert(Value::Text("attStmt".into()), Value::Map(BTreeMap::new()));
+            attestation.insert(
+                Value::Text("authData".into()),
+                Value::Bytes(self.auth_data(rp_id, 0x41, true)),
+            );
+            (
+                client_data,
+                serde_cbor::to_vec(&Value::Map(attestation)).unwrap(),
+            )
+        }
+
+        fn get(&mut self, rp_id: &str, origin: &str, challenge: &str) -> Assertion {
+            self.sign_count += 1;
+            let client_data = client_data("webauthn.get", challenge, origin);
+            let auth_data = self.auth_data(rp_id, 0x05, false);
+            let mut message = auth_data.clone();
+            message.extend_from_slice(digest(&SHA256, &client_data).as_ref());
+            let signature = self.key_pair.sign(&self.rng, &message).unwrap();
+            Assertion {
+                client_data,
+                auth_data,
+                signature: signature.as_ref().to_vec(),
+            }
+        }
+    }
+
+    struct Assertion {
+        client_data: Vec<u8>,
+        auth_data: Vec<u8>,
+        signature: Vec<u8>,
+    }
+
+    fn client_data(ceremony_type: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": ceremony_type,
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        }))
+        .unwrap()
+    }
+
+    fn test_rp() -> RelyingParty {
+        RelyingParty {
+            id: "localhost".into(),
+            name: "localhost".into(),
+            origin: "https://localhost".into(),
+        }
+    }
+
+    #[test]
+    fn test_registration_and_assertion() {
+        let rp = test_rp();
+        let mut authenticator = SoftwareAuthenticator::new();
+
+        let challenge = generate_challenge();
+        let (client_data, attestation) = authenticator.create(&rp.id, &rp.origin, &challenge);
+        let credential = verify_registration(&rp, &challenge, &client_data, &attestation).unwrap();
+        assert_eq!(credential.credential_id, authenticator.credential_id);
+        assert_eq!(credential.public_key, authenticator.cose_key());
+
+        let challenge = generate_challenge();
+        let assertion = authenticator.get(&rp.id, &rp.origin, &challenge);
+        let sign_count = verify_assertion(
+            &rp,
+            &challenge,
+            &assertion.client_data,
+            &assertion.auth_data,
+            &assertion.signature,
+            &credential.public_key,
+            credential.sign_count,
+        )
+        .unwrap();
+        assert_eq!(sign_count, 1);
+
+        // replaying the same assertion fails the counter check
+        assert!(verify_assertion(
+            &rp,
+            &challenge,
+            &assertion.client_data,
+            &assertion.auth_data,
+            &assertion.signature,
+            &credential.public_key,
+            sign_count,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_registration_rejects_mismatches() {
+        let rp = test_rp();
+        let authenticator = SoftwareAuthenticator::new();
+        let challenge = generate_challenge();
+
+        let (client_data, attestation) =
+            authenticator.create(&rp.id, "https://evil.example", &challenge);
+        assert!(verify_registration(&rp, &challenge, &client_data, &attestation).is_err());
+
+        let (client_data, attestation) =
+            authenticator.create("evil.example", &rp.origin, &challenge);
+        assert!(verify_registration(&rp, &challenge, &client_data, &attestation).is_err());
+
+        let (client_data, attestation) = authenticator.create(&rp.id, &rp.origin, &challenge);
+        assert!(
+            verify_registration(&rp, &generate_challenge(), &client_data, &attestation).is_err()
+        );
+    }
+
+    #[test]
+    fn test_assertion_rejects_bad_signature() {
+        let rp = test_rp();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let other = SoftwareAuthenticator::new();
+
+        let challenge = generate_challenge();
+        let assertion = authenticator.get(&rp.id, &rp.origin, &challenge);
+        assert!(verify_assertion(
+            &rp,
+            &challenge,
+            &assertion.client_data,
+            &assertion.auth_data,
+            &assertion.signature,
+            &other.cose_key(),
+            0,
+        )
+        .is_err());
+    }
+}
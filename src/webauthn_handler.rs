@@ -0,0 +1,434 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use data_encoding::BASE64URL_NOPAD;
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::task::spawn_blocking;
+
+use crate::{
+    errors::ServiceError,
+    models::{DbExecutor, HandleRequest, SlimUser, WebauthnCredential},
+    webauthn::{
+        client_data_challenge, decode_base64url, generate_challenge, user_handle, verify_assertion,
+        verify_registration, CredentialDescriptor, RelyingParty, COSE_ALG_EDDSA, COSE_ALG_ES256,
+        COSE_ALG_RS256,
+    },
+};
+
+// how long the browser has to complete a ceremony, in seconds
+const CEREMONY_TIMEOUT: i64 = 300;
+
+lazy_static! {
+    static ref WEBAUTHN_CHALLENGES: RwLock<HashMap<String, PendingCeremony>> =
+        RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+struct PendingCeremony {
+    ceremony: Ceremony,
+    // always set for registration, optional for authentication with discoverable credentials
+    email: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+fn start_ceremony(ceremony: Ceremony, email: Option<String>) -> String {
+    let challenge = generate_challenge();
+    WEBAUTHN_CHALLENGES.write().insert(
+        challenge.clone(),
+        PendingCeremony {
+            ceremony,
+            email,
+            timestamp: Utc::now(),
+        },
+    );
+    challenge
+}
+
+/// Challenges are single use, so they're removed whether or not the ceremony succeeds
+fn take_ceremony(challenge: &str, ceremony: Ceremony) -> Result<Option<String>, ServiceError> {
+    match WEBAUTHN_CHALLENGES.write().remove(challenge) {
+        Some(pending)
+            if pending.ceremony == ceremony
+                && (Utc::now() - pending.timestamp).num_seconds() <= CEREMONY_TIMEOUT =>
+        {
+            Ok(pending.email)
+        }
+        _ => Err(ServiceError::BadRequest(
+            "Invalid or expired challenge".into(),
+        )),
+    }
+}
+
+pub fn cleanup_webauthn_challenges() {
+    WEBAUTHN_CHALLENGES
+        .write()
+        .retain(|_, pending| (Utc::now() - pending.timestamp).num_seconds() <= CEREMONY_TIMEOUT);
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelyingPartyEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialParameter {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub alg: i128,
+}
+
+/// Options passed (after base64url decoding) to `navigator.credentials.create()`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: RelyingPartyEntity,
+    pub user: UserEntity,
+    pub pub_key_cred_params: Vec<CredentialParameter>,
+    pub timeout: i64,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+}
+
+/// Options passed (after base64url decoding) to `navigator.credentials.get()`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub timeout: i64,
+    pub user_verification: String,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+    // see `AuthData`
+    #[serde(default)]
+    pub return_token: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+impl From<WebauthnCredential> for CredentialInfo {
+    fn from(credential: WebauthnCredential) -> Self {
+        Self {
+            id: credential.id,
+            name: credential.name,
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+        }
+    }
+}
+
+fn load_credential_ids(
+    email_: &str,
+    conn: &diesel::PgConnection,
+) -> Result<Vec<CredentialDescriptor>, ServiceError> {
+    use crate::schema::webauthn_credentials::dsl::{email, id, webauthn_credentials};
+
+    webauthn_credentials
+        .filter(email.eq(email_))
+        .select(id)
+        .load::<String>(conn)
+        .map(|ids| ids.into_iter().map(CredentialDescriptor::new).collect())
+        .map_err(Into::into)
+}
+
+#[derive(Debug)]
+pub struct StartRegistration {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<StartRegistration> for DbExecutor {
+    type Result = Result<CreationOptions, ServiceError>;
+
+    async fn handle(&self, msg: StartRegistration) -> Self::Result {
+        let dbex = self.clone();
+        let email = msg.email.clone();
+        let exclude_credentials = spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            load_credential_ids(&email, &conn)
+        })
+        .await??;
+
+        let rp = RelyingParty::from_env();
+        let pub_key_cred_params = [COSE_ALG_ES256, COSE_ALG_EDDSA, COSE_ALG_RS256]
+            .iter()
+            .map(|alg| CredentialParameter {
+                credential_type: "public-key".to_string(),
+                alg: *alg,
+            })
+            .collect();
+        Ok(CreationOptions {
+            challenge: start_ceremony(Ceremony::Registration, Some(msg.email.clone())),
+            rp: RelyingPartyEntity {
+                id: rp.id,
+                name: rp.name,
+            },
+            user: UserEntity {
+                id: user_handle(&msg.email),
+                name: msg.email.clone(),
+                display_name: msg.email,
+            },
+            pub_key_cred_params,
+            timeout: CEREMONY_TIMEOUT * 1000,
+            attestation: "none".to_string(),
+            exclude_credentials,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct FinishRegistration {
+    pub email: String,
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+#[async_trait]
+impl HandleRequest<FinishRegistration> for DbExecutor {
+    type Result = Result<CredentialInfo, ServiceError>;
+
+    async fn handle(&self, msg: FinishRegistration) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::webauthn_credentials;
+
+        let client_data_json = decode_base64url(&msg.credential.response.client_data_json)?;
+        let attestation_object = decode_base64url(&msg.credential.response.attestation_object)?;
+        let challenge = client_data_challenge(&client_data_json)?;
+        if take_ceremony(&challenge, Ceremony::Registration)?.as_ref() != Some(&msg.email) {
+            return Err(ServiceError::BadRequest(
+                "Invalid or expired challenge".into(),
+            ));
+        }
+
+        let rp = RelyingParty::from_env();
+        let registered =
+            verify_registration(&rp, &challenge, &client_data_json, &attestation_object)?;
+        if BASE64URL_NOPAD.encode(&registered.credential_id) != msg.credential.id {
+            return Err(ServiceError::BadRequest("Credential id mismatch".into()));
+        }
+
+        let credential = WebauthnCredential {
+            id: BASE64URL_NOPAD.encode(&registered.credential_id),
+            email: msg.email,
+            name: msg.name.unwrap_or_else(|| "Passkey".to_string()),
+            public_key: registered.public_key,
+            sign_count: i64::from(registered.sign_count),
+            created_at: Local::now().naive_local(),
+            last_used_at: None,
+        };
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::insert_into(webauthn_credentials)
+                .values(&credential)
+                .get_result::<WebauthnCredential>(&conn)
+                .map(Into::into)
+                .map_err(|_db_error| {
+                    ServiceError::BadRequest("Credential already registered".into())
+                })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StartAuthentication {
+    pub email: Option<String>,
+}
+
+#[async_trait]
+impl HandleRequest<StartAuthentication> for DbExecutor {
+    type Result = Result<RequestOptions, ServiceError>;
+
+    async fn handle(&self, msg: StartAuthentication) -> Self::Result {
+        // without an email the browser offers any discoverable credential for this rp
+        let allow_credentials = match msg.email.clone() {
+            Some(email) => {
+                let dbex = self.clone();
+                spawn_blocking(move || {
+                    let conn = dbex.0.get()?;
+                    load_credential_ids(&email, &conn)
+                })
+                .await??
+            }
+            None => Vec::new(),
+        };
+
+        Ok(RequestOptions {
+            challenge: start_ceremony(Ceremony::Authentication, msg.email),
+            rp_id: RelyingParty::from_env().id,
+            timeout: CEREMONY_TIMEOUT * 1000,
+            user_verification: "required".to_string(),
+            allow_credentials,
+        })
+    }
+}
+
+#[async_trait]
+impl HandleRequest<AssertionCredential> for DbExecutor {
+    type Result = Result<SlimUser, ServiceError>;
+
+    async fn handle(&self, msg: AssertionCredential) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::{
+            last_used_at, sign_count, webauthn_credentials,
+        };
+
+        let client_data_json = decode_base64url(&msg.response.client_data_json)?;
+        let authenticator_data = decode_base64url(&msg.response.authenticator_data)?;
+        let signature = decode_base64url(&msg.response.signature)?;
+        let challenge = client_data_challenge(&client_data_json)?;
+        let expected_email = take_ceremony(&challenge, Ceremony::Authentication)?;
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            conn.transaction(|| {
+                let credential = webauthn_credentials
+                    .find(&msg.id)
+                    .for_update()
+                    .first::<WebauthnCredential>(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest("Unknown credential".into()))?;
+                if expected_email.is_some_and(|e| e != credential.email)
+                    || msg
+                        .response
+                        .user_handle
+                        .as_ref()
+                        .is_some_and(|h| h != &user_handle(&credential.email))
+                {
+                    return Err(ServiceError::BadRequest("Unknown credential".into()));
+                }
+
+                let rp = RelyingParty::from_env();
+                let new_sign_count = verify_assertion(
+                    &rp,
+                    &challenge,
+                    &client_data_json,
+                    &authenticator_data,
+                    &signature,
+                    &credential.public_key,
+                    credential.sign_count as u32,
+                )?;
+                diesel::update(webauthn_credentials.find(&credential.id))
+                    .set((
+                        sign_count.eq(i64::from(new_sign_count)),
+                        last_used_at.eq(Local::now().naive_local()),
+                    ))
+                    .execute(&conn)?;
+
+                Ok(SlimUser {
+                    email: credential.email,
+                })
+            })
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct ListCredentials {
+    pub email: String,
+}
+
+#[async_trait]
+impl HandleRequest<ListCredentials> for DbExecutor {
+    type Result = Result<Vec<CredentialInfo>, ServiceError>;
+
+    async fn handle(&self, msg: ListCredentials) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::{created_at, email, webauthn_credentials};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            webauthn_credentials
+                .filter(email.eq(msg.email))
+                .order(created_at)
+                .load::<WebauthnCredential>(&conn)
+                .map(|credentials| credentials.into_iter().map(Into::into).collect())
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteCredential {
+    pub email: String,
+    pub id: String,
+}
+
+#[async_trait]
+impl HandleRequest<DeleteCredential> for DbExecutor {
+    type Result = Result<bool, ServiceError>;
+
+    async fn handle(&self, msg: DeleteCredential) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::{email, id, webauthn_credentials};
+
+        let dbex = self.clone();
+        spawn_blocking(move || {
+            let conn = dbex.0.get()?;
+            diesel::delete(
+                webauthn_credentials
+                    .filter(id.eq(msg.id))
+                    .filter(email.eq(msg.email)),
+            )
+            .execute(&conn)
+            .map(|deleted| deleted > 0)
+            .map_err(Into::into)
+        })
+        .await?
+    }
+}
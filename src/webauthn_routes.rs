@@ -0,0 +1,108 @@
+use actix_identity::Identity;
+use actix_web::{
+    web::{Data, Json, Path},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use maplit::hashmap;
+use serde::Deserialize;
+
+use crate::{
+    auth_routes::complete_login,
+    logged_user::{LoggedUser, SessionUser},
+    models::{DbExecutor, HandleRequest},
+    webauthn_handler::{
+        AssertionCredential, DeleteCredential, FinishRegistration, ListCredentials,
+        RegistrationCredential, StartAuthentication, StartRegistration,
+    },
+};
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationData {
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+pub async fn register_start(
+    session_user: SessionUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = StartRegistration {
+        email: session_user.email,
+    };
+    match db.handle(msg).await {
+        Ok(options) => Ok(HttpResponse::Ok().json(options)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn register_finish(
+    session_user: SessionUser,
+    registration: Json<RegistrationData>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let registration = registration.into_inner();
+    let msg = FinishRegistration {
+        email: session_user.email,
+        name: registration.name,
+        credential: registration.credential,
+    };
+    match db.handle(msg).await {
+        Ok(credential) => Ok(HttpResponse::Ok().json(credential)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn login_start(
+    auth_data: Json<StartAuthentication>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    match db.handle(auth_data.into_inner()).await {
+        Ok(options) => Ok(HttpResponse::Ok().json(options)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn login_finish(
+    assertion: Json<AssertionCredential>,
+    request: HttpRequest,
+    id: Identity,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let return_token = assertion.return_token;
+    let user = db.handle(assertion.into_inner()).await?;
+    complete_login(&user, &["hwk"], return_token, &request, &id, &db)
+        .await
+        .map_err(Into::into)
+}
+
+pub async fn list_credentials(
+    logged_user: LoggedUser,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = ListCredentials {
+        email: logged_user.email,
+    };
+    match db.handle(msg).await {
+        Ok(credentials) => Ok(HttpResponse::Ok().json(credentials)),
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
+
+pub async fn delete_credential(
+    session_user: SessionUser,
+    credential_id: Path<String>,
+    db: Data<DbExecutor>,
+) -> Result<HttpResponse, Error> {
+    let msg = DeleteCredential {
+        email: session_user.email,
+        id: credential_id.into_inner(),
+    };
+    match db.handle(msg).await {
+        Ok(success) => {
+            let status = if success { "success" } else { "failure" };
+            let result = hashmap! { "status" => status };
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Err(service_error) => Ok(service_error.error_response()),
+    }
+}
@@ -12,3 +12,57 @@ function post(url = ``, data = {}) {
     body: JSON.stringify(data), // body data type must match "Content-Type" header
   }).then(response => response.json()); // parses response to JSON
 }
+
+function base64urlToBuffer(value) {
+  let base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+  let binary = atob(base64 + '==='.slice((base64.length + 3) % 4));
+  return Uint8Array.from(binary, c => c.charCodeAt(0)).buffer;
+}
+
+function bufferToBase64url(buffer) {
+  let binary = String.fromCharCode.apply(null, new Uint8Array(buffer));
+  return btoa(binary).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+}
+
+function passkeyLogin(email) {
+  return post('/api/webauthn/login/start', { email: email || null }).then(options => {
+    options.challenge = base64urlToBuffer(options.challenge);
+    options.allowCredentials.forEach(c => c.id = base64urlToBuffer(c.id));
+    return navigator.credentials.get({ publicKey: options });
+  }).then(credential => {
+    return post('/api/webauthn/login/finish', {
+      id: credential.id,
+      response: {
+        clientDataJSON: bufferToBase64url(credential.response.clientDataJSON),
+        authenticatorData: bufferToBase64url(credential.response.authenticatorData),
+        signature: bufferToBase64url(credential.response.signature),
+        userHandle: credential.response.userHandle ? bufferToBase64url(credential.response.userHandle) : null,
+      },
+    });
+  });
+}
+
+// keep the short lived auth cookie fresh using the refresh cookie, rescheduling itself
+// shortly before the new access token expires. Open tabs share that expiry, so only one of
+// them rotates the refresh token: presenting a rotated one again ends the session.
+function refreshSession() {
+  let expiresAt = Number(localStorage.getItem('authExpiresAt')) || 0;
+  if (expiresAt - Date.now() > 60 * 1000) {
+    scheduleRefresh(expiresAt);
+    return Promise.resolve({});
+  }
+  return post('/api/token/refresh', {}).then(data => {
+    if (data.expires_in) {
+      let expiresAt = Date.now() + data.expires_in * 1000;
+      localStorage.setItem('authExpiresAt', expiresAt);
+      scheduleRefresh(expiresAt);
+    } else {
+      localStorage.removeItem('authExpiresAt');
+    }
+    return data;
+  }).catch(() => ({}));
+}
+
+function scheduleRefresh(expiresAt) {
+  setTimeout(refreshSession, Math.max(expiresAt - Date.now() - 60 * 1000, 30 * 1000));
+}